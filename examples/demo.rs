@@ -1,40 +1,43 @@
-mod noise;
-mod lines;
-mod assets;
-mod rolling_stock;
-mod world;
-
 use std::ops::RangeInclusive;
 use bevy::color::palettes::basic::WHITE;
 use bevy::pbr::wireframe::{WireframeConfig, WireframePlugin};
 use bevy::prelude::*;
 use bevy_atmosphere::prelude::*;
 use bevy::render::camera::Projection;
-use bevy::render::mesh::{Indices, PrimitiveTopology};
-use bevy::render::render_asset::RenderAssets;
-use bevy::render::render_resource::{AddressMode, SamplerDescriptor};
 
 use bevy::window::{PresentMode, WindowPlugin};
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
 use bevy_egui::egui::emath;
 use bevy_flycam::{FlyCam, MovementSettings, NoCameraPlayerPlugin};
-use crate::assets::AssetsPlugin;
-
-use world::WorldPlugin;
-use world::terrain::Terrain;
-use crate::noise::NoiseSettings;
-use crate::rolling_stock::{RollingStockPlugin};
-use crate::rolling_stock::components::Wagon;
+use bevy_procedural_world::{headless, NoiseSettings, PHYSICS_TIMESTEP, Player};
+use bevy_procedural_world::camera::{CameraMode, CameraRig, CameraRigPlugin};
+use bevy_procedural_world::config::WorldConfig;
+use bevy_procedural_world::rolling_stock::components::{Wagon, WagonNumber};
+use bevy_procedural_world::world::terrain::Terrain;
 
 #[derive(Default, Resource)]
 struct ControlsUiState {
     wireframe_enabled: bool,
-    cam_follows_wagon: bool,
 }
 
-const PHYSICS_TIMESTEP: f32 = 1. / 60.;
+fn main() -> AppExit {
+    // Load the world from `--config <path>` or restore it from `--load <path>`, and override it with the other flags
+    // (e.g. `--seed <u32>`, `--headless`). Press F5 to save the world, and M to show the map of the world.
+    // Record a run with `--record <path>` (or start and stop recording with F6), and replay it with `--replay <path>`.
+    // Write the telemetry of the train to a CSV file with `--telemetry <path>` (sampled `--telemetry-rate <hz>` times per second).
+    // Switch between the free, chase, cab, orbit and trackside cameras with 1 to 5, or cycle through them with C.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match WorldConfig::from_args(&args) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            return AppExit::error();
+        },
+    };
+    if config.headless {
+        return headless::run_headless(&config);
+    }
 
-fn main() {
     App::new()
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -45,13 +48,17 @@ fn main() {
         }))
         .add_plugins((WireframePlugin, NoCameraPlayerPlugin, AtmospherePlugin, EguiPlugin))
 
-        .add_plugins((AssetsPlugin, WorldPlugin, RollingStockPlugin))
+        .add_plugins(config.plugins())
+        .add_plugins(CameraRigPlugin)
 
         .insert_resource(MovementSettings {
             sensitivity: 0.00012, // default: 0.00012
-            speed: 100.0, // default: 12.0
+            speed: config.camera_speed, // default: 12.0
         })
-        .insert_resource(NoiseSettings::default())
+        // The physics integrates with a constant timestep, so the fixed clock has to tick at the same rate.
+        .insert_resource(Time::<Fixed>::from_seconds(PHYSICS_TIMESTEP as f64))
+        .insert_resource(config.noise)
+        .insert_resource(config.clone())
         .insert_resource(WireframeConfig::default())
         .insert_resource(AtmosphereModel::new(Gradient {
             sky: LinearRgba::from(WHITE),
@@ -65,17 +72,13 @@ fn main() {
         .add_systems(Update, apply_controls_settings)
         .add_systems(Update, controls_ui)
 
-        .run();
+        .run()
 }
 
 /// Marker for updating the position of the global light
 #[derive(Component)]
 struct Sun;
 
-/// Marker to track Player position
-#[derive(Component)]
-struct Player;
-
 fn setup(
     mut commands: Commands,
     mut wireframe_config: ResMut<WireframeConfig>,
@@ -124,31 +127,44 @@ fn setup(
 fn apply_controls_settings(
     controls_res: Res<ControlsUiState>,
     mut wireframe_config: ResMut<WireframeConfig>,
-
-    mut cam_query: Query<&mut Transform, (With<Camera>, Without<rolling_stock::components::Bogie>)>,
-    tracked_wagon_query: Query<&Transform, (With<Wagon>, With<rolling_stock::components::TrackedWagon>, Without<Camera>)>,
 ) {
-    if controls_res.cam_follows_wagon {
-        if let Ok(wagon_transform) = tracked_wagon_query.get_single() {
-            let mut cam_transform = cam_query.single_mut();
-            cam_transform.translation = wagon_transform.translation;
-            cam_transform.translation.y += 10.0;
-        }
-    }
-
     wireframe_config.global = controls_res.wireframe_enabled;
 }
 
 fn controls_ui(
     mut egui_contexts: EguiContexts,
     mut controls_res: ResMut<ControlsUiState>,
+    mut camera_rig: ResMut<CameraRig>,
+    wagon_query: Query<(Entity, &Wagon, &WagonNumber)>,
 ) {
     egui::Window::new("Controls").show(egui_contexts.ctx_mut(), |ui| {
         ui.allocate_space(emath::Vec2::new(250., 0.));
         ui.set_max_width(250.0);
 
         ui.checkbox(&mut controls_res.wireframe_enabled, "Enable wireframe");
-        ui.checkbox(&mut controls_res.cam_follows_wagon, "Camera follows wagon");
+
+        ui.label("Camera (1-5, C to cycle)");
+        ui.horizontal_wrapped(|ui| {
+            for mode in CameraMode::ALL {
+                ui.radio_value(&mut camera_rig.mode, mode, mode.name());
+            }
+        });
+        if camera_rig.mode == CameraMode::Orbit {
+            let mut wagons: Vec<_> = wagon_query.iter().collect();
+            wagons.sort_by_key(|(_, _, number)| **number);
+            let wagon_label = |entity: Option<Entity>| match wagons.iter().find(|(wagon_entity, ..)| Some(*wagon_entity) == entity) {
+                Some((_, wagon, number)) => format!("{} {}", number.0, wagon.definition_id),
+                None => "Tracked wagon".to_string(),
+            };
+            let selected = wagon_label(camera_rig.orbit_target);
+            egui::ComboBox::from_label("Orbit vehicle").selected_text(selected).show_ui(ui, |ui| {
+                ui.selectable_value(&mut camera_rig.orbit_target, None, wagon_label(None));
+                for (entity, ..) in &wagons {
+                    ui.selectable_value(&mut camera_rig.orbit_target, Some(*entity), wagon_label(Some(*entity)));
+                }
+            });
+            ui.label("Turn with the arrow keys, zoom with page up/down.");
+        }
     });
 }
 
@@ -186,6 +202,6 @@ fn terrain_gen_ui(
         });
     });
     if any_changed {
-        terrain_res.loaded_chunks.clear();
+        terrain_res.reload_chunks();
     }
 }
@@ -1,21 +1,43 @@
-use bevy::gltf::Gltf;
+use std::marker::PhantomData;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
+use bevy::asset::io::Reader;
 use bevy::prelude::*;
+use bevy::utils::HashMap;
 use bevy_asset_loader::prelude::*;
+use serde::Deserialize;
+use crate::rolling_stock::stock_definitions::RollingStockSet;
+use crate::world::track_profiles::TrackProfileSet;
 
-pub(crate) struct AssetsPlugin;
+/// Loads the definitions, the textures and the models, then enters `AssetLoadingState::AssetsLoaded`.
+#[derive(Default)]
+pub struct AssetsPlugin {
+    /// Only load the definitions, without the textures and the models (no renderer needed).
+    pub headless: bool,
+}
 
 impl Plugin for AssetsPlugin {
     fn build(&self, app: &mut App) {
         app
-            .init_state::<AssetLoadingState>()
-            .add_loading_state(
-                LoadingState::new(AssetLoadingState::AssetsLoading)
-                    .continue_to_state(AssetLoadingState::AssetsLoaded)
-                    .with_dynamic_assets_file::<StandardDynamicAssetCollection>("textures.assets.ron")
-                    .with_dynamic_assets_file::<StandardDynamicAssetCollection>("models.assets.ron")
-                    .load_collection::<TextureAssets>()
-                    .load_collection::<ModelAssets>()
-            );
+            .init_asset::<TrackProfileSet>()
+            .register_asset_loader(RonAssetLoader::<TrackProfileSet>::new(&["profiles.ron"]))
+            .init_asset::<RollingStockSet>()
+            .register_asset_loader(RonAssetLoader::<RollingStockSet>::new(&["stock.ron"]))
+
+            .init_state::<AssetLoadingState>();
+
+        let loading_state = LoadingState::new(AssetLoadingState::AssetsLoading)
+            .continue_to_state(AssetLoadingState::AssetsLoaded)
+            .with_dynamic_assets_file::<StandardDynamicAssetCollection>("definitions.assets.ron")
+            .load_collection::<DefinitionAssets>();
+        if self.headless {
+            app.add_loading_state(loading_state);
+        } else {
+            app.add_loading_state(loading_state
+                .with_dynamic_assets_file::<StandardDynamicAssetCollection>("textures.assets.ron")
+                .with_dynamic_assets_file::<StandardDynamicAssetCollection>("models.assets.ron")
+                .load_collection::<TextureAssets>()
+                .load_collection::<ModelAssets>());
+        }
     }
 }
 
@@ -30,18 +52,78 @@ pub(crate) struct TextureAssets {
 }
 
 #[derive(AssetCollection, Resource)]
-pub(crate) struct ModelAssets {
-    #[asset(key = "models.track.cross_section")]
-    pub(crate) track_cross_section: Handle<Gltf>,
-    #[asset(key = "models.train.wagon_bogie")]
-    pub(crate) train_wagon_bogie: Handle<Scene>,
-    #[asset(key = "models.train.gondola")]
-    pub(crate) train_gondola: Handle<Scene>,
+pub struct ModelAssets {
+    /// The scenes used by the rolling stock definitions, mapped by asset path.
+    #[asset(key = "models.rolling_stock", collection(typed, mapped))]
+    pub rolling_stock: HashMap<String, Handle<Scene>>,
+}
+
+/// The RON definitions of the track profiles and the rolling stock.
+#[derive(AssetCollection, Resource)]
+pub struct DefinitionAssets {
+    #[asset(key = "definitions.track_profiles")]
+    pub track_profiles: Handle<TrackProfileSet>,
+    #[asset(key = "definitions.rolling_stock")]
+    pub rolling_stock: Handle<RollingStockSet>,
 }
 
 #[derive(States, Default, Clone, Eq, PartialEq, Debug, Hash)]
-pub(crate) enum AssetLoadingState {
+pub enum AssetLoadingState {
     #[default]
     AssetsLoading,
     AssetsLoaded,
 }
+
+/// Loads assets of type `A` from RON files with the given extensions.
+pub(crate) struct RonAssetLoader<A> {
+    extensions: &'static [&'static str],
+    _marker: PhantomData<fn() -> A>,
+}
+
+impl<A> RonAssetLoader<A> {
+    pub(crate) fn new(extensions: &'static [&'static str]) -> Self {
+        Self {
+            extensions,
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum RonAssetLoaderError {
+    Io(std::io::Error),
+    Ron(bevy::asset::ron::error::SpannedError),
+}
+
+impl std::fmt::Display for RonAssetLoaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RonAssetLoaderError::Io(error) => write!(f, "could not read the asset: {}", error),
+            RonAssetLoaderError::Ron(error) => write!(f, "could not parse the asset: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RonAssetLoaderError {}
+
+impl<A: Asset + for<'de> Deserialize<'de>> AssetLoader for RonAssetLoader<A> {
+    type Asset = A;
+    type Settings = ();
+    type Error = RonAssetLoaderError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a Self::Settings,
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.map_err(RonAssetLoaderError::Io)?;
+
+        bevy::asset::ron::de::from_bytes(&bytes).map_err(RonAssetLoaderError::Ron)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        self.extensions
+    }
+}
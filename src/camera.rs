@@ -0,0 +1,316 @@
+use std::f32::consts::PI;
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use bevy_flycam::FlyCam;
+use crate::{noise, NoiseSettings, Player};
+use crate::rolling_stock::components::{AttachedToWagon, Bogie, Coupling, TrackedWagon, Wagon, WagonPhysics};
+use crate::rolling_stock::utils::get_consist;
+use crate::world::route_gen::Route;
+
+/// The keys selecting the modes in the order of `CameraMode::ALL`, and the key cycling through them.
+const MODE_KEYS: [KeyCode; 5] = [KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3, KeyCode::Digit4, KeyCode::Digit5];
+const CYCLE_KEY: KeyCode = KeyCode::KeyC;
+/// The distance of the chase camera behind the rear of the consist and its height in m.
+const CHASE_DISTANCE: f32 = 25.;
+const CHASE_HEIGHT: f32 = 8.;
+/// How far ahead of the rear vehicle the chase camera looks in m.
+const CHASE_LOOK_AHEAD: f32 = 15.;
+/// The rate the chase camera catches up with its position behind the consist at, in 1/s.
+const CHASE_SMOOTHING: f32 = 3.;
+/// The height of the eyes above the leading bogie (on top of the body offset) and their distance behind it in m.
+const CAB_EYE_HEIGHT: f32 = 2.2;
+const CAB_SETBACK: f32 = 1.5;
+/// The speeds the orbit camera is turned (in rad/s) and zoomed (in fractions of the distance per s) at with the arrow keys and page up/down.
+const ORBIT_TURN_SPEED: f32 = 1.5;
+const ORBIT_ZOOM_SPEED: f32 = 1.;
+const ORBIT_MIN_DISTANCE: f32 = 5.;
+const ORBIT_MAX_DISTANCE: f32 = 300.;
+const ORBIT_MAX_PITCH: f32 = 1.4;
+/// The distance along the route ahead of the train a trackside camera is placed at, and its distance beside the track
+/// and height above the ground in m.
+const TRACKSIDE_DISTANCE_AHEAD: f32 = 250.;
+const TRACKSIDE_OFFSET: f32 = 12.;
+const TRACKSIDE_HEIGHT: f32 = 3.;
+/// The distance the train passes a trackside camera by before the next one is placed in m.
+const TRACKSIDE_PASSED_DISTANCE: f32 = 40.;
+/// A trackside camera further away from the train is replaced, e.g. after the train was restored elsewhere.
+const TRACKSIDE_MAX_DISTANCE: f32 = 1000.;
+
+/// Moves the camera marked with [`Player`] along with the consist of the tracked wagon.
+/// The fly camera only moves it in the free mode.
+pub struct CameraRigPlugin;
+
+impl Plugin for CameraRigPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<CameraRig>()
+            .add_systems(Update, switch_camera_mode)
+            // After the wagons follow their bogies, so that the camera does not lag a frame behind
+            .add_systems(PostUpdate, update_camera_rig.before(TransformSystem::TransformPropagate));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CameraMode {
+    /// Moved by the fly camera.
+    #[default]
+    Free,
+    /// Follows behind the rear of the consist, smoothed.
+    Chase,
+    /// Looks ahead from the cab of the leading vehicle.
+    Cab,
+    /// Circles a vehicle, turned with the arrow keys and zoomed with page up/down.
+    Orbit,
+    /// Stands beside the track ahead of the train, and moves ahead again once the train has passed.
+    Trackside,
+}
+
+impl CameraMode {
+    pub const ALL: [CameraMode; 5] = [CameraMode::Free, CameraMode::Chase, CameraMode::Cab, CameraMode::Orbit, CameraMode::Trackside];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CameraMode::Free => "Free",
+            CameraMode::Chase => "Chase",
+            CameraMode::Cab => "Cab",
+            CameraMode::Orbit => "Orbit",
+            CameraMode::Trackside => "Trackside",
+        }
+    }
+}
+
+/// The mode of the camera, switched with the keys 1 to 5 or cycled with C, and the state of the modes.
+#[derive(Resource)]
+pub struct CameraRig {
+    pub mode: CameraMode,
+    /// The vehicle the orbit camera circles. Circles the tracked wagon if `None` or despawned.
+    pub orbit_target: Option<Entity>,
+    orbit_yaw: f32,
+    orbit_pitch: f32,
+    orbit_distance: f32,
+    trackside_position: Option<Vec3>,
+    /// The side of the track the next trackside camera is placed on.
+    trackside_left: bool,
+    /// The mode the camera was last moved in, the camera is moved without smoothing after switching.
+    applied_mode: Option<CameraMode>,
+}
+
+impl Default for CameraRig {
+    fn default() -> Self {
+        Self {
+            mode: CameraMode::Free,
+            orbit_target: None,
+            orbit_yaw: 0.,
+            orbit_pitch: 0.4,
+            orbit_distance: 30.,
+            trackside_position: None,
+            trackside_left: true,
+            applied_mode: None,
+        }
+    }
+}
+
+fn switch_camera_mode(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut rig: ResMut<CameraRig>,
+) {
+    if let Some(index) = MODE_KEYS.iter().position(|key| keyboard_input.just_pressed(*key)) {
+        rig.mode = CameraMode::ALL[index];
+    }
+    if keyboard_input.just_pressed(CYCLE_KEY) {
+        let index = CameraMode::ALL.iter().position(|mode| *mode == rig.mode).unwrap_or_default();
+        rig.mode = CameraMode::ALL[(index + 1) % CameraMode::ALL.len()];
+    }
+}
+
+fn update_camera_rig(
+    mut commands: Commands,
+    time: Res<Time>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut rig: ResMut<CameraRig>,
+    noise_settings: Res<NoiseSettings>,
+    route: Res<Route>,
+    mut camera_query: Query<(Entity, &mut Transform, Has<FlyCam>), (With<Player>, With<Camera>, Without<Wagon>, Without<Bogie>)>,
+    tracked_wagon_query: Query<Entity, With<TrackedWagon>>,
+    wagon_query: Query<(&Wagon, &WagonPhysics, &Transform), Without<Bogie>>,
+    bogie_query: Query<(&Transform, &AttachedToWagon), (With<Bogie>, Without<Wagon>)>,
+    couplings_query: Query<(Entity, &Coupling)>,
+) {
+    let Ok((camera_entity, mut camera_transform, has_fly_cam)) = camera_query.get_single_mut() else {
+        return;
+    };
+    let rig = &mut *rig;
+    let mode_changed = rig.applied_mode != Some(rig.mode);
+    if mode_changed {
+        rig.applied_mode = Some(rig.mode);
+        rig.trackside_position = None;
+    }
+
+    // The fly camera would fight the rig over the transform
+    let is_free = rig.mode == CameraMode::Free;
+    if is_free && !has_fly_cam {
+        commands.entity(camera_entity).insert(FlyCam);
+    } else if !is_free && has_fly_cam {
+        commands.entity(camera_entity).remove::<FlyCam>();
+    }
+
+    let Ok(tracked_wagon) = tracked_wagon_query.get_single() else {
+        return;
+    };
+    let consist = get_consist(tracked_wagon, &couplings_query);
+    let target = match rig.mode {
+        CameraMode::Free => None,
+        CameraMode::Chase => chase_transform(&consist, &wagon_query),
+        CameraMode::Cab => cab_transform(&consist, &wagon_query, &bogie_query),
+        CameraMode::Orbit => {
+            let delta = time.delta_seconds();
+            let axis = |positive: KeyCode, negative: KeyCode| {
+                keyboard_input.pressed(positive) as i32 as f32 - keyboard_input.pressed(negative) as i32 as f32
+            };
+            rig.orbit_yaw += axis(KeyCode::ArrowRight, KeyCode::ArrowLeft) * ORBIT_TURN_SPEED * delta;
+            rig.orbit_pitch = (rig.orbit_pitch + axis(KeyCode::ArrowUp, KeyCode::ArrowDown) * ORBIT_TURN_SPEED * delta)
+                .clamp(-ORBIT_MAX_PITCH, ORBIT_MAX_PITCH);
+            rig.orbit_distance = (rig.orbit_distance * (1. + axis(KeyCode::PageDown, KeyCode::PageUp) * ORBIT_ZOOM_SPEED * delta))
+                .clamp(ORBIT_MIN_DISTANCE, ORBIT_MAX_DISTANCE);
+
+            let target_wagon = rig.orbit_target.filter(|entity| wagon_query.contains(*entity)).unwrap_or(tracked_wagon);
+            wagon_query.get(target_wagon).ok().map(|(_, _, wagon_transform)| {
+                let offset = Quat::from_euler(EulerRot::YXZ, rig.orbit_yaw, -rig.orbit_pitch, 0.) * Vec3::Z * rig.orbit_distance;
+                Transform::from_translation(wagon_transform.translation + offset).looking_at(wagon_transform.translation, Vec3::Y)
+            })
+        },
+        CameraMode::Trackside => trackside_transform(rig, &consist, &wagon_query, &route, *noise_settings),
+    };
+    let Some(target) = target else {
+        return;
+    };
+
+    if rig.mode == CameraMode::Chase && !mode_changed {
+        let factor = 1. - (-CHASE_SMOOTHING * time.delta_seconds()).exp();
+        camera_transform.translation = camera_transform.translation.lerp(target.translation, factor);
+        camera_transform.rotation = camera_transform.rotation.slerp(target.rotation, factor);
+    } else {
+        camera_transform.translation = target.translation;
+        camera_transform.rotation = target.rotation;
+    }
+}
+
+/// Returns the direction the vehicle at the index of the consist faces towards the front of the consist.
+/// The vehicles face their leading bogie, which may point to the rear of the consist.
+fn direction_to_front(
+    consist: &[Entity],
+    index: usize,
+    wagon_query: &Query<(&Wagon, &WagonPhysics, &Transform), Without<Bogie>>,
+) -> Option<Vec3> {
+    let (_, _, transform) = wagon_query.get(*consist.get(index)?).ok()?;
+    let forward = transform.forward().as_vec3();
+    let (neighbour, sign) = if index == 0 { (consist.get(1), -1.) } else { (consist.get(index - 1), 1.) };
+    let Some((_, _, neighbour_transform)) = neighbour.and_then(|entity| wagon_query.get(*entity).ok()) else {
+        return Some(forward);
+    };
+    if (neighbour_transform.translation - transform.translation).dot(forward) * sign < 0. {
+        Some(-forward)
+    } else {
+        Some(forward)
+    }
+}
+
+fn chase_transform(
+    consist: &[Entity],
+    wagon_query: &Query<(&Wagon, &WagonPhysics, &Transform), Without<Bogie>>,
+) -> Option<Transform> {
+    let rear_index = consist.len().checked_sub(1)?;
+    let (_, _, rear_transform) = wagon_query.get(consist[rear_index]).ok()?;
+    let direction = direction_to_front(consist, rear_index, wagon_query)?.with_y(0.).normalize_or_zero();
+
+    let position = rear_transform.translation - direction * CHASE_DISTANCE + Vec3::Y * CHASE_HEIGHT;
+    Some(Transform::from_translation(position).looking_at(rear_transform.translation + direction * CHASE_LOOK_AHEAD, Vec3::Y))
+}
+
+/// Places the camera in the cab of the leading vehicle, aligned to its bogie at the front of the consist.
+fn cab_transform(
+    consist: &[Entity],
+    wagon_query: &Query<(&Wagon, &WagonPhysics, &Transform), Without<Bogie>>,
+    bogie_query: &Query<(&Transform, &AttachedToWagon), (With<Bogie>, Without<Wagon>)>,
+) -> Option<Transform> {
+    let front = *consist.first()?;
+    let (wagon, _, wagon_transform) = wagon_query.get(front).ok()?;
+    let direction = direction_to_front(consist, 0, wagon_query)?;
+    let bogie_transform = bogie_query.iter()
+        .filter(|(_, attached_to)| attached_to.0 == front)
+        .map(|(transform, _)| transform)
+        .max_by(|a, b| {
+            let ahead = |transform: &Transform| (transform.translation - wagon_transform.translation).dot(direction);
+            ahead(a).total_cmp(&ahead(b))
+        })?;
+
+    // The bogies face the direction of the track, which may be against the consist
+    let mut rotation = bogie_transform.rotation;
+    if (rotation * Vec3::NEG_Z).dot(direction) < 0. {
+        rotation *= Quat::from_rotation_y(PI);
+    }
+    let position = bogie_transform.translation
+        + rotation * Vec3::Y * (wagon.body_offset + CAB_EYE_HEIGHT)
+        - rotation * Vec3::NEG_Z * CAB_SETBACK;
+    Some(Transform::from_translation(position).with_rotation(rotation))
+}
+
+/// Keeps the camera beside the route ahead of the train looking at the vehicle at the end of the consist it moves
+/// towards, and places it further ahead once the train has passed.
+fn trackside_transform(
+    rig: &mut CameraRig,
+    consist: &[Entity],
+    wagon_query: &Query<(&Wagon, &WagonPhysics, &Transform), Without<Bogie>>,
+    route: &Route,
+    noise_settings: NoiseSettings,
+) -> Option<Transform> {
+    let (_, front_physics, front_transform) = wagon_query.get(*consist.first()?).ok()?;
+    let front_direction = direction_to_front(consist, 0, wagon_query)?;
+    // The velocity is positive towards the leading bogie the vehicle faces
+    let travel_direction = (front_transform.forward().as_vec3() * front_physics.velocity.signum()).with_y(0.).normalize_or_zero();
+    let leading_end = if travel_direction.dot(front_direction) >= 0. { consist[0] } else { *consist.last()? };
+    let (_, _, leading_transform) = wagon_query.get(leading_end).ok()?;
+    let train_position = leading_transform.translation;
+
+    let is_passed = rig.trackside_position.map_or(true, |position| {
+        let offset = (position - train_position).with_y(0.);
+        offset.dot(travel_direction) < -TRACKSIDE_PASSED_DISTANCE || offset.length() > TRACKSIDE_MAX_DISTANCE
+    });
+    if is_passed {
+        rig.trackside_position = place_trackside_camera(route, train_position, travel_direction, rig.trackside_left, noise_settings);
+        rig.trackside_left = !rig.trackside_left;
+    }
+
+    let position = rig.trackside_position?;
+    Some(Transform::from_translation(position).looking_at(train_position, Vec3::Y))
+}
+
+/// Returns a position beside the route, the given distance ahead of the train along it.
+fn place_trackside_camera(route: &Route, train_position: Vec3, travel_direction: Vec3, left: bool, noise_settings: NoiseSettings) -> Option<Vec3> {
+    let points = route.points();
+    let nearest = (0..points.len())
+        .min_by(|a, b| points[*a].xz().distance_squared(train_position.xz()).total_cmp(&points[*b].xz().distance_squared(train_position.xz())))?;
+    let next = (nearest + 1).min(points.len() - 1);
+    let previous = nearest.saturating_sub(1);
+    let forward = (points[next] - points[previous]).dot(travel_direction) >= 0.;
+
+    let mut index = nearest;
+    let mut distance = 0.;
+    while distance < TRACKSIDE_DISTANCE_AHEAD {
+        let next_index = if forward { index + 1 } else { index.checked_sub(1)? };
+        let Some(next_point) = points.get(next_index) else {
+            break;
+        };
+        distance += points[index].xz().distance(next_point.xz());
+        index = next_index;
+    }
+
+    let point = points[index];
+    let along = (points[(index + 1).min(points.len() - 1)] - points[index.saturating_sub(1)]).with_y(0.).normalize_or_zero();
+    let side = if left { Vec3::new(along.z, 0., -along.x) } else { Vec3::new(-along.z, 0., along.x) };
+    let position = point + side * TRACKSIDE_OFFSET;
+    // Above the ground, and above the track where it runs on an embankment
+    let height_fn = noise::get_heightmap_function(noise_settings, Vec3::ZERO);
+    let ground = (height_fn(position.x as f64, position.z as f64) as f32).max(point.y);
+    Some(position.with_y(ground + TRACKSIDE_HEIGHT))
+}
@@ -0,0 +1,174 @@
+use std::str::FromStr;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::{NoiseSettings, PHYSICS_TIMESTEP};
+use crate::assets::AssetsPlugin;
+use crate::replay::{Recording, ReplayPlugin};
+use crate::rolling_stock::RollingStockPlugin;
+use crate::save::{SavePlugin, WorldSave};
+use crate::telemetry::TelemetryPlugin;
+use crate::world::{WorldPlugin, WorldSettings};
+
+/// Everything needed to reproduce a world: the noise, the world dimensions, the route constraints and the train.
+/// Read from a RON file with `--config <path>` (or from a save with `--load <path>`), the fields missing from the file keep their defaults.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldConfig {
+    pub noise: NoiseSettings,
+    pub world: WorldSettings,
+    /// The id of the track profile. Uses the default profile of the definitions if `None`.
+    pub track_profile: Option<String>,
+    /// The ids of the vehicles of the train. Uses the default consist of the definitions if `None`.
+    pub consist: Option<Vec<String>>,
+    /// The speed of the free camera in m/s.
+    pub camera_speed: f32,
+    /// Run the simulation without a window or a renderer.
+    pub headless: bool,
+    /// The simulated time of a headless run in seconds.
+    pub duration: f32,
+    /// The number of telemetry samples per simulated second.
+    pub telemetry_rate: f32,
+    /// The save to restore the world from.
+    #[serde(skip)]
+    pub restore_from: Option<String>,
+    /// The file the world is saved to at the end of a headless run.
+    #[serde(skip)]
+    pub save_to: Option<String>,
+    /// The file the run is recorded to.
+    #[serde(skip)]
+    pub record_to: Option<String>,
+    /// The recording to replay.
+    #[serde(skip)]
+    pub replay_from: Option<String>,
+    /// The CSV file the telemetry of the vehicles is written to.
+    #[serde(skip)]
+    pub telemetry_to: Option<String>,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            noise: NoiseSettings::default(),
+            world: WorldSettings::default(),
+            track_profile: None,
+            consist: None,
+            camera_speed: 100.,
+            headless: false,
+            duration: 60.,
+            telemetry_rate: 10.,
+            restore_from: None,
+            save_to: None,
+            record_to: None,
+            replay_from: None,
+            telemetry_to: None,
+        }
+    }
+}
+
+impl WorldConfig {
+    /// Reads the config from a RON file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+        bevy::asset::ron::from_str(&contents).map_err(|error| format!("could not parse {}: {}", path, error))
+    }
+
+    /// Reads the config of the recording given with `--replay <path>`, of the save given with `--load <path>`
+    /// or the config file given with `--config <path>` (or uses the defaults without any), then applies the other flags on top of it.
+    /// A replay lasts as long as the recording unless `--duration` is given.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let get_path = |flag: &str| args.iter()
+            .position(|arg| arg == flag)
+            .map(|index| args.get(index + 1).ok_or(format!("{} needs a value", flag)))
+            .transpose();
+        let mut config = match (get_path("--replay")?, get_path("--load")?, get_path("--config")?) {
+            (Some(recording_path), _, _) => {
+                let recording = Recording::load(recording_path)?;
+                let mut config = recording.start.config;
+                config.duration = recording.trace.len() as f32 * PHYSICS_TIMESTEP;
+                config
+            },
+            (None, Some(save_path), _) => WorldSave::load(save_path)?.config,
+            (None, None, Some(path)) => Self::load(path)?,
+            (None, None, None) => Self::default(),
+        };
+        config.apply_args(args)?;
+
+        Ok(config)
+    }
+
+    /// Returns the plugins of the world, configured for the window or the headless mode.
+    pub fn plugins(&self) -> (AssetsPlugin, WorldPlugin, RollingStockPlugin, SavePlugin, ReplayPlugin, TelemetryPlugin) {
+        (
+            AssetsPlugin { headless: self.headless },
+            WorldPlugin { headless: self.headless, settings: self.world.clone(), track_profile: self.track_profile.clone() },
+            RollingStockPlugin { headless: self.headless, consist: self.consist.clone() },
+            SavePlugin { headless: self.headless, restore_from: self.restore_from.clone() },
+            ReplayPlugin { headless: self.headless, record_to: self.record_to.clone(), replay_from: self.replay_from.clone() },
+            TelemetryPlugin { path: self.telemetry_to.clone(), rate: self.telemetry_rate },
+        )
+    }
+
+    /// Overrides the config with the flags in the arguments.
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let name = arg.as_str();
+            let mut value = || args.next().ok_or(format!("{} needs a value", name));
+            match name {
+                "--config" => { value()?; },
+                "--load" => self.restore_from = Some(value()?.clone()),
+                "--save" => self.save_to = Some(value()?.clone()),
+                "--record" => self.record_to = Some(value()?.clone()),
+                "--replay" => self.replay_from = Some(value()?.clone()),
+                "--telemetry" => self.telemetry_to = Some(value()?.clone()),
+                "--telemetry-rate" => self.telemetry_rate = parse(name, value()?)?,
+                "--headless" => self.headless = true,
+                "--window" => self.headless = false,
+                "--duration" => self.duration = parse(name, value()?)?,
+                "--seed" => self.noise.seed = parse(name, value()?)?,
+                "--amplitude" => self.noise.amplitude = parse(name, value()?)?,
+                "--noise-scale" => {
+                    let scale = parse(name, value()?)?;
+                    self.noise.scale = (scale, scale);
+                },
+                "--far-chunk-size" => self.world.far_chunk_size = parse(name, value()?)?,
+                "--render-distance" => self.world.far_render_distance = parse(name, value()?)?,
+                "--near-chunks" => self.world.near_chunks_per_side = parse(name, value()?)?,
+                "--water-level" => self.world.water_level = parse(name, value()?)?,
+                "--node-length" => self.world.route.node_length = parse(name, value()?)?,
+                "--max-turn-angle" => self.world.route.max_turn_angle = parse(name, value()?)?,
+                "--tunnel-depth" => self.world.route.tunnel_depth_threshold = parse(name, value()?)?,
+                "--max-tunnel-grade" => self.world.route.max_tunnel_grade = parse(name, value()?)?,
+                "--tunnel-cost" => self.world.route.tunnel_node_cost = parse(name, value()?)?,
+                "--track-profile" => self.track_profile = Some(value()?.clone()),
+                "--consist" => self.consist = Some(value()?.split(',').map(|id| id.trim().to_string()).collect()),
+                "--camera-speed" => self.camera_speed = parse(name, value()?)?,
+                _ => return Err(format!("unknown argument {:?}", arg)),
+            }
+        }
+
+        self.validate()
+    }
+
+    /// Rejects the values the world cannot be generated with.
+    fn validate(&self) -> Result<(), String> {
+        if self.world.far_chunk_size == 0 || self.world.near_chunks_per_side == 0 || self.world.far_chunk_size % self.world.near_chunks_per_side != 0 {
+            return Err("the far chunk size has to be a non-zero multiple of the number of near chunks".to_string());
+        }
+        if self.world.route.node_length <= 0. {
+            return Err("the node length has to be positive".to_string());
+        }
+        if self.telemetry_rate <= 0. {
+            return Err("the telemetry rate has to be positive".to_string());
+        }
+        if self.duration < 0. {
+            return Err("the duration cannot be negative".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value {:?} for {}", value, name))
+}
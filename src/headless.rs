@@ -0,0 +1,129 @@
+use std::time::Duration;
+use bevy::app::PluginsState;
+use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use bevy::time::TimeUpdateStrategy;
+use crate::{NoiseSettings, PHYSICS_TIMESTEP, Player};
+use crate::config::WorldConfig;
+use crate::replay;
+use crate::save::WorldSave;
+use crate::rolling_stock::WagonPhysicsSet;
+use crate::rolling_stock::components::{Cargo, CargoSpace, Derailed, TrackedWagon, Wagon, WagonPhysics};
+use crate::world::route_gen::Route;
+
+/// The number of app updates the runner waits for the assets and the track around the train to load,
+/// on top of one update per physics step, before giving up.
+const MAX_LOADING_UPDATES: u64 = 100000;
+
+/// The number of physics steps simulated so far.
+#[derive(Resource, Default)]
+struct SimulatedSteps(u64);
+
+fn count_simulated_steps(mut steps: ResMut<SimulatedSteps>) {
+    steps.0 += 1;
+}
+
+fn spawn_player(mut commands: Commands) {
+    commands.spawn(TransformBundle::default()).insert(Player);
+}
+
+/// Keeps the player at the tracked wagon, so that the route and the track are generated around the train.
+fn follow_tracked_wagon(
+    mut player_query: Query<&mut Transform, (With<Player>, Without<TrackedWagon>)>,
+    tracked_wagon_query: Query<&Transform, (With<TrackedWagon>, Without<Player>)>,
+) {
+    if let (Ok(mut player_transform), Ok(wagon_transform)) = (player_query.get_single_mut(), tracked_wagon_query.get_single()) {
+        player_transform.translation = wagon_transform.translation;
+    }
+}
+
+/// Runs the simulation without a window or a renderer for the configured simulated time, and prints the final state of the train.
+/// Every app update advances the clock by one physics step, so the result only depends on the config.
+pub fn run_headless(config: &WorldConfig) -> AppExit {
+    let mut app = build_headless_app(config);
+    if let Err(error) = simulate(&mut app, config.duration) {
+        eprintln!("{}", error);
+        return AppExit::error();
+    }
+
+    print_results(app.world_mut(), &config.noise, config.duration);
+    if let Some(path) = &config.save_to {
+        if let Err(error) = WorldSave::capture(app.world_mut()).write(path) {
+            eprintln!("Could not save the world: {}", error);
+            return AppExit::error();
+        }
+        println!("saved to: {}", path);
+    }
+    if let Err(error) = replay::finish_headless_run(app.world_mut()) {
+        eprintln!("{}", error);
+        return AppExit::error();
+    }
+    AppExit::Success
+}
+
+/// Builds the app of a headless run of the config, ready to be updated.
+pub fn build_headless_app(config: &WorldConfig) -> App {
+    let mut config = config.clone();
+    config.headless = true;
+
+    let mut app = App::new();
+    app
+        .add_plugins((MinimalPlugins, AssetPlugin::default(), StatesPlugin, TransformPlugin, HierarchyPlugin))
+        .add_plugins(config.plugins())
+
+        .insert_resource(Time::<Fixed>::from_seconds(PHYSICS_TIMESTEP as f64))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(PHYSICS_TIMESTEP)))
+        .insert_resource(config.noise)
+        .insert_resource(config.clone())
+        .init_resource::<SimulatedSteps>()
+
+        .add_systems(Startup, spawn_player)
+        .add_systems(Update, follow_tracked_wagon)
+        .add_systems(FixedUpdate, count_simulated_steps.in_set(WagonPhysicsSet::ApplyForces));
+
+    while app.plugins_state() == PluginsState::Adding {
+        bevy::tasks::tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+
+    app
+}
+
+/// Updates the headless app until the given simulated time in seconds has passed, counting from the first physics step.
+/// Fails if the physics stops stepping, e.g. when a replay has reached its end.
+pub fn simulate(app: &mut App, duration: f32) -> Result<(), String> {
+    let target_steps = (duration / PHYSICS_TIMESTEP).round() as u64;
+    let mut updates = 0;
+    while app.world().resource::<SimulatedSteps>().0 < target_steps {
+        if updates >= target_steps + MAX_LOADING_UPDATES {
+            return Err(format!("The simulation stalled after {} of {} steps.", app.world().resource::<SimulatedSteps>().0, target_steps));
+        }
+        app.update();
+        updates += 1;
+    }
+
+    Ok(())
+}
+
+fn print_results(world: &mut World, noise_settings: &NoiseSettings, duration: f32) {
+    println!("seed: {}", noise_settings.seed);
+    println!("simulated time: {} s", duration);
+    println!("route nodes: {}", world.resource::<Route>().id_counter);
+
+    let mut wagons_query = world.query::<(Entity, &Wagon, &WagonPhysics, &Transform, Option<&Cargo>, Option<&CargoSpace>, Option<&Derailed>)>();
+    let mut wagons: Vec<_> = wagons_query.iter(world).collect();
+    wagons.sort_by_key(|(entity, ..)| *entity);
+    for (entity, wagon, wagon_physics, transform, cargo, cargo_space, derailed) in wagons {
+        println!("wagon {:?} ({}):", entity, wagon.definition_id);
+        println!("  position: {:.2} {:.2} {:.2}", transform.translation.x, transform.translation.y, transform.translation.z);
+        println!("  velocity: {:.3} m/s", wagon_physics.velocity);
+        println!("  mass: {:.0} kg", wagon_physics.mass);
+        if let (Some(cargo), Some(cargo_space)) = (cargo, cargo_space) {
+            println!("  cargo: {:?}, {:.0} kg", cargo.cargo_type, cargo.get_mass(cargo_space));
+        }
+        if let Some(derailed) = derailed {
+            println!("  derailed: {}", derailed.cause);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! A procedurally generated world with a railway route, and the rolling stock running on it.
+//!
+//! Add the [`assets::AssetsPlugin`], the [`world::WorldPlugin`] and the [`rolling_stock::RollingStockPlugin`] to an app,
+//! and mark the entity the world should be generated around with [`Player`]. The plugins are configured through their fields,
+//! e.g. the chunk sizes, the render distance and the route constraints through [`world::WorldSettings`].
+//! The terrain height is sampled with [`noise::get_heightmap_function`], the tracks are queried through [`world::train_tracks::Track`].
+
+pub mod noise;
+mod lines;
+pub mod assets;
+pub mod camera;
+pub mod config;
+pub mod headless;
+pub mod replay;
+pub mod rolling_stock;
+pub mod save;
+pub mod telemetry;
+pub mod world;
+
+use bevy::prelude::*;
+
+pub use crate::noise::NoiseSettings;
+
+/// The timestep of the rolling stock physics in seconds. The fixed clock of the app has to tick at the same rate.
+pub const PHYSICS_TIMESTEP: f32 = 1. / 60.;
+
+/// Marks the entity (usually the camera) the route, the terrain and the tracks are generated around.
+#[derive(Component)]
+pub struct Player;
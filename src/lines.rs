@@ -3,7 +3,8 @@ use bevy::color::LinearRgba;
 use bevy::pbr::{MaterialPipeline, MaterialPipelineKey};
 use bevy::render::mesh::{MeshVertexBufferLayoutRef};
 use bevy::render::render_resource::{PolygonMode, RenderPipelineDescriptor, SpecializedMeshPipelineError};
-use crate::{Material, Mesh, PrimitiveTopology, Vec3};
+use bevy::prelude::{Material, Mesh, Vec3};
+use bevy::render::mesh::PrimitiveTopology;
 use bevy::reflect::{TypePath};
 use bevy::render::render_asset::RenderAssetUsages;
 use bevy::render::render_resource::{ShaderRef, AsBindGroup};
@@ -1,9 +1,13 @@
 use bevy::prelude::*;
 use noisy_bevy::simplex_noise_2d_seeded;
+use serde::{Deserialize, Serialize};
 
 const SEED: u32 = 1354251456;
+/// The shift of the noise origin from the world origin in meters, on both axes.
+const NOISE_ORIGIN_OFFSET: f32 = 500.;
 
-#[derive(Copy, Clone, Resource)]
+#[derive(Copy, Clone, Resource, Serialize, Deserialize)]
+#[serde(default)]
 pub struct NoiseSettings {
     pub amplitude: f64,
     pub frequency: f32,
@@ -22,10 +26,12 @@ impl Default for NoiseSettings {
     }
 }
 
-pub(crate) fn get_heightmap_function(chunk_size: f32, noise_settings: NoiseSettings, offset: Vec3) -> impl Fn(f64, f64) -> f64 {
+/// Returns the terrain height function of the world. The function takes x and z relative to `offset`,
+/// and returns the height with `offset.y` added.
+pub fn get_heightmap_function(noise_settings: NoiseSettings, offset: Vec3) -> impl Fn(f64, f64) -> f64 {
     let heightmap_fn = move |x: f64, y: f64| -> f64 {
-        let base_pos_x = x as f32 - chunk_size / 2. + offset.x;
-        let base_pos_y = y as f32 - chunk_size / 2. + offset.z;
+        let base_pos_x = x as f32 - NOISE_ORIGIN_OFFSET + offset.x;
+        let base_pos_y = y as f32 - NOISE_ORIGIN_OFFSET + offset.z;
         noise_settings.amplitude * simplex_noise_2d_seeded(Vec2::new(base_pos_x / noise_settings.scale.0 as f32, base_pos_y / noise_settings.scale.0 as f32), noise_settings.seed as f32) as f64
             + noise_settings.amplitude / 2. * simplex_noise_2d_seeded(Vec2::new((base_pos_x + 100.) / noise_settings.scale.0 as f32, (base_pos_y + 100.) / noise_settings.scale.0 as f32), noise_settings.seed as f32) as f64
             + noise_settings.amplitude / 3. * simplex_noise_2d_seeded(Vec2::new((base_pos_x + 200.) / noise_settings.scale.0 as f32, (base_pos_y + 200.) / noise_settings.scale.0 as f32), noise_settings.seed as f32) as f64
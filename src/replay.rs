@@ -0,0 +1,489 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use bevy::asset::ron;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use serde::{Deserialize, Serialize};
+use crate::assets::AssetLoadingState;
+use crate::rolling_stock::WagonPhysicsSet;
+use crate::rolling_stock::components::{AttachedToWagon, Bogie, BogiePhysics, BrakeValvePosition, Coupling, DriversBrakeValve, Locomotive, Reverser};
+use crate::rolling_stock::locomotive_systems::RailCondition;
+use crate::rolling_stock::wagon_systems::{count_physics_ticks, PhysicsTick};
+use crate::save::{get_wagons_in_spawn_order, PendingRestore, WorldSave};
+use crate::world::train_tracks::Crossover;
+
+/// The version of the recording format. Recordings with another version are rejected.
+pub const RECORDING_VERSION: u32 = 1;
+/// The directory the recordings made from the window are written to.
+const RECORDING_DIRECTORY: &str = "recordings";
+const RECORD_KEY: KeyCode = KeyCode::F6;
+/// How many times faster than real time the replay runs while seeking a tick.
+const SEEK_SPEED: f32 = 10.;
+
+/// Records the controls of every physics step and the resulting state of the bogies, and replays the recordings.
+/// A replay starts from the state the recording started from, applies the recorded controls at the same steps
+/// and reports the first step where the bogies differ from the recording.
+#[derive(Default)]
+pub struct ReplayPlugin {
+    /// Without the window: no recording from the keyboard and no replay controls.
+    pub headless: bool,
+    /// Record from the first physics step and write the recording to this path
+    /// (when stopped with F6, or at the end of a headless run).
+    pub record_to: Option<String>,
+    /// The recording to replay.
+    pub replay_from: Option<String>,
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Recorder {
+                path: self.record_to.clone(),
+                armed: self.record_to.is_some(),
+                ..default()
+            })
+            .add_systems(FixedUpdate, record_controls.in_set(WagonPhysicsSet::Controls).run_if(is_recording))
+            .add_systems(FixedUpdate,
+                         record_bogie_states
+                             .in_set(WagonPhysicsSet::ApplyForces)
+                             .after(count_physics_ticks)
+                             .run_if(is_recording));
+
+        if let Some(path) = &self.replay_from {
+            match Recording::load(path) {
+                Ok(recording) => {
+                    app
+                        .insert_resource(PendingRestore(recording.start.clone()))
+                        .insert_resource(Replay::new(recording))
+                        .configure_sets(FixedUpdate,
+                                        (WagonPhysicsSet::Controls, WagonPhysicsSet::ReadTrack, WagonPhysicsSet::SetForces, WagonPhysicsSet::ApplyForces)
+                                            .run_if(replay_is_running))
+                        .add_systems(FixedUpdate, apply_recorded_controls.in_set(WagonPhysicsSet::Controls))
+                        .add_systems(FixedUpdate,
+                                     compare_bogie_states
+                                         .in_set(WagonPhysicsSet::ApplyForces)
+                                         .after(count_physics_ticks));
+                    if !self.headless {
+                        app.add_systems(Update, replay_ui.run_if(in_state(AssetLoadingState::AssetsLoaded)));
+                    }
+                },
+                Err(error) => warn!("Not replaying: {}", error),
+            }
+        }
+
+        if self.headless {
+            return;
+        }
+
+        app.add_systems(Update, record_on_key.run_if(in_state(AssetLoadingState::AssetsLoaded)));
+    }
+}
+
+/// A recorded run: the state it started from (including the config and the seed), the controls and the state of the bogies.
+#[derive(Serialize, Deserialize)]
+pub struct Recording {
+    pub version: u32,
+    pub start: WorldSave,
+    /// The controls at the start of the recording and every change of them, in the order of their ticks.
+    pub inputs: Vec<RecordedInput>,
+    /// The state of the bogies after every physics step of the recording.
+    pub trace: Vec<Vec<BogieState>>,
+}
+
+/// The controls set before the physics step with the given tick.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedInput {
+    pub tick: u64,
+    pub controls: Controls,
+}
+
+/// The controls of the vehicles, which the physics cannot change. The wagons are referred to by their index in the spawn order.
+#[derive(Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Controls {
+    /// The wagon index, the throttle notch and the reverser of every locomotive.
+    pub locomotives: Vec<(usize, u32, Reverser)>,
+    pub brake_valves: Vec<(usize, BrakeValvePosition)>,
+    /// The segment ids of the crossovers set to diverging.
+    pub diverging_crossovers: Vec<usize>,
+    pub(crate) rail_condition: RailCondition,
+    /// The indices of the front and the rear wagon of every coupling. Uncoupling removes a coupling.
+    pub couplings: Vec<(usize, usize)>,
+}
+
+/// The state of a bogie after a physics step. The bogies are ordered by their wagon, the leading bogie first.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BogieState {
+    pub position_on_track: f32,
+    pub velocity: f32,
+}
+
+/// Only the version of a recording, read first so that recordings in another format are rejected with a clear error.
+#[derive(Deserialize)]
+struct RecordingVersion {
+    version: u32,
+}
+
+impl Recording {
+    /// Reads a recording from a RON file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+        let version: RecordingVersion = ron::from_str(&contents).map_err(|error| format!("could not parse {}: {}", path, error))?;
+        if version.version != RECORDING_VERSION {
+            return Err(format!("{} is a version {} recording, only version {} is supported", path, version.version, RECORDING_VERSION));
+        }
+
+        ron::from_str(&contents).map_err(|error| format!("could not parse {}: {}", path, error))
+    }
+
+    /// Writes the recording to a RON file, creating the directory if needed.
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        // The trace has an array per step, one line each keeps the file readable
+        let pretty_config = ron::ser::PrettyConfig::default().compact_arrays(true);
+        let contents = ron::ser::to_string_pretty(self, pretty_config)
+            .map_err(|error| format!("could not serialize the recording: {}", error))?;
+        if let Some(directory) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(directory).map_err(|error| format!("could not create {}: {}", directory.display(), error))?;
+        }
+
+        std::fs::write(path, contents).map_err(|error| format!("could not write {}: {}", path, error))
+    }
+
+    /// The tick after the last recorded physics step.
+    pub fn end_tick(&self) -> u64 {
+        self.start.tick + self.trace.len() as u64
+    }
+}
+
+impl Controls {
+    fn capture(world: &mut World) -> Self {
+        let wagons = get_wagons_in_spawn_order(world);
+        let wagon_index = |entity: Entity| wagons.iter().position(|wagon| *wagon == entity);
+
+        let mut locomotives = Vec::new();
+        let mut brake_valves = Vec::new();
+        let mut controls_query = world.query::<(Option<&Locomotive>, Option<&DriversBrakeValve>)>();
+        for (index, entity) in wagons.iter().enumerate() {
+            let Ok((locomotive, brake_valve)) = controls_query.get(world, *entity) else {
+                continue;
+            };
+            if let Some(locomotive) = locomotive {
+                locomotives.push((index, locomotive.notch, locomotive.reverser));
+            }
+            if let Some(brake_valve) = brake_valve {
+                brake_valves.push((index, brake_valve.position));
+            }
+        }
+
+        let mut diverging_crossovers: Vec<usize> = world.query::<&Crossover>().iter(world)
+            .filter(|crossover| crossover.diverging)
+            .map(|crossover| crossover.segment_id)
+            .collect();
+        diverging_crossovers.sort();
+        let mut couplings: Vec<(usize, usize)> = world.query::<&Coupling>().iter(world)
+            .filter_map(|coupling| Some((wagon_index(coupling.front_wagon)?, wagon_index(coupling.rear_wagon)?)))
+            .collect();
+        couplings.sort();
+
+        Controls {
+            locomotives,
+            brake_valves,
+            diverging_crossovers,
+            rail_condition: *world.resource::<RailCondition>(),
+            couplings,
+        }
+    }
+
+    fn apply(&self, world: &mut World) {
+        let wagons = get_wagons_in_spawn_order(world);
+        let wagon_index = |entity: Entity| wagons.iter().position(|wagon| *wagon == entity);
+
+        for (index, notch, reverser) in &self.locomotives {
+            if let Some(mut locomotive) = wagons.get(*index).and_then(|entity| world.get_mut::<Locomotive>(*entity)) {
+                locomotive.notch = *notch;
+                locomotive.reverser = *reverser;
+            }
+        }
+        for (index, position) in &self.brake_valves {
+            if let Some(mut brake_valve) = wagons.get(*index).and_then(|entity| world.get_mut::<DriversBrakeValve>(*entity)) {
+                brake_valve.position = *position;
+            }
+        }
+        for mut crossover in world.query::<&mut Crossover>().iter_mut(world) {
+            crossover.diverging = self.diverging_crossovers.contains(&crossover.segment_id);
+        }
+        *world.resource_mut::<RailCondition>() = self.rail_condition;
+
+        let uncoupled: Vec<Entity> = world.query::<(Entity, &Coupling)>().iter(world)
+            .filter(|(_, coupling)| match (wagon_index(coupling.front_wagon), wagon_index(coupling.rear_wagon)) {
+                (Some(front_wagon), Some(rear_wagon)) => !self.couplings.contains(&(front_wagon, rear_wagon)),
+                _ => false,
+            })
+            .map(|(entity, _)| entity)
+            .collect();
+        for entity in uncoupled {
+            world.despawn(entity);
+        }
+    }
+}
+
+impl BogieState {
+    fn capture_all(world: &mut World) -> Vec<Self> {
+        let wagons = get_wagons_in_spawn_order(world);
+        let mut bogies: Vec<(usize, bool, BogieState)> = world.query::<(&Bogie, &BogiePhysics, &AttachedToWagon)>().iter(world)
+            .filter_map(|(bogie, physics, attached_to)| {
+                let wagon_index = wagons.iter().position(|wagon| *wagon == attached_to.0)?;
+                Some((wagon_index, !bogie.is_leading.unwrap_or(false), BogieState {
+                    position_on_track: bogie.position_on_track,
+                    velocity: physics.velocity,
+                }))
+            })
+            .collect();
+        bogies.sort_by_key(|(wagon_index, is_trailing, _)| (*wagon_index, *is_trailing));
+        bogies.into_iter().map(|(_, _, state)| state).collect()
+    }
+
+    /// Whether the states are the same bit for bit.
+    fn matches(&self, other: &BogieState) -> bool {
+        self.position_on_track.to_bits() == other.position_on_track.to_bits() && self.velocity.to_bits() == other.velocity.to_bits()
+    }
+}
+
+#[derive(Resource, Default)]
+struct Recorder {
+    /// Where the recording is written, a new file in the recording directory if `None`.
+    path: Option<String>,
+    /// Starts recording at the next physics step.
+    armed: bool,
+    recording: Option<Recording>,
+    /// The controls after the last physics step. The controls changed since then are the inputs of the next step.
+    last_controls: Controls,
+}
+
+fn is_recording(recorder: Res<Recorder>) -> bool {
+    recorder.armed || recorder.recording.is_some()
+}
+
+/// The recording being replayed.
+#[derive(Resource)]
+pub struct Replay {
+    recording: Recording,
+    /// The physics stops before this tick: at the end of the recording, or at the tick being seeked.
+    stop_tick: u64,
+    seeking: bool,
+    /// The tick shown on the slider while it is dragged.
+    scrub_tick: Option<u64>,
+    /// The first tick after which the bogies differed from the recording.
+    pub diverged_at: Option<u64>,
+}
+
+impl Replay {
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    fn new(recording: Recording) -> Self {
+        Self {
+            stop_tick: recording.end_tick(),
+            recording,
+            seeking: false,
+            scrub_tick: None,
+            diverged_at: None,
+        }
+    }
+}
+
+fn replay_is_running(replay: Res<Replay>, tick: Res<PhysicsTick>) -> bool {
+    tick.0 < replay.stop_tick
+}
+
+/// Starts recording from the next physics step, or records the controls that changed since the last step.
+fn record_controls(world: &mut World) {
+    let tick = world.resource::<PhysicsTick>().0;
+    let controls = Controls::capture(world);
+    if world.resource::<Recorder>().armed {
+        let start = WorldSave::capture(world);
+        let mut recorder = world.resource_mut::<Recorder>();
+        recorder.armed = false;
+        recorder.recording = Some(Recording {
+            version: RECORDING_VERSION,
+            start,
+            inputs: vec![RecordedInput { tick, controls }],
+            trace: Vec::new(),
+        });
+        info!("Recording from tick {}", tick);
+        return;
+    }
+
+    let mut recorder = world.resource_mut::<Recorder>();
+    let recorder = &mut *recorder;
+    if let Some(recording) = recorder.recording.as_mut().filter(|_| controls != recorder.last_controls) {
+        recording.inputs.push(RecordedInput { tick, controls });
+    }
+}
+
+fn record_bogie_states(world: &mut World) {
+    let bogie_states = BogieState::capture_all(world);
+    let controls = Controls::capture(world);
+    let mut recorder = world.resource_mut::<Recorder>();
+    if let Some(recording) = &mut recorder.recording {
+        recording.trace.push(bogie_states);
+    }
+    recorder.last_controls = controls;
+}
+
+/// Returns the path of a new recording in the recording directory.
+fn get_recording_path(seed: u32) -> String {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default();
+    format!("{}/{}-{}.replay.ron", RECORDING_DIRECTORY, seed, timestamp)
+}
+
+/// Writes the recording in progress, returning the path it was written to.
+fn finish_recording(world: &mut World) -> Result<Option<String>, String> {
+    let mut recorder = world.resource_mut::<Recorder>();
+    recorder.armed = false;
+    let Some(recording) = recorder.recording.take() else {
+        return Ok(None);
+    };
+    let path = recorder.path.take().unwrap_or_else(|| get_recording_path(recording.start.config.noise.seed));
+    recording.write(&path)?;
+
+    Ok(Some(path))
+}
+
+/// Starts recording with F6, and stops and writes the recording when pressed again.
+fn record_on_key(world: &mut World) {
+    if !world.get_resource::<ButtonInput<KeyCode>>().is_some_and(|input| input.just_pressed(RECORD_KEY)) {
+        return;
+    }
+
+    let mut recorder = world.resource_mut::<Recorder>();
+    if recorder.recording.is_none() {
+        recorder.armed = !recorder.armed;
+        return;
+    }
+    match finish_recording(world) {
+        Ok(path) => info!("Recorded the run to {}", path.unwrap_or_default()),
+        Err(error) => warn!("Could not write the recording: {}", error),
+    }
+}
+
+/// Writes the recording and reports the result of the replay at the end of a headless run.
+/// Fails if the replay diverged from the recording.
+pub fn finish_headless_run(world: &mut World) -> Result<(), String> {
+    if let Some(path) = finish_recording(world)? {
+        println!("recorded to: {}", path);
+    }
+
+    let Some(replay) = world.get_resource::<Replay>() else {
+        return Ok(());
+    };
+    match replay.diverged_at {
+        Some(tick) => Err(format!("the replay diverged from the recording at tick {}", tick)),
+        None => {
+            println!("replay matches the recording: {} steps", replay.recording.trace.len());
+            Ok(())
+        },
+    }
+}
+
+fn apply_recorded_controls(world: &mut World) {
+    let tick = world.resource::<PhysicsTick>().0;
+    world.resource_scope(|world, replay: Mut<Replay>| {
+        let inputs = &replay.recording.inputs;
+        let first_input = inputs.partition_point(|input| input.tick < tick);
+        for input in inputs[first_input..].iter().take_while(|input| input.tick == tick) {
+            input.controls.apply(world);
+        }
+    });
+}
+
+/// Compares the bogies with the recorded trace after every step, and reports the first step where they differ.
+fn compare_bogie_states(world: &mut World) {
+    let tick = world.resource::<PhysicsTick>().0;
+    let bogie_states = BogieState::capture_all(world);
+    let mut replay = world.resource_mut::<Replay>();
+    if replay.diverged_at.is_some() {
+        return;
+    }
+    let Some(recorded_states) = tick.checked_sub(replay.recording.start.tick + 1).and_then(|index| replay.recording.trace.get(index as usize)) else {
+        return;
+    };
+
+    let difference = if bogie_states.len() != recorded_states.len() {
+        Some(format!("{} bogies, {} recorded", bogie_states.len(), recorded_states.len()))
+    } else {
+        bogie_states.iter().zip(recorded_states).enumerate()
+            .find(|(_, (state, recorded_state))| !state.matches(recorded_state))
+            .map(|(index, (state, recorded_state))| format!(
+                "bogie {} is at t {} with {} m/s, recorded at t {} with {} m/s",
+                index, state.position_on_track, state.velocity, recorded_state.position_on_track, recorded_state.velocity,
+            ))
+    };
+    if let Some(difference) = difference {
+        warn!("The physics diverged from the recording at tick {}: {}", tick, difference);
+        replay.diverged_at = Some(tick);
+    }
+}
+
+/// Pauses and resumes the replay, and seeks a tick. Seeking backwards restores the start of the recording
+/// and replays it up to the tick, faster than real time.
+fn replay_ui(
+    mut commands: Commands,
+    mut egui_contexts: EguiContexts,
+    mut replay: ResMut<Replay>,
+    tick: Res<PhysicsTick>,
+    mut time: ResMut<Time<Virtual>>,
+    pending_restore: Option<Res<PendingRestore>>,
+) {
+    let (start_tick, end_tick) = (replay.recording.start.tick, replay.recording.end_tick());
+    if pending_restore.is_none() && tick.0 >= replay.stop_tick && replay.seeking {
+        replay.seeking = false;
+        replay.stop_tick = end_tick;
+        time.set_relative_speed(1.);
+        time.pause();
+    }
+
+    let mut seek_tick = None;
+    egui::Window::new("Replay").show(egui_contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            let is_playing = !time.is_paused() && tick.0 < end_tick;
+            if ui.button(if is_playing { "Pause" } else { "Play" }).clicked() {
+                if is_playing {
+                    time.pause();
+                } else {
+                    time.unpause();
+                }
+            }
+            if ui.button("Restart").clicked() {
+                seek_tick = Some(start_tick);
+            }
+        });
+
+        let mut scrub_tick = replay.scrub_tick.unwrap_or(tick.0);
+        let response = ui.add(egui::Slider::new(&mut scrub_tick, start_tick..=end_tick).text("Tick"));
+        if response.drag_stopped() || (response.changed() && !response.dragged()) {
+            seek_tick = Some(scrub_tick);
+            replay.scrub_tick = None;
+        } else if response.dragged() {
+            replay.scrub_tick = Some(scrub_tick);
+        }
+
+        if replay.seeking {
+            ui.label(format!("Seeking tick {}...", replay.stop_tick));
+        }
+        match replay.diverged_at {
+            Some(tick) => { ui.colored_label(egui::Color32::RED, format!("Diverged from the recording at tick {}", tick)); },
+            None => { ui.label("Matches the recording"); },
+        }
+    });
+
+    if let Some(seek_tick) = seek_tick {
+        if seek_tick < tick.0 {
+            commands.insert_resource(PendingRestore(replay.recording.start.clone()));
+            replay.diverged_at = None;
+        }
+        replay.stop_tick = seek_tick;
+        replay.seeking = true;
+        time.set_relative_speed(SEEK_SPEED);
+        time.unpause();
+    }
+}
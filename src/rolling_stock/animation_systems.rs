@@ -0,0 +1,56 @@
+use std::f32::consts::TAU;
+use bevy::prelude::*;
+use crate::PHYSICS_TIMESTEP;
+use crate::rolling_stock::components::{BogiePhysics, Derailed, TrackNotLoaded, Wheels};
+
+/// The prefixes of the names of the nodes in the bogie scene that turn with the wheels.
+const WHEELSET_NODE_PREFIXES: [&str; 2] = ["Axle", "Wheel"];
+
+/// A node of a bogie scene that turns with the wheels around its local X axis (the axle).
+#[derive(Component)]
+pub(crate) struct Wheelset {
+    bogie: Entity,
+    /// The rotation of the node in the scene.
+    base_rotation: Quat,
+}
+
+/// Finds the axles and wheels in the bogie scenes once they are spawned.
+pub(crate) fn attach_wheelsets(
+    mut commands: Commands,
+    nodes_query: Query<(Entity, &Name, &Transform), Added<Name>>,
+    parents_query: Query<&Parent>,
+    bogies_query: Query<(), With<Wheels>>,
+) {
+    for (entity, name, transform) in &nodes_query {
+        if !WHEELSET_NODE_PREFIXES.iter().any(|prefix| name.as_str().starts_with(prefix)) {
+            continue;
+        }
+        let Some(bogie) = parents_query.iter_ancestors(entity).find(|ancestor| bogies_query.contains(*ancestor)) else {
+            continue;
+        };
+        commands.entity(entity).insert(Wheelset { bogie, base_rotation: transform.rotation });
+    }
+}
+
+/// Turns the wheels by the distance the bogies moved in the physics step.
+pub(crate) fn turn_wheels(
+    mut bogies_query: Query<(&BogiePhysics, &mut Wheels), (Without<Derailed>, Without<TrackNotLoaded>)>,
+) {
+    for (bogie_physics, mut wheels) in &mut bogies_query {
+        if wheels.radius <= 0. {
+            continue;
+        }
+        wheels.angle = (wheels.angle + bogie_physics.velocity * PHYSICS_TIMESTEP / wheels.radius).rem_euclid(TAU);
+    }
+}
+
+pub(crate) fn update_wheelset_transforms(
+    mut wheelsets_query: Query<(&Wheelset, &mut Transform)>,
+    wheels_query: Query<&Wheels>,
+) {
+    for (wheelset, mut transform) in &mut wheelsets_query {
+        let Ok(wheels) = wheels_query.get(wheelset.bogie) else { continue };
+        // The bogies run towards -Z, so moving forward turns the wheels backwards around X
+        transform.rotation = Quat::from_rotation_x(-wheels.angle) * wheelset.base_rotation;
+    }
+}
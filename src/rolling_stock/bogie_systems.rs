@@ -1,30 +1,106 @@
 use bevy::prelude::*;
+use bevy::utils::HashSet;
 use crate::{noise, NoiseSettings, PHYSICS_TIMESTEP};
 use crate::rolling_stock::{utils};
 
-use crate::rolling_stock::components::{AttachedToWagon, Bogie, BogiePhysics, WagonPhysics};
-use crate::world::terrain::FAR_GRID_CHUNK_SIZE;
-use crate::world::train_tracks::Track;
+use crate::rolling_stock::components::{AttachedToWagon, Bogie, BogiePhysics, Coupling, Derailed, RunningResistance, SharedWithWagon, TrackNotLoaded, WagonPhysics};
+use crate::rolling_stock::wagon_systems::WagonBogieCounts;
+use crate::world::route_gen::Route;
+use crate::world::track_profiles::BOGIE_MODEL_GAUGE;
+use crate::world::train_tracks::{Crossover, follow_track, get_bogie_line_position_at_t, PlacementData, Track};
 
-const GRAV_ACCELERATION: f32 = -9.8;
+pub(crate) const GRAV_ACCELERATION: f32 = 9.8;
 const T_COEFFICIENT: f32 = 100.;
 
-const KINETIC_FRICTION_COEFFICIENT: f32 = 0.001;
+/// The breakaway resistance of a standing vehicle as a share of its weight.
 const STATIC_FRICTION_COEFFICIENT: f32 = 0.01;
+/// The smallest curve radius the curve resistance formula is evaluated for, in m.
+const MIN_CURVE_RADIUS: f32 = 60.;
+
+/// Marks the bogies of the consists with a bogie on a track that is not sampled around it, and unmarks them once it is.
+/// Otherwise the steps of the consist would depend on how far the track loading (which runs every frame) has progressed.
+/// The other consists keep running. Bogies on the first or the last built segment do not wait for the next one, so that they can run off the end of the track.
+pub(crate) fn mark_bogies_on_unloaded_track(
+    mut commands: Commands,
+    bogies_query: Query<(Entity, &Bogie, &AttachedToWagon, Has<TrackNotLoaded>), Without<Derailed>>,
+    track_query: Query<&Track>,
+    couplings_query: Query<(Entity, &Coupling)>,
+    placement_data: Res<PlacementData>,
+    route: Res<Route>,
+) {
+    let segment_ids = placement_data.segment_ids(&route);
+    let (first_segment_t, last_segment_t) = (*segment_ids.start() as f32, *segment_ids.end() as f32);
+    let is_loaded = |bogie: &Bogie| {
+        bogie.current_track
+            .and_then(|entity| track_query.get(entity).ok())
+            .is_some_and(|track| track.is_loaded_around_t(bogie.position_on_track.min(last_segment_t - 1.).max(first_segment_t + 1.)))
+    };
+    let waiting_wagons: HashSet<Entity> = bogies_query.iter()
+        .filter(|(_, bogie, ..)| !is_loaded(bogie))
+        .flat_map(|(_, _, attached_to, _)| utils::get_consist(attached_to.0, &couplings_query))
+        .collect();
+
+    for (entity, _, attached_to, is_marked) in &bogies_query {
+        let is_waiting = waiting_wagons.contains(&attached_to.0);
+        if is_waiting && !is_marked {
+            commands.entity(entity).insert(TrackNotLoaded);
+        } else if !is_waiting && is_marked {
+            commands.entity(entity).remove::<TrackNotLoaded>();
+        }
+    }
+}
+
+/// Remembers the positions of the bogies before the physics step, to interpolate the rendered positions.
+pub(crate) fn store_previous_bogie_positions(
+    mut bogies_query: Query<&mut Bogie>,
+) {
+    for mut bogie in &mut bogies_query {
+        bogie.previous_position_on_track = bogie.position_on_track;
+    }
+}
+
+/// Puts the bogies that are not on any track onto the first track of the route.
+pub(crate) fn assign_bogie_tracks(
+    mut bogies_query: Query<&mut Bogie, Without<Derailed>>,
+    track_query: Query<(Entity, &Track)>,
+) {
+    let Some((first_track, _)) = track_query.iter().min_by_key(|(_, track)| track.index()) else {
+        return;
+    };
+
+    for mut bogie in &mut bogies_query {
+        if bogie.current_track.is_none() {
+            bogie.current_track = Some(first_track);
+        }
+    }
+}
 
 pub(crate) fn apply_bogie_velocities(
-    mut bogies_query: Query<(&BogiePhysics, &mut Bogie)>,
+    mut bogies_query: Query<(&BogiePhysics, &mut Bogie), Without<TrackNotLoaded>>,
+    crossover_query: Query<&Crossover>,
 ) {
     for (physics , mut bogie) in &mut bogies_query {
-        bogie.position_on_track += physics.velocity * PHYSICS_TIMESTEP / T_COEFFICIENT;
+        let old_t = bogie.position_on_track;
+        bogie.position_on_track = old_t + physics.velocity * PHYSICS_TIMESTEP / T_COEFFICIENT;
+        switch_track_at_crossovers(&mut bogie, old_t, &crossover_query);
     }
 }
 
+/// Moves the bogie onto the diverging rails of the crossovers it entered since it was at `old_t`,
+/// and onto the other track at the far end of the crossovers it left.
+pub(crate) fn switch_track_at_crossovers(bogie: &mut Bogie, old_t: f32, crossover_query: &Query<&Crossover>) {
+    let Some(current_track) = bogie.current_track else { return };
+    let (track, on_crossover) = follow_track(crossover_query, current_track, bogie.on_crossover, old_t, bogie.position_on_track);
+    bogie.current_track = Some(track);
+    bogie.on_crossover = on_crossover;
+}
+
 pub(crate) fn apply_bogie_forces(
-    mut bogies_query: Query<(&mut BogiePhysics, Option<&AttachedToWagon>)>,
+    mut bogies_query: Query<(&mut BogiePhysics, Option<&AttachedToWagon>, Option<&SharedWithWagon>), Without<TrackNotLoaded>>,
     wagons_query: Query<&WagonPhysics>,
+    bogie_counts: Res<WagonBogieCounts>,
 ) {
-    for (mut bogie_physics, attached_to) in &mut bogies_query {
+    for (mut bogie_physics, attached_to, shared_with) in &mut bogies_query {
         if bogie_physics.current_slope_angle.is_none() {
             continue;
         }
@@ -34,28 +110,53 @@ pub(crate) fn apply_bogie_forces(
             bogie_physics.velocity = 0.;
         }
 
-        // Do not start moving the bogie if the sum of vertical and horizontal forces is less than the static force.
+        // Do not start moving the bogie if the sum of vertical, horizontal and coupler forces is less than the static force.
+        let driving_force = bogie_physics.vertical_force + bogie_physics.horizontal_force + bogie_physics.coupler_force;
         if bogie_physics.velocity == 0. {
-            if (bogie_physics.vertical_force + bogie_physics.horizontal_force).abs() <= bogie_physics.static_force {
+            if driving_force.abs() <= bogie_physics.static_force {
                 continue;
             }
         }
 
-        let mass = utils::get_carried_mass(attached_to, &bogie_physics, &wagons_query);
+        let carried_wagons = utils::get_carried_wagons(attached_to, shared_with, &bogie_counts);
+        let mass = utils::get_carried_mass(&carried_wagons, &bogie_physics, &wagons_query);
 
         // Apply the kinetic force (opposite to velocity).
         bogie_physics.velocity += (-1. * bogie_physics.velocity.signum()) * (bogie_physics.kinetic_force / mass * PHYSICS_TIMESTEP);
 
-        // Finally, apply the vertical and horizontal velocities.
-        bogie_physics.velocity += (bogie_physics.vertical_force + bogie_physics.horizontal_force) / mass * PHYSICS_TIMESTEP;
+        // Finally, apply the vertical, horizontal and coupler velocities.
+        bogie_physics.velocity += driving_force / mass * PHYSICS_TIMESTEP;
     }
 }
 
+/// Returns the curve resistance of the given mass in N on track of the given gauge in m, following Röckl's formula.
+/// Its constants are given for standard, metre, 750 mm and 600 mm gauge, the ones of the nearest gauge are used.
+fn get_curve_resistance(mass: f32, curve_radius: f32, gauge: f32) -> f32 {
+    if curve_radius.is_infinite() {
+        return 0.;
+    }
+    let radius = curve_radius.max(MIN_CURVE_RADIUS);
+    // The specific resistance in N per kN of weight
+    let specific_resistance = match gauge {
+        gauge if gauge >= 1.22 => if radius >= 300. { 650. / (radius - 55.) } else { 500. / (radius - 30.) },
+        gauge if gauge >= 0.875 => 400. / (radius - 20.),
+        gauge if gauge >= 0.675 => 350. / (radius - 10.),
+        _ => 300. / (radius - 5.),
+    };
+
+    specific_resistance * mass * GRAV_ACCELERATION / 1000.
+}
+
+/// Sets the forces opposing the motion of the bogies: the brakes and the running resistance of the wagons (split between their bogies),
+/// and the curve resistance. The static force also includes the breakaway resistance.
 pub(crate) fn set_bogie_static_kinetic_forces(
-    mut bogies_query: Query<(&mut BogiePhysics, Option<&AttachedToWagon>)>,
+    mut bogies_query: Query<(&Bogie, &mut BogiePhysics, Option<&AttachedToWagon>, Option<&SharedWithWagon>)>,
     wagons_query: Query<&WagonPhysics>,
+    resistance_query: Query<&RunningResistance>,
+    track_query: Query<&Track>,
+    bogie_counts: Res<WagonBogieCounts>,
 ) {
-    for (mut bogie_physics, attached_to) in &mut bogies_query {
+    for (bogie, mut bogie_physics, attached_to, shared_with) in &mut bogies_query {
         let slope_angle = bogie_physics.current_slope_angle;
         if slope_angle.is_none() {
             println!("(static+kinetic forces) slope angle is none, skipping this bogie");
@@ -63,41 +164,44 @@ pub(crate) fn set_bogie_static_kinetic_forces(
         }
         let slope_cos = slope_angle.unwrap().cos();
 
-        let wagon_braking_force = if let Some(attached_to) = attached_to {
-            let wagon_physics = wagons_query.get(attached_to.0).unwrap();
-            wagon_physics.braking_force
-        } else {
-            0.
-        };
+        let carried_wagons = utils::get_carried_wagons(attached_to, shared_with, &bogie_counts);
+        let braking_force: f32 = carried_wagons.iter()
+            .map(|(wagon, share)| wagons_query.get(*wagon).unwrap().braking_force * share)
+            .sum();
+        let running_resistance: f32 = carried_wagons.iter()
+            .filter_map(|(wagon, share)| resistance_query.get(*wagon).ok().map(|resistance| resistance.get_force(bogie_physics.velocity) * share))
+            .sum();
 
-        let mass = utils::get_carried_mass(attached_to, &bogie_physics, &wagons_query);
+        let mass = utils::get_carried_mass(&carried_wagons, &bogie_physics, &wagons_query);
         let static_friction = STATIC_FRICTION_COEFFICIENT * mass * GRAV_ACCELERATION * slope_cos;
-        let kinetic_friction = KINETIC_FRICTION_COEFFICIENT * mass * GRAV_ACCELERATION * slope_cos;
-        bogie_physics.static_force = wagon_braking_force / 2. + static_friction.abs();
-        bogie_physics.kinetic_force = wagon_braking_force / 2. + kinetic_friction.abs();
+        let gauge = bogie.current_track.and_then(|track| track_query.get(track).ok()).map(|track| track.gauge());
+        let curve_resistance = match (bogie_physics.current_curve_radius, gauge) {
+            (Some(curve_radius), Some(gauge)) => get_curve_resistance(mass, curve_radius, gauge),
+            _ => 0.,
+        };
+        bogie_physics.static_force = braking_force + static_friction + curve_resistance;
+        bogie_physics.kinetic_force = braking_force + running_resistance + curve_resistance;
     }
 }
 
 pub(crate) fn set_bogie_horizontal_forces(
-    mut bogies_query: Query<(&mut BogiePhysics, Option<&AttachedToWagon>)>,
+    mut bogies_query: Query<(&mut BogiePhysics, Option<&AttachedToWagon>, Option<&SharedWithWagon>)>,
     wagons_query: Query<&WagonPhysics>,
+    bogie_counts: Res<WagonBogieCounts>,
 ) {
-    for (mut bogie_physics, attached_to) in &mut bogies_query {
-        let mut wagon_tractive_force = 0.;
-        if let Some(attached_to) = attached_to {
-            let wagon_physics = wagons_query.get(attached_to.0).unwrap();
-            wagon_tractive_force = wagon_physics.tractive_force;
-        }
-
-        bogie_physics.horizontal_force = wagon_tractive_force / 2.;
+    for (mut bogie_physics, attached_to, shared_with) in &mut bogies_query {
+        bogie_physics.horizontal_force = utils::get_carried_wagons(attached_to, shared_with, &bogie_counts).iter()
+            .map(|(wagon, share)| wagons_query.get(*wagon).unwrap().tractive_force * share)
+            .sum();
     }
 }
 
 pub(crate) fn set_bogie_vertical_forces(
-    mut bogies_query: Query<(&mut BogiePhysics, Option<&AttachedToWagon>)>,
+    mut bogies_query: Query<(&mut BogiePhysics, Option<&AttachedToWagon>, Option<&SharedWithWagon>)>,
     wagons_query: Query<&WagonPhysics>,
+    bogie_counts: Res<WagonBogieCounts>,
 ) {
-    for (mut bogie_physics, attached_to) in &mut bogies_query {
+    for (mut bogie_physics, attached_to, shared_with) in &mut bogies_query {
         let slope_angle = bogie_physics.current_slope_angle;
         if slope_angle.is_none() {
             println!("(vertical forces) slope angle is none, skipping this bogie");
@@ -105,8 +209,9 @@ pub(crate) fn set_bogie_vertical_forces(
         }
         let slope_sin = slope_angle.unwrap().sin();
 
-        let mass = utils::get_carried_mass(attached_to, &bogie_physics, &wagons_query);
-        bogie_physics.vertical_force = mass * GRAV_ACCELERATION * slope_sin;
+        let carried_wagons = utils::get_carried_wagons(attached_to, shared_with, &bogie_counts);
+        let mass = utils::get_carried_mass(&carried_wagons, &bogie_physics, &wagons_query);
+        bogie_physics.vertical_force = -mass * GRAV_ACCELERATION * slope_sin;
     }
 }
 
@@ -115,32 +220,48 @@ pub(crate) fn update_bogie_current_slope_angle(
     track_query: Query<&Track>,
     noise_settings: Res<NoiseSettings>,
 ) {
-    if track_query.is_empty() {
-        return;
-    }
-
-    let track = track_query.single();
     for (mut bogie_physics, bogie) in &mut bogies_query {
-        let height_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings.clone(), Vec3::ZERO);
+        let Some(track) = bogie.current_track.and_then(|entity| track_query.get(entity).ok()) else {
+            continue;
+        };
+        let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
         let slope_angle = track.get_slope_angle_at_t(bogie.position_on_track, &height_fn);
         bogie_physics.current_slope_angle = slope_angle;
     }
 }
 
-pub(crate) fn update_bogie_transforms(
-    mut bogies_query: Query<(&mut Transform, &BogiePhysics, &Bogie)>,
+pub(crate) fn update_bogie_current_curve_radius(
+    mut bogies_query: Query<(&mut BogiePhysics, &Bogie)>,
     track_query: Query<&Track>,
     noise_settings: Res<NoiseSettings>,
 ) {
-    if track_query.is_empty() {
-        return;
+    for (mut bogie_physics, bogie) in &mut bogies_query {
+        let Some(track) = bogie.current_track.and_then(|entity| track_query.get(entity).ok()) else {
+            continue;
+        };
+        let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+        bogie_physics.current_curve_radius = track.get_curve_radius_at_t(bogie.position_on_track, &height_fn);
     }
+}
 
-    let track = track_query.single();
+/// Places the bogies on the track. The position is interpolated between the last two physics steps,
+/// so that the movement is smooth regardless of the frame rate.
+pub(crate) fn update_bogie_transforms(
+    mut bogies_query: Query<(&mut Transform, &BogiePhysics, &Bogie), Without<Derailed>>,
+    track_query: Query<&Track>,
+    crossover_query: Query<&Crossover>,
+    noise_settings: Res<NoiseSettings>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    let overstep = fixed_time.overstep_fraction();
     for (mut bogie_transform, bogie_physics, bogie) in &mut bogies_query {
-        let t = bogie.position_on_track;
-        let height_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings.clone(), Vec3::ZERO);
-        let point_option = track.get_interpolated_position_at_t(t, &height_fn);
+        let Some(track_entity) = bogie.current_track else { continue };
+        let Ok(track) = track_query.get(track_entity) else { continue };
+
+        // Followed back from the current position, so that the track switched to in the last step is only used past the switch
+        let t = bogie.previous_position_on_track + (bogie.position_on_track - bogie.previous_position_on_track) * overstep;
+        let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+        let point_option = get_bogie_line_position_at_t(&track_query, &crossover_query, bogie, t, &height_fn);
         let angle = bogie_physics.current_slope_angle;
         if angle.is_none() {
             return;
@@ -150,6 +271,83 @@ pub(crate) fn update_bogie_transforms(
             let angle_rotation = Quat::from_rotation_x(angle.unwrap());
             bogie_transform.translation = position;
             bogie_transform.rotation = rotation * angle_rotation;
+            // Stretch the wheelsets to the gauge of the track
+            bogie_transform.scale.x = track.gauge() / BOGIE_MODEL_GAUGE;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use crate::rolling_stock::wagon_systems::count_wagon_bogies;
+    use super::*;
+
+    const MASS: f32 = 10000.;
+
+    /// Checks the curve resistance of `MASS` against the specific resistance in N per kN of weight.
+    fn assert_curve_resistance(curve_radius: f32, gauge: f32, specific_resistance: f32) {
+        let expected = specific_resistance * MASS * GRAV_ACCELERATION / 1000.;
+        let resistance = get_curve_resistance(MASS, curve_radius, gauge);
+        assert!((resistance - expected).abs() < 0.01, "the resistance at {} m is {} N instead of {} N", curve_radius, resistance, expected);
+    }
+
+    #[test]
+    fn curve_resistance_follows_rockls_formula() {
+        assert_curve_resistance(f32::INFINITY, 1.435, 0.);
+        // 650 / (R - 55) from 300 m on, 500 / (R - 30) below on standard gauge
+        assert_curve_resistance(400., 1.435, 650. / 345.);
+        assert_curve_resistance(300., 1.435, 650. / 245.);
+        assert_curve_resistance(200., 1.435, 500. / 170.);
+        // Sharper curves are treated as the sharpest one the formula holds for
+        assert_curve_resistance(30., 1.435, 500. / (MIN_CURVE_RADIUS - 30.));
+        // 400 / (R - 20) on metre gauge, 350 / (R - 10) on 750 mm gauge and 300 / (R - 5) on 600 mm gauge
+        assert_curve_resistance(200., 1., 400. / 180.);
+        assert_curve_resistance(200., 0.76, 350. / 190.);
+        assert_curve_resistance(200., 0.6, 300. / 195.);
+    }
+
+    #[test]
+    fn gravity_pulls_bogies_down_the_slope() {
+        let mut world = World::new();
+        let slope_angle = 0.02_f32;
+        let uphill = world.spawn(BogiePhysics { mass: MASS, current_slope_angle: Some(slope_angle), ..default() }).id();
+        let downhill = world.spawn(BogiePhysics { mass: MASS, current_slope_angle: Some(-slope_angle), ..default() }).id();
+
+        world.init_resource::<WagonBogieCounts>();
+        world.run_system_once(set_bogie_vertical_forces);
+
+        // The slope rises towards a greater t, so the force points towards a smaller t
+        let expected = MASS * GRAV_ACCELERATION * slope_angle.sin();
+        assert_eq!(world.get::<BogiePhysics>(uphill).unwrap().vertical_force, -expected);
+        assert_eq!(world.get::<BogiePhysics>(downhill).unwrap().vertical_force, expected);
+    }
+
+    #[test]
+    fn articulation_bogies_carry_a_share_of_both_wagons() {
+        let mut world = World::new();
+        let slope_angle = 0.02_f32;
+        let front_wagon = world.spawn(WagonPhysics { mass: 4. * MASS, tractive_force: 3000., ..default() }).id();
+        let rear_wagon = world.spawn(WagonPhysics { mass: 2. * MASS, ..default() }).id();
+        let bogie_physics = || BogiePhysics { mass: MASS, current_slope_angle: Some(slope_angle), ..default() };
+        let front = world.spawn((bogie_physics(), AttachedToWagon(front_wagon))).id();
+        let shared = world.spawn((bogie_physics(), AttachedToWagon(front_wagon), SharedWithWagon(rear_wagon))).id();
+        let rear = world.spawn((bogie_physics(), AttachedToWagon(rear_wagon))).id();
+
+        world.init_resource::<WagonBogieCounts>();
+        world.run_system_once(count_wagon_bogies);
+        world.run_system_once(set_bogie_vertical_forces);
+        world.run_system_once(set_bogie_horizontal_forces);
+
+        // Each wagon rests on two bogies, one of them shared
+        let assert_carried = |bogie: Entity, mass: f32, tractive_force: f32| {
+            let physics = world.get::<BogiePhysics>(bogie).unwrap();
+            let expected_vertical_force = -mass * GRAV_ACCELERATION * slope_angle.sin();
+            assert!((physics.vertical_force - expected_vertical_force).abs() < 0.01, "the vertical force is {} N instead of {} N", physics.vertical_force, expected_vertical_force);
+            assert_eq!(physics.horizontal_force, tractive_force);
+        };
+        assert_carried(front, 3. * MASS, 1500.);
+        assert_carried(shared, 4. * MASS, 1500.);
+        assert_carried(rear, 2. * MASS, 0.);
+    }
+}
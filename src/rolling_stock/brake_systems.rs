@@ -0,0 +1,114 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use crate::PHYSICS_TIMESTEP;
+use crate::rolling_stock::components::{AirBrake, BrakeValvePosition, Coupling, DriversBrakeValve, WagonPhysics};
+
+/// The brake pipe pressure of released brakes in bar.
+pub(crate) const RUNNING_PIPE_PRESSURE: f32 = 5.;
+/// The brake pipe pressure of a full service application in bar.
+const FULL_SERVICE_PIPE_PRESSURE: f32 = 3.5;
+/// The rates (in bar/s) at which the driver's brake valve changes the brake pipe pressure.
+const VALVE_CHARGE_RATE: f32 = 0.5;
+const VALVE_SERVICE_RATE: f32 = 0.3;
+const VALVE_EMERGENCY_RATE: f32 = 5.;
+/// The share of the pressure difference between two coupled wagons that equalises per second.
+/// Makes pressure changes propagate along the consist with a delay.
+const PIPE_FLOW_RATE: f32 = 4.;
+/// The pressure difference between the brake pipe and the auxiliary reservoir the triple valve reacts to, in bar.
+const TRIPLE_VALVE_SENSITIVITY: f32 = 0.05;
+/// The brake pipe pressure below which the triple valve makes an emergency application, in bar.
+const EMERGENCY_PIPE_PRESSURE: f32 = 2.;
+/// The volume of the auxiliary reservoir relative to the brake cylinder. A full service reduction of the brake pipe
+/// pressure (1.5 bar) fills the brake cylinder to its maximum pressure (3.75 bar).
+const AUXILIARY_TO_CYLINDER_RATIO: f32 = 2.5;
+/// The rates (in bar/s) at which the triple valve applies, releases and recharges.
+const APPLICATION_RATE: f32 = 0.6;
+const EMERGENCY_APPLICATION_RATE: f32 = 3.;
+const RELEASE_RATE: f32 = 0.3;
+const RECHARGE_RATE: f32 = 0.2;
+
+/// Moves the brake pipe pressure of the locomotives towards the pressure set by the driver's brake valve,
+/// and lets the pressure flow between coupled wagons.
+pub(crate) fn update_brake_pipes(
+    mut air_brake_query: Query<(&mut AirBrake, Option<&DriversBrakeValve>)>,
+    couplings_query: Query<&Coupling>,
+) {
+    for (mut air_brake, valve) in &mut air_brake_query {
+        let Some(valve) = valve else { continue };
+        let pressure = air_brake.brake_pipe_pressure;
+        air_brake.brake_pipe_pressure = match valve.position {
+            BrakeValvePosition::Release => (pressure + VALVE_CHARGE_RATE * PHYSICS_TIMESTEP).min(RUNNING_PIPE_PRESSURE),
+            BrakeValvePosition::Lap => pressure,
+            BrakeValvePosition::Service if pressure > FULL_SERVICE_PIPE_PRESSURE => {
+                (pressure - VALVE_SERVICE_RATE * PHYSICS_TIMESTEP).max(FULL_SERVICE_PIPE_PRESSURE)
+            },
+            BrakeValvePosition::Service => pressure,
+            BrakeValvePosition::Emergency => (pressure - VALVE_EMERGENCY_RATE * PHYSICS_TIMESTEP).max(0.),
+        };
+    }
+
+    // Calculate all the flows from the current pressures first, so that the order of the couplings doesn't matter
+    let mut pressure_changes = HashMap::<Entity, f32>::new();
+    for coupling in &couplings_query {
+        let (Ok((front, _)), Ok((rear, _))) = (air_brake_query.get(coupling.front_wagon), air_brake_query.get(coupling.rear_wagon)) else {
+            continue;
+        };
+        let flow = (front.brake_pipe_pressure - rear.brake_pipe_pressure) * PIPE_FLOW_RATE * PHYSICS_TIMESTEP;
+        *pressure_changes.entry(coupling.front_wagon).or_insert(0.) -= flow;
+        *pressure_changes.entry(coupling.rear_wagon).or_insert(0.) += flow;
+    }
+    for (wagon, change) in pressure_changes {
+        if let Ok((mut air_brake, _)) = air_brake_query.get_mut(wagon) {
+            air_brake.brake_pipe_pressure = (air_brake.brake_pipe_pressure + change).max(0.);
+        }
+    }
+}
+
+/// Applies or releases the brake of each wagon depending on the brake pipe pressure, like a triple valve.
+/// A drop of the brake pipe pressure below the auxiliary reservoir pressure lets air from the reservoir into the brake cylinder,
+/// a rise above it vents the brake cylinder and recharges the reservoir.
+pub(crate) fn update_triple_valves(
+    mut air_brake_query: Query<&mut AirBrake>,
+) {
+    for mut air_brake in &mut air_brake_query {
+        let pipe_pressure = air_brake.brake_pipe_pressure;
+        let reservoir_pressure = air_brake.auxiliary_reservoir_pressure;
+
+        if pipe_pressure < EMERGENCY_PIPE_PRESSURE {
+            let transfer = (EMERGENCY_APPLICATION_RATE * PHYSICS_TIMESTEP / AUXILIARY_TO_CYLINDER_RATIO).min(reservoir_pressure);
+            apply_brake(&mut air_brake, transfer);
+        } else if pipe_pressure < reservoir_pressure - TRIPLE_VALVE_SENSITIVITY {
+            let transfer = (APPLICATION_RATE * PHYSICS_TIMESTEP / AUXILIARY_TO_CYLINDER_RATIO).min(reservoir_pressure - pipe_pressure);
+            apply_brake(&mut air_brake, transfer);
+        } else if pipe_pressure > reservoir_pressure + TRIPLE_VALVE_SENSITIVITY {
+            air_brake.brake_cylinder_pressure = (air_brake.brake_cylinder_pressure - RELEASE_RATE * PHYSICS_TIMESTEP).max(0.);
+            air_brake.auxiliary_reservoir_pressure = (reservoir_pressure + RECHARGE_RATE * PHYSICS_TIMESTEP).min(pipe_pressure);
+        }
+    }
+}
+
+/// Moves the given pressure from the auxiliary reservoir into the brake cylinder, unless it's already full.
+fn apply_brake(air_brake: &mut AirBrake, reservoir_pressure_drop: f32) {
+    if air_brake.brake_cylinder_pressure >= air_brake.max_cylinder_pressure {
+        return;
+    }
+    air_brake.auxiliary_reservoir_pressure -= reservoir_pressure_drop;
+    air_brake.brake_cylinder_pressure = (air_brake.brake_cylinder_pressure + reservoir_pressure_drop * AUXILIARY_TO_CYLINDER_RATIO)
+        .min(air_brake.max_cylinder_pressure);
+}
+
+/// Returns the friction coefficient between cast iron brake blocks and the wheels, which decreases with speed.
+fn get_block_friction_coefficient(speed: f32) -> f32 {
+    let speed_kmh = speed.abs() * 3.6;
+    0.25 * (speed_kmh + 100.) / (5. * speed_kmh + 100.)
+}
+
+/// Sets the braking force of each wagon from its brake cylinder pressure.
+pub(crate) fn set_brake_forces(
+    mut wagons_query: Query<(&AirBrake, &mut WagonPhysics)>,
+) {
+    for (air_brake, mut wagon_physics) in &mut wagons_query {
+        let block_force = air_brake.brake_cylinder_pressure * air_brake.block_force_per_bar;
+        wagon_physics.braking_force = block_force * get_block_friction_coefficient(wagon_physics.velocity);
+    }
+}
@@ -0,0 +1,162 @@
+use bevy::prelude::*;
+use crate::PHYSICS_TIMESTEP;
+use crate::rolling_stock::components::{AttachedToWagon, Bogie, Cargo, CargoSpace, CargoTransfer, CargoType, Derailed, Wagon, WagonPhysics};
+use crate::world::train_tracks::PlacementData;
+
+/// The number of track segments between loading points along the route.
+const LOADING_POINT_INTERVAL: usize = 15;
+/// The rate cargo is loaded or unloaded at in kg/s.
+const TRANSFER_RATE: f32 = 2500.;
+/// The highest speed in m/s a wagon can be loaded or unloaded at.
+const MAX_TRANSFER_SPEED: f32 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum LoadingPointKind {
+    /// Loads wagons with the given cargo. Wagons already carrying another cargo are left alone.
+    Load(CargoType),
+    Unload,
+}
+
+/// A place along the route where wagons standing in the given segment (on any of the tracks) are loaded or unloaded.
+#[derive(Component)]
+pub(crate) struct LoadingPoint {
+    pub(crate) segment_id: usize,
+    pub(crate) kind: LoadingPointKind,
+}
+
+/// Marks the mesh showing the cargo inside a wagon. Spawned as a child of the wagon.
+#[derive(Component)]
+pub(crate) struct CargoLoad;
+
+/// Designates every `LOADING_POINT_INTERVAL`th segment as a loading point, alternating between loading and unloading.
+pub(crate) fn place_loading_points(
+    mut commands: Commands,
+    placement_data: Res<PlacementData>,
+    mut last_checked_segment_id: Local<usize>,
+) {
+    let current_segment_id = placement_data.current_segment_id();
+    for segment_id in (*last_checked_segment_id + 1)..=current_segment_id {
+        if segment_id % LOADING_POINT_INTERVAL != 0 {
+            continue;
+        }
+        let index = segment_id / LOADING_POINT_INTERVAL;
+        let kind = if index % 2 == 1 {
+            LoadingPointKind::Load(CargoType::ALL[(index / 2) % CargoType::ALL.len()])
+        } else {
+            LoadingPointKind::Unload
+        };
+        commands.spawn(LoadingPoint { segment_id, kind });
+    }
+    *last_checked_segment_id = current_segment_id.max(*last_checked_segment_id);
+}
+
+/// Returns the position of the wagon on the track, halfway between its bogies.
+fn get_wagon_t(wagon: Entity, bogies_query: &Query<(&Bogie, &AttachedToWagon)>) -> Option<f32> {
+    let bogie_ts: Vec<f32> = bogies_query.iter()
+        .filter(|(_, attached_to)| attached_to.0 == wagon)
+        .map(|(bogie, _)| bogie.position_on_track)
+        .collect();
+    if bogie_ts.is_empty() {
+        return None;
+    }
+
+    Some(bogie_ts.iter().sum::<f32>() / bogie_ts.len() as f32)
+}
+
+/// Loads and unloads the wagons standing (or crawling) at the loading points.
+pub(crate) fn transfer_cargo(
+    mut wagons_query: Query<(Entity, &Wagon, &WagonPhysics, &CargoSpace, &mut Cargo), Without<Derailed>>,
+    bogies_query: Query<(&Bogie, &AttachedToWagon)>,
+    loading_points_query: Query<&LoadingPoint>,
+) {
+    for (entity, wagon, wagon_physics, cargo_space, mut cargo) in &mut wagons_query {
+        // Only touch the cargo when something changes, the load meshes are updated on change
+        if cargo.transfer != CargoTransfer::Idle {
+            cargo.transfer = CargoTransfer::Idle;
+        }
+        if wagon_physics.velocity.abs() > MAX_TRANSFER_SPEED {
+            continue;
+        }
+        let Some(t) = get_wagon_t(entity, &bogies_query) else { continue };
+        let Some(loading_point) = loading_points_query.iter().find(|point| point.segment_id == t.floor() as usize) else {
+            continue;
+        };
+
+        // The change of the fill level in this step
+        let step = TRANSFER_RATE * PHYSICS_TIMESTEP / (cargo_space.volume() * cargo.density);
+        match loading_point.kind {
+            LoadingPointKind::Load(cargo_type) => {
+                if cargo.fill_level == 0. && cargo.cargo_type != cargo_type {
+                    *cargo = Cargo::empty(cargo_type);
+                }
+                let max_fill_level = cargo.get_max_fill_level(cargo_space, wagon.capacity);
+                if cargo.cargo_type == cargo_type && cargo.fill_level < max_fill_level {
+                    cargo.fill_level = (cargo.fill_level + step).min(max_fill_level);
+                    cargo.transfer = CargoTransfer::Loading;
+                }
+            },
+            LoadingPointKind::Unload => {
+                if cargo.fill_level > 0. {
+                    cargo.fill_level = (cargo.fill_level - step).max(0.);
+                    cargo.transfer = CargoTransfer::Unloading;
+                }
+            },
+        }
+    }
+}
+
+/// Sets the mass of the wagons carrying cargo to their tare mass plus the mass of the cargo.
+pub(crate) fn update_wagon_masses(
+    mut wagons_query: Query<(&Wagon, &CargoSpace, &Cargo, &mut WagonPhysics)>,
+) {
+    for (wagon, cargo_space, cargo, mut wagon_physics) in &mut wagons_query {
+        wagon_physics.mass = wagon.tare_mass + cargo.get_mass(cargo_space);
+    }
+}
+
+/// Spawns the load meshes inside the wagons with a cargo space.
+pub(crate) fn spawn_cargo_loads(
+    mut commands: Commands,
+    wagons_query: Query<(Entity, &Cargo), Added<CargoSpace>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, cargo) in &wagons_query {
+        // Each load has its own material, so that its color can follow the cargo type
+        let load = commands.spawn(PbrBundle {
+            mesh: meshes.add(Cuboid::new(1., 1., 1.)),
+            material: materials.add(StandardMaterial {
+                base_color: cargo.cargo_type.color(),
+                perceptual_roughness: 1.,
+                ..default()
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        })
+            .insert(CargoLoad)
+            .id();
+        commands.entity(entity).add_child(load);
+    }
+}
+
+/// Scales the load meshes to the fill level of the cargo.
+pub(crate) fn update_cargo_loads(
+    mut loads_query: Query<(&Parent, &mut Transform, &mut Visibility, &Handle<StandardMaterial>), With<CargoLoad>>,
+    wagons_query: Query<(&CargoSpace, &Cargo), Changed<Cargo>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (parent, mut transform, mut visibility, material) in &mut loads_query {
+        let Ok((cargo_space, cargo)) = wagons_query.get(parent.get()) else { continue };
+
+        let height = cargo_space.depth * cargo.fill_level;
+        *visibility = if height > 0. { Visibility::Inherited } else { Visibility::Hidden };
+        // The wagons are aligned with their bogies along Z
+        transform.translation = Vec3::new(0., cargo_space.floor_height + height / 2., 0.);
+        transform.scale = Vec3::new(cargo_space.width, height.max(0.001), cargo_space.length);
+        if materials.get(material).is_some_and(|material| material.base_color != cargo.cargo_type.color()) {
+            if let Some(material) = materials.get_mut(material) {
+                material.base_color = cargo.cargo_type.color();
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
 #[derive(Component, Default)]
 pub struct Bogie {
@@ -11,9 +12,23 @@ pub struct Bogie {
     /// The integer part of the number is the index of the track segment,
     /// the decimal part of the number is the position inside the segment.
     pub position_on_track: f32,
+    /// The position on track before the last physics step, used to interpolate the rendered position between steps.
+    pub previous_position_on_track: f32,
+    /// Whether the bogie runs on the diverging rails of the crossover in its current segment.
+    /// The current track is the one it entered the crossover from, until it leaves the segment.
+    pub on_crossover: bool,
 }
 
-#[derive(Component, Default)]
+/// The wheelsets of a bogie, turned by the movement of the bogie along the track.
+#[derive(Component, Clone, Default, Serialize, Deserialize)]
+pub struct Wheels {
+    /// The rolling radius of the wheels in m.
+    pub radius: f32,
+    /// The angle the wheelsets are turned to in radians.
+    pub angle: f32,
+}
+
+#[derive(Component, Clone, Default, Serialize, Deserialize)]
 pub struct BogiePhysics {
     /// The mass of the bogie in kg.
     pub mass: f32,
@@ -30,29 +45,399 @@ pub struct BogiePhysics {
     /// The force that, if greater than horizontal_force + vertical_force, prevents the bogie from moving,
     /// i.e. Braking force, static friction.
     pub static_force: f32,
+    /// The force transmitted to this bogie by the couplers and buffers of the wagon.
+    pub coupler_force: f32,
     /// The angle of the current slope in radians.
     pub current_slope_angle: Option<f32>,
+    /// The horizontal radius of the current curve in m (infinite on straight track).
+    pub current_curve_radius: Option<f32>,
 }
 
 /// Specifies the wagon entity this part is attached to.
 #[derive(Component)]
 pub struct AttachedToWagon(pub Entity);
 
+/// Specifies the second wagon resting on an articulation bogie, which is shared by two bodies of an articulated unit.
+/// The bogie is attached to the other wagon with `AttachedToWagon`.
+#[derive(Component)]
+pub struct SharedWithWagon(pub Entity);
+
+/// A wagon body. The bogies carrying a wagon are kept apart by `BogieDistanceConstraint`s.
 #[derive(Component, Default)]
 pub struct Wagon {
-    pub distance_between_bogies: f32,
+    /// The id of the rolling stock definition the wagon was spawned from.
+    pub definition_id: String,
+    /// The mass of the empty body in kg. The mass in `WagonPhysics` also includes the cargo.
+    pub tare_mass: f32,
+    /// The height of the body above the bogies in m.
+    pub body_offset: f32,
+    /// The mass of the payload the wagon can carry in kg.
+    pub capacity: f32,
 }
 
-#[derive(Component, Default)]
+/// The number of a wagon, unique and given in the order the wagons are spawned.
+/// Identifies the wagons in saves and recordings, where the entities differ between runs.
+#[derive(Component, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct WagonNumber(pub u32);
+
+/// Keeps a bogie at a fixed chord distance behind another one along the track. Spawned as a separate entity.
+/// Wagons with more than two bogies, and articulated units sharing bogies, chain several constraints.
+#[derive(Component)]
+pub struct BogieDistanceConstraint {
+    /// The bogie ahead (towards a greater t value), which is placed first.
+    pub leading_bogie: Entity,
+    /// The bogie placed behind the leading bogie.
+    pub trailing_bogie: Entity,
+    /// The straight-line distance between the bogie pivots in m.
+    pub distance: f32,
+}
+
+#[derive(Component, Clone, Default, Serialize, Deserialize)]
 pub struct WagonPhysics {
     /// The mass of the wagon (excluding bogies) in kg.
     pub mass: f32,
-    /// The averaged velocity of the bogies attached to the wagon. Updated each frame.
+    /// The averaged velocity of the bogies attached to the wagon. Updated each physics step.
     pub velocity: f32,
     pub tractive_force: f32,
     pub braking_force: f32,
 }
 
+/// The running resistance of a vehicle on straight and level track, following the Davis equation: A + B*v + C*v^2.
+#[derive(Component, Clone, Deserialize)]
+pub struct RunningResistance {
+    /// The speed-independent resistance (bearings, rolling) in N.
+    pub a: f32,
+    /// The resistance proportional to speed (flange friction, track flexing) in N*s/m.
+    pub b: f32,
+    /// The resistance proportional to the square of speed (aerodynamic drag) in N*s^2/m^2.
+    pub c: f32,
+}
+
+impl RunningResistance {
+    /// Returns the magnitude of the running resistance at the given speed in N.
+    pub fn get_force(&self, speed: f32) -> f32 {
+        let speed = speed.abs();
+        self.a + self.b * speed + self.c * speed * speed
+    }
+}
+
+/// The properties of a vehicle that determine when it derails.
+#[derive(Component, Clone, Deserialize)]
+pub struct VehicleStability {
+    /// The height of the center of gravity above the top of the rails in m.
+    pub center_of_gravity_height: f32,
+    /// The angle of the wheel flanges from the horizontal in radians.
+    pub flange_angle: f32,
+    /// The friction coefficient between the wheel flanges and the rails.
+    pub flange_friction: f32,
+}
+
+impl VehicleStability {
+    /// Returns the ratio of the lateral and the vertical wheel force above which the flange climbs the rail (Nadal's formula).
+    pub fn get_nadal_limit(&self) -> f32 {
+        let flange_tan = self.flange_angle.tan();
+        (flange_tan - self.flange_friction) / (1. + self.flange_friction * flange_tan)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum DerailmentCause {
+    /// The unbalanced lateral acceleration in a curve tipped the vehicle over.
+    Overturning { lateral_acceleration: f32 },
+    /// The lateral wheel force made the flange climb over the rail.
+    FlangeClimb { force_ratio: f32 },
+    /// The vehicle ran past the end of the generated track.
+    EndOfTrack,
+}
+
+impl std::fmt::Display for DerailmentCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DerailmentCause::Overturning { lateral_acceleration } => {
+                write!(f, "overturned at a lateral acceleration of {:.2} m/s^2", lateral_acceleration)
+            },
+            DerailmentCause::FlangeClimb { force_ratio } => write!(f, "flange climb at L/Q = {:.2}", force_ratio),
+            DerailmentCause::EndOfTrack => write!(f, "ran off the end of the track"),
+        }
+    }
+}
+
+/// Marks a derailed wagon and its bogies. Derailed vehicles leave the track physics and move as free bodies.
+#[derive(Component)]
+pub struct Derailed {
+    pub cause: DerailmentCause,
+}
+
+/// Marks the bogies of a consist with a bogie on a track that is not sampled around it yet.
+/// The marked bogies are left out of the physics steps until the track is loaded.
+#[derive(Component)]
+pub struct TrackNotLoaded;
+
+/// Holds a bogie of a derailed vehicle at a fixed place under the vehicle body, which moves as one rigid body.
+/// The transform is relative to the body.
+#[derive(Component, Clone, Copy)]
+pub struct BodyOffset(pub Transform);
+
+impl BodyOffset {
+    /// Returns the offset of the part at `part_transform` from the body at `body_transform`.
+    pub fn between(body_transform: &Transform, part_transform: &Transform) -> Self {
+        Self(Transform::from_matrix(body_transform.compute_matrix().inverse() * part_transform.compute_matrix()))
+    }
+}
+
+/// A rigid body moving freely under gravity, used for derailed vehicles.
+#[derive(Component, Clone, Default, Serialize, Deserialize)]
+pub struct FreeBody {
+    pub linear_velocity: Vec3,
+    /// The angular velocity in world space in rad/s.
+    pub angular_velocity: Vec3,
+}
+
+/// The position of the reverser, which selects the direction the locomotive pulls in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Reverser {
+    #[default]
+    Forward,
+    Neutral,
+    Reverse,
+}
+
+impl Reverser {
+    pub fn direction(&self) -> f32 {
+        match self {
+            Reverser::Forward => 1.,
+            Reverser::Neutral => 0.,
+            Reverser::Reverse => -1.,
+        }
+    }
+}
+
+/// Makes a wagon powered. The tractive force of the wagon is set from the throttle notch and the speed,
+/// and limited by the adhesion between the wheels and the rails.
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Locomotive {
+    /// The current throttle notch, from 0 (idle) to `max_notch` (full power).
+    pub notch: u32,
+    pub max_notch: u32,
+    pub reverser: Reverser,
+    /// The tractive effort at full throttle in the constant-force region in N.
+    pub max_tractive_effort: f32,
+    /// The power at the rails at full throttle in W. Limits the tractive effort above the base speed.
+    pub max_power: f32,
+    /// The adhesion coefficient between the wheels and dry rails.
+    pub adhesion_coefficient: f32,
+    /// The tractive force demanded by the throttle at the current speed in N. Updated each frame.
+    pub demanded_force: f32,
+    /// The maximum tractive force the wheels can transmit to the rails in N. Updated each frame.
+    pub adhesion_limit: f32,
+    /// Whether the demanded force exceeds the adhesion limit, making the wheels slip.
+    pub wheel_slip: bool,
+}
+
+impl Locomotive {
+    /// Returns the tractive effort at the given speed: constant up to the base speed,
+    /// then following the constant-power hyperbola. Both scale with the throttle notch.
+    pub fn get_tractive_effort(&self, speed: f32) -> f32 {
+        if self.max_notch == 0 {
+            return 0.;
+        }
+        let throttle = self.notch.min(self.max_notch) as f32 / self.max_notch as f32;
+        let effort = self.max_tractive_effort.min(self.max_power / speed.abs().max(0.1));
+
+        throttle * effort * self.reverser.direction()
+    }
+}
+
+/// The position of the driver's brake valve.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum BrakeValvePosition {
+    /// Charges the brake pipe to the running pressure, releasing the brakes.
+    #[default]
+    Release,
+    /// Holds the current brake pipe pressure.
+    Lap,
+    /// Reduces the brake pipe pressure gradually, down to a full service application.
+    Service,
+    /// Vents the brake pipe completely.
+    Emergency,
+}
+
+/// The driver's brake valve of a locomotive, which controls the brake pipe pressure of its consist.
+#[derive(Component, Clone, Default, Serialize, Deserialize)]
+pub struct DriversBrakeValve {
+    pub position: BrakeValvePosition,
+}
+
+/// The automatic air brake of a wagon: a triple valve, an auxiliary reservoir and a brake cylinder.
+/// All the pressures are gauge pressures in bar.
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct AirBrake {
+    /// The brake pipe pressure at this wagon.
+    pub brake_pipe_pressure: f32,
+    pub auxiliary_reservoir_pressure: f32,
+    pub brake_cylinder_pressure: f32,
+    /// The brake cylinder pressure of a full application.
+    pub max_cylinder_pressure: f32,
+    /// The total force pressing the brake blocks against the wheels per bar of brake cylinder pressure in N.
+    pub block_force_per_bar: f32,
+}
+
+impl AirBrake {
+    /// Returns a released brake with charged reservoirs.
+    pub fn charged(running_pressure: f32, max_cylinder_pressure: f32, block_force_per_bar: f32) -> Self {
+        Self {
+            brake_pipe_pressure: running_pressure,
+            auxiliary_reservoir_pressure: running_pressure,
+            brake_cylinder_pressure: 0.,
+            max_cylinder_pressure,
+            block_force_per_bar,
+        }
+    }
+}
+
+/// The couplers at both ends of a wagon, together with the draft gear connecting them to the wagon frame.
+#[derive(Component, Clone, Deserialize)]
+pub struct Coupler {
+    /// The distance from the bogie pivot to the coupler face in m.
+    pub overhang: f32,
+    /// The free play of the coupler in m, in which no force is transmitted.
+    pub slack: f32,
+    /// The stiffness of the draft gear in N/m.
+    pub stiffness: f32,
+    /// The damping of the draft gear in N*s/m.
+    pub damping: f32,
+}
+
+/// Links two adjacent wagons into a consist. Spawned as a separate entity, despawned when uncoupled.
+#[derive(Component)]
+pub struct Coupling {
+    /// The wagon ahead (towards a greater t value).
+    pub front_wagon: Entity,
+    pub rear_wagon: Entity,
+    /// The force transmitted by the coupling in N.
+    /// Positive when in draft (pulling the wagons together), negative when in buff (pushing them apart).
+    pub force: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum CargoType {
+    Coal,
+    Gravel,
+    Grain,
+    IronOre,
+}
+
+impl CargoType {
+    pub const ALL: [CargoType; 4] = [CargoType::Coal, CargoType::Gravel, CargoType::Grain, CargoType::IronOre];
+
+    /// Returns the bulk density in kg/m^3.
+    pub fn density(&self) -> f32 {
+        match self {
+            CargoType::Coal => 800.,
+            CargoType::Gravel => 1600.,
+            CargoType::Grain => 750.,
+            CargoType::IronOre => 2500.,
+        }
+    }
+
+    /// Returns the sRGB color of the load.
+    pub fn color(&self) -> Color {
+        match self {
+            CargoType::Coal => Color::srgb(0.08, 0.08, 0.09),
+            CargoType::Gravel => Color::srgb(0.55, 0.53, 0.5),
+            CargoType::Grain => Color::srgb(0.85, 0.72, 0.4),
+            CargoType::IronOre => Color::srgb(0.45, 0.22, 0.15),
+        }
+    }
+}
+
+/// The box inside a wagon body that bulk cargo is loaded into, in the wagon's local space.
+#[derive(Component, Clone, Deserialize)]
+pub struct CargoSpace {
+    /// The inner length along the wagon in m.
+    pub length: f32,
+    pub width: f32,
+    pub depth: f32,
+    /// The height of the floor above the wagon origin in m.
+    pub floor_height: f32,
+}
+
+impl CargoSpace {
+    pub fn volume(&self) -> f32 {
+        self.length * self.width * self.depth
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum CargoTransfer {
+    #[default]
+    Idle,
+    Loading,
+    Unloading,
+}
+
+/// The bulk cargo carried in the `CargoSpace` of a wagon.
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Cargo {
+    pub cargo_type: CargoType,
+    /// The bulk density of the cargo in kg/m^3.
+    pub density: f32,
+    /// The filled share of the cargo space, from 0 to 1.
+    pub fill_level: f32,
+    /// Whether the cargo is being loaded or unloaded. Updated each physics step.
+    pub transfer: CargoTransfer,
+}
+
+impl Cargo {
+    pub fn empty(cargo_type: CargoType) -> Self {
+        Self {
+            cargo_type,
+            density: cargo_type.density(),
+            fill_level: 0.,
+            transfer: CargoTransfer::Idle,
+        }
+    }
+
+    /// Returns the mass of the cargo in kg.
+    pub fn get_mass(&self, space: &CargoSpace) -> f32 {
+        space.volume() * self.fill_level * self.density
+    }
+
+    /// Returns the fill level at which the cargo reaches the given payload capacity (or fills the space).
+    pub fn get_max_fill_level(&self, space: &CargoSpace, capacity: f32) -> f32 {
+        (capacity / (space.volume() * self.density)).min(1.)
+    }
+}
+
 /// Used as a marker to track a single wagon for UI.
 #[derive(Component)]
 pub struct TrackedWagon;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_resistance_follows_the_davis_equation() {
+        let resistance = RunningResistance { a: 1000., b: 20., c: 5. };
+
+        assert_eq!(resistance.get_force(0.), 1000.);
+        assert_eq!(resistance.get_force(10.), 1000. + 200. + 500.);
+        // The magnitude does not depend on the direction of travel
+        assert_eq!(resistance.get_force(-10.), resistance.get_force(10.));
+    }
+
+    #[test]
+    fn nadal_limit_matches_the_known_values() {
+        let stability = |flange_friction: f32| VehicleStability {
+            center_of_gravity_height: 2.,
+            flange_angle: 70_f32.to_radians(),
+            flange_friction,
+        };
+
+        // Without friction the limit is the tangent of the flange angle
+        assert!((stability(0.).get_nadal_limit() - 2.747).abs() < 0.001);
+        assert!((stability(0.3).get_nadal_limit() - 1.342).abs() < 0.001);
+        assert!((stability(0.5).get_nadal_limit() - 0.947).abs() < 0.001);
+    }
+}
@@ -0,0 +1,305 @@
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use crate::{noise, NoiseSettings};
+use crate::rolling_stock::bogie_systems::switch_track_at_crossovers;
+use crate::rolling_stock::components::{Bogie, BogieDistanceConstraint, TrackNotLoaded};
+use crate::world::train_tracks::{Crossover, get_bogie_line_position_at_t, Track};
+
+/// The accepted error of the distance between constrained bogies in m.
+const DISTANCE_TOLERANCE: f32 = 0.0001;
+const MAX_BISECTION_STEPS: u32 = 40;
+/// The maximum number of times the search interval is doubled while looking for a position far enough behind the leading bogie.
+const MAX_BRACKET_STEPS: u32 = 12;
+/// The initial width (in t) of the search interval behind the current position of the trailing bogie.
+const INITIAL_BRACKET_WIDTH: f32 = 0.01;
+
+#[derive(Debug)]
+pub(crate) enum ConstraintError {
+    /// A constrained bogie entity does not exist (or is not a bogie).
+    MissingBogie(Entity),
+    /// The bogie is not on any track, or the track around it is not loaded.
+    TrackNotAvailable(Entity),
+    /// No position on the track is at the required distance behind the leading bogie.
+    NoSolution { trailing_bogie: Entity, distance: f32 },
+    /// The constraints form a cycle, so no bogie can be placed first.
+    Cycle(Vec<Entity>),
+}
+
+impl std::fmt::Display for ConstraintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConstraintError::MissingBogie(entity) => write!(f, "bogie {:?} does not exist", entity),
+            ConstraintError::TrackNotAvailable(entity) => write!(f, "the track under bogie {:?} is not available", entity),
+            ConstraintError::NoSolution { trailing_bogie, distance } => {
+                write!(f, "bogie {:?} can not be placed {} m behind its leading bogie", trailing_bogie, distance)
+            },
+            ConstraintError::Cycle(bogies) => write!(f, "the constraints of bogies {:?} form a cycle", bogies),
+        }
+    }
+}
+
+impl std::error::Error for ConstraintError {}
+
+/// Places each constrained bogie exactly at the constraint distance behind its leading bogie.
+/// Constraints are solved starting from the bogies that don't trail any other bogie, so chains of any length work.
+/// Errors are logged once until they go away, and the affected bogies are left where they are. Consists waiting for their track are skipped.
+pub(crate) fn solve_bogie_constraints(
+    mut bogies_query: Query<&mut Bogie>,
+    constraints_query: Query<&BogieDistanceConstraint>,
+    waiting_query: Query<(), With<TrackNotLoaded>>,
+    track_query: Query<&Track>,
+    crossover_query: Query<&Crossover>,
+    noise_settings: Res<NoiseSettings>,
+    mut reported_errors: Local<HashSet<String>>,
+) {
+    let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+    let line_position = |bogie: &Bogie, t: f32| -> Option<Vec3> {
+        if t < 0. {
+            return None;
+        }
+        get_bogie_line_position_at_t(&track_query, &crossover_query, bogie, t, &height_fn).map(|(position, _)| position)
+    };
+
+    let constraints = constraints_query.iter()
+        .filter(|constraint| !waiting_query.contains(constraint.leading_bogie) && !waiting_query.contains(constraint.trailing_bogie))
+        .collect();
+    let errors: HashSet<String> = solve_constraints(constraints, &mut bogies_query, &line_position, &crossover_query).iter()
+        .map(ToString::to_string)
+        .collect();
+    for error in errors.difference(&reported_errors) {
+        warn!("Unable to solve a bogie constraint: {}", error);
+    }
+    *reported_errors = errors;
+}
+
+/// Solves the constraints in order, and returns the errors of the ones that could not be solved.
+/// A constraint whose leading bogie could not be placed is solved from the current position of that bogie.
+/// If the constraints form a cycle, the one closing it is skipped.
+fn solve_constraints<F: Fn(&Bogie, f32) -> Option<Vec3>>(
+    mut pending: Vec<&BogieDistanceConstraint>,
+    bogies_query: &mut Query<&mut Bogie>,
+    line_position: &F,
+    crossover_query: &Query<&Crossover>,
+) -> Vec<ConstraintError> {
+    let mut errors = Vec::new();
+    // The positions of the solved bogies
+    let mut placed = HashMap::<Entity, Vec3>::new();
+
+    while !pending.is_empty() {
+        let is_waiting = |bogie: Entity| pending.iter().any(|constraint| constraint.trailing_bogie == bogie);
+        let ready = pending.iter().position(|constraint| !is_waiting(constraint.leading_bogie));
+        let Some(ready) = ready else {
+            // Every pending constraint waits for another one, so following the leading bogies leads into a cycle
+            let mut chain = vec![0];
+            let cycle_start = loop {
+                let leading_bogie = pending[*chain.last().unwrap()].leading_bogie;
+                let next = pending.iter().position(|constraint| constraint.trailing_bogie == leading_bogie).unwrap();
+                if let Some(start) = chain.iter().position(|index| *index == next) {
+                    break start;
+                }
+                chain.push(next);
+            };
+            let mut bogies: Vec<Entity> = chain[cycle_start..].iter().map(|index| pending[*index].trailing_bogie).collect();
+            bogies.sort();
+            errors.push(ConstraintError::Cycle(bogies));
+            pending.swap_remove(*chain.last().unwrap());
+            continue;
+        };
+        let constraint = pending.swap_remove(ready);
+
+        match solve_constraint(constraint, bogies_query, &placed, line_position, crossover_query) {
+            Ok(trailing_position) => {
+                placed.insert(constraint.trailing_bogie, trailing_position);
+            },
+            Err(error) => errors.push(error),
+        }
+    }
+
+    errors
+}
+
+/// Moves the trailing bogie of the constraint into place and returns its position.
+fn solve_constraint<F: Fn(&Bogie, f32) -> Option<Vec3>>(
+    constraint: &BogieDistanceConstraint,
+    bogies_query: &mut Query<&mut Bogie>,
+    placed: &HashMap<Entity, Vec3>,
+    line_position: &F,
+    crossover_query: &Query<&Crossover>,
+) -> Result<Vec3, ConstraintError> {
+    let leading = bogies_query.get(constraint.leading_bogie).map_err(|_| ConstraintError::MissingBogie(constraint.leading_bogie))?;
+    let leading_t = leading.position_on_track;
+    let leading_position = match placed.get(&constraint.leading_bogie) {
+        Some(position) => *position,
+        None => line_position(leading, leading_t).ok_or(ConstraintError::TrackNotAvailable(constraint.leading_bogie))?,
+    };
+
+    let mut trailing = bogies_query.get_mut(constraint.trailing_bogie).map_err(|_| ConstraintError::MissingBogie(constraint.trailing_bogie))?;
+    let no_solution = ConstraintError::NoSolution { trailing_bogie: constraint.trailing_bogie, distance: constraint.distance };
+    let distance_at = |t: f32| -> Result<f32, ConstraintError> {
+        line_position(&trailing, t)
+            .map(|position| position.distance(leading_position))
+            .ok_or(ConstraintError::TrackNotAvailable(constraint.trailing_bogie))
+    };
+
+    // Find an interval [low, high] behind the leading bogie that contains the solution.
+    // The distance grows monotonically moving backwards from the leading bogie.
+    let mut high = leading_t;
+    let mut low = trailing.position_on_track.min(leading_t) - INITIAL_BRACKET_WIDTH;
+    let mut bracket_steps = 0;
+    while distance_at(low)? < constraint.distance {
+        high = low;
+        low -= INITIAL_BRACKET_WIDTH * 2_f32.powi(bracket_steps as i32 + 1);
+        bracket_steps += 1;
+        if bracket_steps > MAX_BRACKET_STEPS {
+            return Err(no_solution);
+        }
+    }
+
+    // The bisection only converges if the distance crosses the constraint distance inside the interval,
+    // e.g. not if the trailing bogie is on another track further away from the leading bogie than that.
+    if distance_at(low)? < constraint.distance || distance_at(high)? > constraint.distance {
+        return Err(no_solution);
+    }
+
+    let mut solution = None;
+    for _ in 0..MAX_BISECTION_STEPS {
+        let t = (low + high) / 2.;
+        let error = distance_at(t)? - constraint.distance;
+        if error.abs() <= DISTANCE_TOLERANCE {
+            solution = Some(t);
+            break;
+        }
+        if error > 0. { low = t; } else { high = t; }
+    }
+
+    let Some(t) = solution else {
+        return Err(no_solution);
+    };
+    let position = line_position(&trailing, t).ok_or(no_solution)?;
+    let old_t = trailing.position_on_track;
+    trailing.position_on_track = t;
+    switch_track_at_crossovers(&mut trailing, old_t, crossover_query);
+    Ok(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use crate::world::track_profiles::TrackProfile;
+    use crate::world::train_tracks::spawn_level_tracks;
+    use super::*;
+
+    const BOGIE_DISTANCE: f32 = 12.;
+
+    /// Spawns a level track along a curve with a radius of 400 m.
+    fn spawn_curved_track(world: &mut World) -> Entity {
+        let points: Vec<Vec3> = (0..8)
+            .map(|i| Vec3::new((i as f32 * 0.15).sin(), 0., 1. - (i as f32 * 0.15).cos()) * 400.)
+            .collect();
+        spawn_level_tracks(world, &TrackProfile::default(), &points)[0]
+    }
+
+    fn spawn_bogie(world: &mut World, track: Option<Entity>, t: f32) -> Entity {
+        world.spawn(Bogie { current_track: track, position_on_track: t, ..default() }).id()
+    }
+
+    fn constraint(leading_bogie: Entity, trailing_bogie: Entity) -> BogieDistanceConstraint {
+        BogieDistanceConstraint { leading_bogie, trailing_bogie, distance: BOGIE_DISTANCE }
+    }
+
+    fn solve(world: &mut World, constraints: Vec<BogieDistanceConstraint>) -> Vec<ConstraintError> {
+        world.run_system_once(move |mut bogies_query: Query<&mut Bogie>, track_query: Query<&Track>, crossover_query: Query<&Crossover>| {
+            let line_position = |bogie: &Bogie, t: f32| -> Option<Vec3> {
+                if t < 0. {
+                    return None;
+                }
+                get_bogie_line_position_at_t(&track_query, &crossover_query, bogie, t, &|_, _| 0.).map(|(position, _)| position)
+            };
+            solve_constraints(constraints.iter().collect(), &mut bogies_query, &line_position, &crossover_query)
+        })
+    }
+
+    fn distance_between(world: &mut World, first: Entity, second: Entity) -> f32 {
+        world.run_system_once(move |bogies_query: Query<&Bogie>, track_query: Query<&Track>, crossover_query: Query<&Crossover>| {
+            let position = |entity: Entity| {
+                let bogie = bogies_query.get(entity).unwrap();
+                get_bogie_line_position_at_t(&track_query, &crossover_query, bogie, bogie.position_on_track, &|_, _| 0.).unwrap().0
+            };
+            position(first).distance(position(second))
+        })
+    }
+
+    fn position_on_track(world: &World, bogie: Entity) -> f32 {
+        world.get::<Bogie>(bogie).unwrap().position_on_track
+    }
+
+    #[test]
+    fn trailing_bogie_is_placed_at_the_distance_behind_the_leading_one_on_a_curve() {
+        let mut world = World::new();
+        world.insert_resource(NoiseSettings::default());
+        let track = spawn_curved_track(&mut world);
+        let leading = spawn_bogie(&mut world, Some(track), 3.5);
+        let trailing = spawn_bogie(&mut world, Some(track), 3.45);
+        world.spawn(constraint(leading, trailing));
+
+        world.run_system_once(solve_bogie_constraints);
+
+        assert_eq!(position_on_track(&world, leading), 3.5);
+        assert!(position_on_track(&world, trailing) < 3.5);
+        let distance = distance_between(&mut world, leading, trailing);
+        assert!((distance - BOGIE_DISTANCE).abs() <= DISTANCE_TOLERANCE * 10., "the bogies are {} m apart", distance);
+    }
+
+    #[test]
+    fn constraints_with_missing_bogies_or_track_are_reported() {
+        let mut world = World::new();
+        let track = spawn_curved_track(&mut world);
+        let leading = spawn_bogie(&mut world, Some(track), 3.5);
+        let missing = world.spawn_empty().id();
+        let derailed = spawn_bogie(&mut world, None, 3.3);
+
+        let errors = solve(&mut world, vec![constraint(missing, leading), constraint(leading, derailed)]);
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|error| matches!(error, ConstraintError::MissingBogie(entity) if *entity == missing)));
+        assert!(errors.iter().any(|error| matches!(error, ConstraintError::TrackNotAvailable(entity) if *entity == derailed)));
+        assert_eq!(position_on_track(&world, derailed), 3.3);
+    }
+
+    #[test]
+    fn only_the_constraint_closing_a_cycle_is_skipped() {
+        let mut world = World::new();
+        let track = spawn_curved_track(&mut world);
+        let first = spawn_bogie(&mut world, Some(track), 3.5);
+        let second = spawn_bogie(&mut world, Some(track), 3.4);
+        let leading = spawn_bogie(&mut world, Some(track), 2.5);
+        let trailing = spawn_bogie(&mut world, Some(track), 2.45);
+
+        let errors = solve(&mut world, vec![constraint(first, second), constraint(second, first), constraint(leading, trailing)]);
+
+        let mut cycle = vec![first, second];
+        cycle.sort();
+        assert!(matches!(errors.as_slice(), [ConstraintError::Cycle(bogies)] if *bogies == cycle));
+        // The rest of the cycle and the other constraints are still solved
+        let distance = distance_between(&mut world, first, second);
+        assert!((distance - BOGIE_DISTANCE).abs() <= DISTANCE_TOLERANCE * 10., "the cycle bogies are {} m apart", distance);
+        let distance = distance_between(&mut world, leading, trailing);
+        assert!((distance - BOGIE_DISTANCE).abs() <= DISTANCE_TOLERANCE * 10., "the bogies are {} m apart", distance);
+    }
+
+    #[test]
+    fn constraints_without_a_position_at_the_distance_are_reported() {
+        let mut world = World::new();
+        let points: Vec<Vec3> = (0..8).map(|i| Vec3::new(i as f32 * 40., 0., 0.)).collect();
+        let tracks = spawn_level_tracks(&mut world, &TrackProfile { tracks: 2, ..default() }, &points);
+        let leading = spawn_bogie(&mut world, Some(tracks[0]), 3.5);
+        // The other track is further away from the leading bogie than the constraint distance everywhere
+        let trailing = spawn_bogie(&mut world, Some(tracks[1]), 3.4);
+        let too_close = BogieDistanceConstraint { leading_bogie: leading, trailing_bogie: trailing, distance: 2. };
+
+        let errors = solve(&mut world, vec![too_close]);
+
+        assert!(matches!(errors.as_slice(), [ConstraintError::NoSolution { trailing_bogie, .. }] if *trailing_bogie == trailing));
+        assert_eq!(position_on_track(&world, trailing), 3.4);
+    }
+}
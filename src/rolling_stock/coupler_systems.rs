@@ -0,0 +1,268 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use crate::{noise, NoiseSettings};
+use crate::rolling_stock::components::{AttachedToWagon, Bogie, BogiePhysics, Coupler, Coupling};
+use crate::world::train_tracks::{Crossover, get_bogie_line_position_at_t, Track};
+
+/// The maximum closing speed in m/s at which wagons running into each other couple automatically.
+/// Faster wagons bounce off each other's buffers.
+const MAX_COUPLING_SPEED: f32 = 1.5;
+
+/// Sent to uncouple the wagons joined by the given `Coupling` entity.
+#[derive(Event)]
+pub(crate) struct Uncouple(pub Entity);
+
+/// The state of the bogie at one end of a wagon.
+struct WagonEnd {
+    bogie: Entity,
+    track: Entity,
+    t: f32,
+    position: Vec3,
+    velocity: f32,
+}
+
+struct WagonEnds {
+    leading: WagonEnd,
+    trailing: WagonEnd,
+}
+
+/// Finds the ends of all the wagons whose bogies are both on a track.
+fn get_wagon_ends<'a>(
+    bogies: impl Iterator<Item = (Entity, &'a Bogie, &'a BogiePhysics, &'a AttachedToWagon)>,
+    track_query: &Query<&Track>,
+    crossover_query: &Query<&Crossover>,
+    noise_settings: &NoiseSettings,
+) -> HashMap<Entity, WagonEnds> {
+    let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+
+    let mut bogie_pairs = HashMap::<Entity, (Option<WagonEnd>, Option<WagonEnd>)>::new();
+    for (entity, bogie, bogie_physics, attached_to) in bogies {
+        let Some(track) = bogie.current_track else { continue };
+        let Some((position, _)) = get_bogie_line_position_at_t(track_query, crossover_query, bogie, bogie.position_on_track, &height_fn) else {
+            continue;
+        };
+
+        let end = WagonEnd { bogie: entity, track, t: bogie.position_on_track, position, velocity: bogie_physics.velocity };
+        let pair = bogie_pairs.entry(attached_to.0).or_insert((None, None));
+        if bogie.is_leading == Some(true) {
+            pair.0 = Some(end);
+        } else {
+            pair.1 = Some(end);
+        }
+    }
+
+    bogie_pairs.into_iter()
+        .filter_map(|(wagon, pair)| match pair {
+            (Some(leading), Some(trailing)) => Some((wagon, WagonEnds { leading, trailing })),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the (front, rear) pairs of neighbouring wagons whose facing ends are on the same track.
+fn get_facing_wagon_pairs(wagon_ends: &HashMap<Entity, WagonEnds>) -> Vec<(Entity, Entity)> {
+    // The wagons with an end on each track, by the t of their middle
+    let mut wagons_by_track = HashMap::<Entity, Vec<(f32, Entity)>>::new();
+    for (wagon, ends) in wagon_ends {
+        let middle_t = (ends.leading.t + ends.trailing.t) / 2.;
+        wagons_by_track.entry(ends.leading.track).or_default().push((middle_t, *wagon));
+        if ends.trailing.track != ends.leading.track {
+            wagons_by_track.entry(ends.trailing.track).or_default().push((middle_t, *wagon));
+        }
+    }
+
+    let mut result = Vec::new();
+    for (track, mut wagons) in wagons_by_track {
+        wagons.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        for pair in wagons.windows(2) {
+            let ((rear_t, rear_wagon), (front_t, front_wagon)) = (pair[0], pair[1]);
+            let (front, rear) = (&wagon_ends[&front_wagon], &wagon_ends[&rear_wagon]);
+            if front_t > rear_t && front.trailing.track == track && rear.leading.track == track {
+                result.push((front_wagon, rear_wagon));
+            }
+        }
+    }
+
+    result
+}
+
+/// Returns the distance between the coupler faces of two facing wagons.
+/// Zero when the faces just touch, negative when the rear wagon's coupler is pushed into the front wagon's.
+fn get_coupler_gap(front: &WagonEnds, front_coupler: &Coupler, rear: &WagonEnds, rear_coupler: &Coupler) -> f32 {
+    let distance = front.trailing.position.distance(rear.leading.position);
+    let signed_distance = if rear.leading.t > front.trailing.t { -distance } else { distance };
+
+    signed_distance - front_coupler.overhang - rear_coupler.overhang
+}
+
+/// Returns the force transmitted between the facing ends of two wagons (positive in draft, negative in buff).
+/// The slack has to be taken up before any force is transmitted. Uncoupled wagons can only push each other apart.
+fn get_draft_gear_force(front_coupler: &Coupler, rear_coupler: &Coupler, gap: f32, gap_rate: f32, coupled: bool) -> f32 {
+    // The draft gears of both wagons act as springs (and dampers) in series
+    let stiffness = front_coupler.stiffness * rear_coupler.stiffness / (front_coupler.stiffness + rear_coupler.stiffness);
+    let damping = front_coupler.damping * rear_coupler.damping / (front_coupler.damping + rear_coupler.damping);
+    let half_slack = (front_coupler.slack + rear_coupler.slack) / 2.;
+
+    if coupled && gap > half_slack {
+        (stiffness * (gap - half_slack) + damping * gap_rate).max(0.)
+    } else if gap < -half_slack {
+        (stiffness * (gap + half_slack) + damping * gap_rate).min(0.)
+    } else {
+        0.
+    }
+}
+
+/// Sets the forces transmitted through the couplings, and between the buffers of uncoupled wagons that touch.
+/// The force is applied to the bogies at the facing ends of the wagons.
+pub(crate) fn set_coupler_forces(
+    mut bogies_query: Query<(Entity, &Bogie, &mut BogiePhysics, &AttachedToWagon)>,
+    wagons_query: Query<&Coupler>,
+    mut couplings_query: Query<&mut Coupling>,
+    track_query: Query<&Track>,
+    crossover_query: Query<&Crossover>,
+    noise_settings: Res<NoiseSettings>,
+) {
+    let wagon_ends = get_wagon_ends(bogies_query.iter(), &track_query, &crossover_query, &noise_settings);
+    let mut bogie_forces = HashMap::<Entity, f32>::new();
+    let mut apply_force = |front: &WagonEnds, rear: &WagonEnds, force: f32| {
+        *bogie_forces.entry(front.trailing.bogie).or_insert(0.) -= force;
+        *bogie_forces.entry(rear.leading.bogie).or_insert(0.) += force;
+    };
+
+    for mut coupling in &mut couplings_query {
+        let ends = (wagon_ends.get(&coupling.front_wagon), wagon_ends.get(&coupling.rear_wagon));
+        let couplers = (wagons_query.get(coupling.front_wagon), wagons_query.get(coupling.rear_wagon));
+        let ((Some(front), Some(rear)), (Ok(front_coupler), Ok(rear_coupler))) = (ends, couplers) else {
+            coupling.force = 0.;
+            continue;
+        };
+
+        let gap = get_coupler_gap(front, front_coupler, rear, rear_coupler);
+        let gap_rate = front.trailing.velocity - rear.leading.velocity;
+        coupling.force = get_draft_gear_force(front_coupler, rear_coupler, gap, gap_rate, true);
+        apply_force(front, rear, coupling.force);
+    }
+
+    for (front_wagon, rear_wagon) in get_facing_wagon_pairs(&wagon_ends) {
+        if couplings_query.iter().any(|coupling| coupling.front_wagon == front_wagon && coupling.rear_wagon == rear_wagon) {
+            continue;
+        }
+        let (Ok(front_coupler), Ok(rear_coupler)) = (wagons_query.get(front_wagon), wagons_query.get(rear_wagon)) else {
+            continue;
+        };
+
+        let (front, rear) = (&wagon_ends[&front_wagon], &wagon_ends[&rear_wagon]);
+        let gap = get_coupler_gap(front, front_coupler, rear, rear_coupler);
+        let gap_rate = front.trailing.velocity - rear.leading.velocity;
+        apply_force(front, rear, get_draft_gear_force(front_coupler, rear_coupler, gap, gap_rate, false));
+    }
+
+    for (entity, _, mut bogie_physics, _) in &mut bogies_query {
+        bogie_physics.coupler_force = bogie_forces.get(&entity).copied().unwrap_or(0.);
+    }
+}
+
+/// Couples wagons that run into each other at low speed, if the facing couplers are free.
+pub(crate) fn couple_touching_wagons(
+    mut commands: Commands,
+    bogies_query: Query<(Entity, &Bogie, &BogiePhysics, &AttachedToWagon)>,
+    wagons_query: Query<&Coupler>,
+    couplings_query: Query<&Coupling>,
+    track_query: Query<&Track>,
+    crossover_query: Query<&Crossover>,
+    noise_settings: Res<NoiseSettings>,
+) {
+    let wagon_ends = get_wagon_ends(bogies_query.iter(), &track_query, &crossover_query, &noise_settings);
+
+    let mut coupled_wagons: Vec<(Entity, Entity)> = couplings_query.iter()
+        .map(|coupling| (coupling.front_wagon, coupling.rear_wagon))
+        .collect();
+    for (front_wagon, rear_wagon) in get_facing_wagon_pairs(&wagon_ends) {
+        if coupled_wagons.iter().any(|(front, rear)| *front == front_wagon || *rear == rear_wagon) {
+            continue;
+        }
+        let (Ok(front_coupler), Ok(rear_coupler)) = (wagons_query.get(front_wagon), wagons_query.get(rear_wagon)) else {
+            continue;
+        };
+
+        let (front, rear) = (&wagon_ends[&front_wagon], &wagon_ends[&rear_wagon]);
+        let gap = get_coupler_gap(front, front_coupler, rear, rear_coupler);
+        let closing_speed = rear.leading.velocity - front.trailing.velocity;
+        let half_slack = (front_coupler.slack + rear_coupler.slack) / 2.;
+        if gap <= -half_slack && closing_speed > 0. && closing_speed <= MAX_COUPLING_SPEED {
+            commands.spawn(Coupling { front_wagon, rear_wagon, force: 0. });
+            coupled_wagons.push((front_wagon, rear_wagon));
+            info!("Coupled wagon {:?} to wagon {:?}", rear_wagon, front_wagon);
+        }
+    }
+}
+
+/// Removes the couplings of the `Uncouple` events before the physics step, so that the recorded controls include the change.
+pub(crate) fn uncouple_wagons(
+    mut commands: Commands,
+    mut uncouple_events: EventReader<Uncouple>,
+    couplings_query: Query<&Coupling>,
+) {
+    for Uncouple(coupling_entity) in uncouple_events.read() {
+        let Ok(coupling) = couplings_query.get(*coupling_entity) else {
+            continue;
+        };
+        commands.entity(*coupling_entity).despawn();
+        info!("Uncoupled wagon {:?} from wagon {:?}", coupling.rear_wagon, coupling.front_wagon);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use super::*;
+
+    fn wagon_end(track: Entity, t: f32) -> WagonEnd {
+        WagonEnd { bogie: Entity::PLACEHOLDER, track, t, position: Vec3::ZERO, velocity: 0. }
+    }
+
+    #[test]
+    fn only_neighbouring_wagons_on_the_same_track_face_each_other() {
+        let (track, other_track) = (Entity::from_raw(0), Entity::from_raw(1));
+        let [first, second, third, beside] = [10, 11, 12, 13].map(Entity::from_raw);
+        let wagon_ends = HashMap::from([
+            (first, WagonEnds { leading: wagon_end(track, 3.5), trailing: wagon_end(track, 3.4) }),
+            (second, WagonEnds { leading: wagon_end(track, 3.3), trailing: wagon_end(track, 3.2) }),
+            (third, WagonEnds { leading: wagon_end(track, 3.1), trailing: wagon_end(track, 3.) }),
+            (beside, WagonEnds { leading: wagon_end(other_track, 3.25), trailing: wagon_end(other_track, 3.15) }),
+        ]);
+
+        let mut pairs = get_facing_wagon_pairs(&wagon_ends);
+        pairs.sort();
+        assert_eq!(pairs, vec![(first, second), (second, third)]);
+    }
+
+    #[test]
+    fn uncoupled_wagons_only_push_each_other_apart() {
+        let coupler = Coupler { overhang: 2., slack: 0.1, stiffness: 2e6, damping: 5e4 };
+        // Half of the slack of both couplers
+        let half_slack = coupler.slack;
+
+        // Coupled wagons pull each other once the slack is taken up
+        assert!(get_draft_gear_force(&coupler, &coupler, half_slack + 0.05, 0., true) > 0.);
+        // Uncoupled wagons part freely, even when they move apart quickly
+        assert_eq!(get_draft_gear_force(&coupler, &coupler, half_slack + 0.05, 0., false), 0.);
+        assert_eq!(get_draft_gear_force(&coupler, &coupler, -half_slack + 0.01, 1., false), 0.);
+        // Touching buffers push the wagons apart, but never pull them together while they part
+        assert!(get_draft_gear_force(&coupler, &coupler, -half_slack - 0.05, 0., false) < 0.);
+        assert_eq!(get_draft_gear_force(&coupler, &coupler, -half_slack - 0.01, 100., false), 0.);
+    }
+
+    #[test]
+    fn uncouple_events_remove_the_coupling() {
+        let mut world = World::new();
+        world.init_resource::<Events<Uncouple>>();
+        let [front_wagon, rear_wagon] = [world.spawn_empty().id(), world.spawn_empty().id()];
+        let coupling = world.spawn(Coupling { front_wagon, rear_wagon, force: 0. }).id();
+
+        world.send_event(Uncouple(coupling));
+        world.run_system_once(uncouple_wagons);
+
+        assert!(world.get_entity(coupling).is_none());
+    }
+}
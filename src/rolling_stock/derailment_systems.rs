@@ -0,0 +1,224 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use crate::{noise, NoiseSettings, PHYSICS_TIMESTEP};
+use crate::rolling_stock::bogie_systems::GRAV_ACCELERATION;
+use crate::rolling_stock::components::{AttachedToWagon, Bogie, BodyOffset, BogieDistanceConstraint, BogiePhysics, Coupling, Derailed, DerailmentCause, FreeBody, TrackNotLoaded, VehicleStability, Wagon};
+use crate::world::route_gen::Route;
+use crate::world::train_tracks::{PlacementData, Track};
+use crate::world::tunnels::get_tunnel_floor_height;
+
+/// The curve radius in m below which the wheels run against the flange with full creep force (a rough fit of the Nadal L/Q in curves).
+const FLANGE_CONTACT_RADIUS: f32 = 150.;
+/// The t distance used to sample the track around a bogie, to find the direction of travel and the outside of the curve.
+const DIRECTION_SAMPLE_T: f32 = 0.02;
+/// The rate in rad/s an overturning vehicle starts to roll over at.
+const OVERTURNING_ROLL_RATE: f32 = 1.2;
+/// The lateral speed in m/s a derailed vehicle leaves the track with towards the outside of the curve.
+const DERAILMENT_LATERAL_SPEED: f32 = 1.;
+/// The weight per unit of mass resting on each of the two wheels of a wheelset.
+const GRAVITY_PER_WHEEL: f32 = GRAV_ACCELERATION / 2.;
+/// The friction coefficient between a derailed vehicle and the ground.
+const GROUND_FRICTION_COEFFICIENT: f32 = 0.5;
+/// The share of the angular velocity kept per second while a derailed vehicle touches the ground.
+const GROUND_ANGULAR_DAMPING: f32 = 0.05;
+
+/// Fired when a bogie leaves the track.
+#[derive(Event)]
+pub(crate) struct Derailment {
+    pub wagon: Entity,
+    pub bogie: Entity,
+    pub cause: DerailmentCause,
+}
+
+/// Returns the cause of a derailment of a vehicle running through a curve, if it derails.
+/// Checks the overturning against the centre of gravity first, and then the flange climb with Nadal's limit.
+fn get_curve_derailment_cause(stability: &VehicleStability, track: &Track, speed: f32, curve_radius: f32) -> Option<DerailmentCause> {
+    if curve_radius.is_infinite() {
+        return None;
+    }
+
+    // The lateral acceleration the cant does not compensate for, in the plane of the track
+    let cant_angle = track.cant_angle();
+    let lateral_acceleration = speed * speed / curve_radius;
+    let unbalanced_acceleration = (lateral_acceleration * cant_angle.cos() - GRAV_ACCELERATION * cant_angle.sin()).abs();
+
+    // The vehicle tips over once the moment of the lateral force around the outer rail exceeds the moment of its weight
+    let half_width = track.contact_width() / 2.;
+    if unbalanced_acceleration * stability.center_of_gravity_height >= GRAV_ACCELERATION * half_width {
+        return Some(DerailmentCause::Overturning { lateral_acceleration: unbalanced_acceleration });
+    }
+
+    // The lateral (L) and vertical (Q) forces on the outer wheel, per unit of mass
+    let creep_share = (FLANGE_CONTACT_RADIUS / curve_radius).min(1.);
+    let lateral_force = unbalanced_acceleration + stability.flange_friction * GRAVITY_PER_WHEEL * creep_share;
+    let vertical_force = GRAVITY_PER_WHEEL + unbalanced_acceleration * stability.center_of_gravity_height / track.contact_width();
+    let force_ratio = lateral_force / vertical_force;
+    if force_ratio >= stability.get_nadal_limit() {
+        return Some(DerailmentCause::FlangeClimb { force_ratio });
+    }
+
+    None
+}
+
+/// Checks every bogie on the track for overturning, flange climb and running past either end of the track.
+pub(crate) fn detect_derailments(
+    bogies_query: Query<(Entity, &Bogie, &BogiePhysics, &AttachedToWagon), (Without<Derailed>, Without<TrackNotLoaded>)>,
+    stability_query: Query<&VehicleStability>,
+    track_query: Query<&Track>,
+    placement_data: Res<PlacementData>,
+    route: Res<Route>,
+    mut derailment_events: EventWriter<Derailment>,
+) {
+    let segment_ids = placement_data.segment_ids(&route);
+    for (entity, bogie, bogie_physics, attached_to) in &bogies_query {
+        let Some(track) = bogie.current_track.and_then(|entity| track_query.get(entity).ok()) else {
+            continue;
+        };
+
+        // The segments before the evicted route nodes are gone, and the segments past the last one do not exist yet
+        let t = bogie.position_on_track;
+        let cause = if t < *segment_ids.start() as f32 || t.floor() as usize > *segment_ids.end() {
+            Some(DerailmentCause::EndOfTrack)
+        } else if let Ok(stability) = stability_query.get(attached_to.0) {
+            let curve_radius = bogie_physics.current_curve_radius.unwrap_or(f32::INFINITY);
+            get_curve_derailment_cause(stability, track, bogie_physics.velocity, curve_radius)
+        } else {
+            None
+        };
+
+        if let Some(cause) = cause {
+            derailment_events.send(Derailment { wagon: attached_to.0, bogie: entity, cause });
+        }
+    }
+}
+
+/// Takes the derailed vehicles off the track: the wagon becomes a free body with its bogies fixed under it, and its couplings break.
+pub(crate) fn derail_vehicles(
+    mut commands: Commands,
+    mut derailment_events: EventReader<Derailment>,
+    mut bogies_query: Query<(Entity, &mut Bogie, &BogiePhysics, &AttachedToWagon, &Transform), Without<Derailed>>,
+    wagons_query: Query<&Transform, (With<Wagon>, Without<Derailed>)>,
+    couplings_query: Query<(Entity, &Coupling)>,
+    constraints_query: Query<(Entity, &BogieDistanceConstraint)>,
+    track_query: Query<&Track>,
+    placement_data: Res<PlacementData>,
+    noise_settings: Res<NoiseSettings>,
+) {
+    let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+
+    let mut derailed_wagons = Vec::new();
+    for event in derailment_events.read() {
+        // Both bogies of a wagon can derail in the same step
+        let Ok(wagon_transform) = wagons_query.get(event.wagon) else { continue };
+        if derailed_wagons.contains(&event.wagon) {
+            continue;
+        }
+        derailed_wagons.push(event.wagon);
+        info!("Wagon {:?} derailed: {}", event.wagon, event.cause);
+
+        // Leave the track in the direction of travel, towards the outside of the curve
+        let (mut linear_velocity, mut angular_velocity) = (Vec3::ZERO, Vec3::ZERO);
+        if let Ok((_, bogie, bogie_physics, ..)) = bogies_query.get(event.bogie) {
+            // Past the end of the track, take the direction at the end
+            let last_t = placement_data.current_segment_id() as f32 + 1. - DIRECTION_SAMPLE_T;
+            let t = bogie.position_on_track.clamp(1. + DIRECTION_SAMPLE_T, last_t.max(1. + DIRECTION_SAMPLE_T));
+            let position_at = |t: f32| bogie.current_track
+                .and_then(|entity| track_query.get(entity).ok())
+                .and_then(|track| track.get_interpolated_position_at_t(t, &height_fn))
+                .map(|(position, _)| position);
+
+            if let (Some(before), Some(at), Some(after)) = (position_at(t - DIRECTION_SAMPLE_T), position_at(t), position_at(t + DIRECTION_SAMPLE_T)) {
+                let forward = (after - before).normalize_or_zero();
+                let outward = (at - (before + after) / 2.).with_y(0.).normalize_or_zero();
+                linear_velocity = forward * bogie_physics.velocity;
+                match event.cause {
+                    DerailmentCause::Overturning { .. } => {
+                        linear_velocity += outward * DERAILMENT_LATERAL_SPEED;
+                        angular_velocity = Vec3::Y.cross(outward) * OVERTURNING_ROLL_RATE;
+                    },
+                    DerailmentCause::FlangeClimb { .. } => linear_velocity += outward * DERAILMENT_LATERAL_SPEED,
+                    DerailmentCause::EndOfTrack => {},
+                }
+            }
+        }
+
+        commands.entity(event.wagon).insert((Derailed { cause: event.cause }, FreeBody { linear_velocity, angular_velocity }));
+        for (entity, mut bogie, _, attached_to, bogie_transform) in &mut bogies_query {
+            if attached_to.0 == event.wagon {
+                bogie.current_track = None;
+                commands.entity(entity).insert((Derailed { cause: event.cause }, BodyOffset::between(wagon_transform, bogie_transform)));
+            }
+        }
+
+        for (entity, coupling) in &couplings_query {
+            if coupling.front_wagon == event.wagon || coupling.rear_wagon == event.wagon {
+                commands.entity(entity).despawn();
+            }
+        }
+        for (entity, constraint) in &constraints_query {
+            let bogies = [constraint.leading_bogie, constraint.trailing_bogie];
+            if bogies.iter().any(|bogie| bogies_query.get(*bogie).is_ok_and(|(_, _, _, attached_to, _)| attached_to.0 == event.wagon)) {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
+/// Moves the derailed vehicles as rigid bodies falling onto the ground and sliding to a halt, and carries their bogies along.
+/// The body touches the ground with its bogies. Inside a tunnel the ground is the tunnel floor.
+pub(crate) fn update_free_bodies(
+    mut bodies_query: Query<(Entity, &mut FreeBody, &mut Transform), Without<BodyOffset>>,
+    mut parts_query: Query<(&AttachedToWagon, &BodyOffset, &mut Transform), Without<FreeBody>>,
+    placement_data: Res<PlacementData>,
+    noise_settings: Res<NoiseSettings>,
+) {
+    let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+    let ground_height = |position: Vec3| {
+        get_tunnel_floor_height(&placement_data, &noise_settings, position)
+            .unwrap_or_else(|| height_fn(position.x as f64, position.z as f64) as f32)
+    };
+
+    let mut part_offsets = HashMap::<Entity, Vec<Transform>>::new();
+    for (attached_to, offset, _) in &parts_query {
+        part_offsets.entry(attached_to.0).or_default().push(offset.0);
+    }
+
+    for (entity, mut body, mut transform) in &mut bodies_query {
+        body.linear_velocity.y -= GRAV_ACCELERATION * PHYSICS_TIMESTEP;
+        transform.translation += body.linear_velocity * PHYSICS_TIMESTEP;
+        transform.rotation = (Quat::from_scaled_axis(body.angular_velocity * PHYSICS_TIMESTEP) * transform.rotation).normalize();
+
+        // The lowest point of the body below the ground, if any
+        let contact_points: Vec<Vec3> = match part_offsets.get(&entity) {
+            Some(offsets) => offsets.iter().map(|offset| transform.transform_point(offset.translation)).collect(),
+            None => vec![transform.translation],
+        };
+        let penetration = contact_points.iter()
+            .map(|point| ground_height(*point) - point.y)
+            .fold(f32::NEG_INFINITY, f32::max);
+        if penetration < 0. {
+            continue;
+        }
+
+        // Rest on the ground, and let the friction slow the vehicle down
+        transform.translation.y += penetration;
+        body.linear_velocity.y = body.linear_velocity.y.max(0.);
+        let horizontal_velocity = body.linear_velocity.with_y(0.);
+        let speed_loss = GROUND_FRICTION_COEFFICIENT * GRAV_ACCELERATION * PHYSICS_TIMESTEP;
+        let horizontal_velocity = horizontal_velocity.normalize_or_zero() * (horizontal_velocity.length() - speed_loss).max(0.);
+        body.linear_velocity = horizontal_velocity.with_y(body.linear_velocity.y);
+
+        // Stop rolling once the vehicle lies on its side
+        if transform.rotation.mul_vec3(Vec3::Y).y <= 0. {
+            body.angular_velocity = Vec3::ZERO;
+        } else {
+            body.angular_velocity *= GROUND_ANGULAR_DAMPING.powf(PHYSICS_TIMESTEP);
+        }
+    }
+
+    for (attached_to, offset, mut part_transform) in &mut parts_query {
+        if let Ok((_, _, body_transform)) = bodies_query.get(attached_to.0) {
+            *part_transform = body_transform.mul_transform(offset.0);
+        }
+    }
+}
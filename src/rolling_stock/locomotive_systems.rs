@@ -0,0 +1,70 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::rolling_stock::bogie_systems::GRAV_ACCELERATION;
+use crate::rolling_stock::components::{AttachedToWagon, BogiePhysics, Locomotive, WagonPhysics};
+
+/// The share of the adhesion limit that slipping wheels can still transmit to the rails.
+const SLIP_ADHESION_RATIO: f32 = 0.8;
+
+/// The condition of the rail surface, which scales the adhesion coefficient of all the locomotives.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub(crate) enum RailCondition {
+    #[default]
+    Dry,
+    Wet,
+    Leaves,
+    Ice,
+}
+
+impl RailCondition {
+    pub(crate) const ALL: [RailCondition; 4] = [RailCondition::Dry, RailCondition::Wet, RailCondition::Leaves, RailCondition::Ice];
+
+    pub(crate) fn adhesion_factor(&self) -> f32 {
+        match self {
+            RailCondition::Dry => 1.,
+            RailCondition::Wet => 0.7,
+            RailCondition::Leaves => 0.3,
+            RailCondition::Ice => 0.15,
+        }
+    }
+}
+
+/// Sets the tractive force of each locomotive from its throttle and speed, limited by the adhesion of its bogies.
+/// If the demanded force exceeds the adhesion limit, the wheels slip and only part of the limit is transmitted.
+pub(crate) fn set_locomotive_tractive_forces(
+    mut locomotives_query: Query<(Entity, &mut Locomotive, &mut WagonPhysics)>,
+    bogies_query: Query<(&BogiePhysics, &AttachedToWagon)>,
+    rail_condition: Res<RailCondition>,
+) {
+    for (entity, mut locomotive, mut wagon_physics) in &mut locomotives_query {
+        let bogies: Vec<&BogiePhysics> = bogies_query.iter()
+            .filter(|(_, attached_to)| attached_to.0 == entity)
+            .map(|(bogie_physics, _)| bogie_physics)
+            .collect();
+
+        // All the axles are powered, so the whole weight of the locomotive counts as axle load.
+        let adhesion_coefficient = locomotive.adhesion_coefficient * rail_condition.adhesion_factor();
+        let adhesion_limit: f32 = bogies.iter()
+            .map(|bogie_physics| {
+                let axle_load = (wagon_physics.mass / bogies.len() as f32 + bogie_physics.mass) * GRAV_ACCELERATION;
+                let slope_cos = bogie_physics.current_slope_angle.unwrap_or(0.).cos();
+                adhesion_coefficient * axle_load * slope_cos
+            })
+            .sum();
+
+        let demanded_force = locomotive.get_tractive_effort(wagon_physics.velocity);
+        let wheel_slip = demanded_force.abs() > adhesion_limit;
+        wagon_physics.tractive_force = if wheel_slip {
+            demanded_force.signum() * adhesion_limit * SLIP_ADHESION_RATIO
+        } else {
+            demanded_force
+        };
+
+        if wheel_slip && !locomotive.wheel_slip {
+            debug!("Wheel slip on locomotive {:?}: demanded {:.0} N, adhesion limit {:.0} N", entity, demanded_force, adhesion_limit);
+        }
+        locomotive.demanded_force = demanded_force;
+        locomotive.adhesion_limit = adhesion_limit;
+        locomotive.wheel_slip = wheel_slip;
+    }
+}
@@ -1,58 +1,148 @@
-pub(crate) mod components;
+pub mod components;
+mod animation_systems;
 mod bogie_systems;
-mod wagon_systems;
-mod utils;
+mod brake_systems;
+pub(crate) mod cargo_systems;
+mod constraint_systems;
+mod coupler_systems;
+mod derailment_systems;
+pub(crate) mod locomotive_systems;
+mod plot_systems;
+pub mod stock_definitions;
+pub mod wagon_systems;
+pub(crate) mod utils;
 mod ui_systems;
 
 use bevy::prelude::*;
 
 use crate::assets::AssetLoadingState;
+use crate::save::PendingRestore;
+use crate::world::train_tracks::load_track_around_bogies;
 
 use crate::rolling_stock::components::{Bogie, BogiePhysics, Wagon, WagonPhysics};
+use crate::rolling_stock::animation_systems::*;
 use crate::rolling_stock::bogie_systems::*;
+use crate::rolling_stock::brake_systems::*;
+use crate::rolling_stock::cargo_systems::*;
+use crate::rolling_stock::constraint_systems::*;
+use crate::rolling_stock::coupler_systems::*;
+use crate::rolling_stock::derailment_systems::*;
+use crate::rolling_stock::locomotive_systems::*;
+use crate::rolling_stock::plot_systems::*;
 use crate::rolling_stock::ui_systems::*;
 use crate::rolling_stock::wagon_systems::*;
 
-pub struct RollingStockPlugin;
+/// Simulates the rolling stock on the tracks and spawns the train at the start of the route.
+#[derive(Default)]
+pub struct RollingStockPlugin {
+    /// Only run the simulation, without the UI, the cargo meshes and the wheel animation (no renderer needed).
+    pub headless: bool,
+    /// The ids of the vehicles of the train spawned at the start of the route. Uses the default consist if `None`.
+    pub consist: Option<Vec<String>>,
+}
 
+/// The steps of the rolling stock physics, which all run in `FixedUpdate` in this order.
 #[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
-enum WagonPhysicsSet {
+pub enum WagonPhysicsSet {
+    /// Applies the controls of the vehicles (the throttle, the brakes and the switches), e.g. from a replay.
+    Controls,
+    /// Reads the state of the track under the bogies.
+    ReadTrack,
     SetForces,
+    /// Integrates the forces into velocities and positions, and resolves the constraints.
     ApplyForces,
 }
 
 impl Plugin for RollingStockPlugin {
     fn build(&self, app: &mut App) {
         app
-            .configure_sets(Update, WagonPhysicsSet::ApplyForces.after(WagonPhysicsSet::SetForces))
+            .configure_sets(FixedUpdate,
+                            (WagonPhysicsSet::Controls, WagonPhysicsSet::ReadTrack, WagonPhysicsSet::SetForces, WagonPhysicsSet::ApplyForces)
+                                .chain()
+                                .run_if(in_state(AssetLoadingState::AssetsLoaded))
+                                .run_if(not(resource_exists::<PendingRestore>)))
+            .init_resource::<RailCondition>()
+            .init_resource::<NextWagonNumber>()
+            .init_resource::<PhysicsTick>()
+            .init_resource::<WagonBogieCounts>()
+            .insert_resource(ConsistSelection(self.consist.clone()))
+            .add_event::<Derailment>()
+            .add_event::<Uncouple>()
 
-            .add_systems(OnEnter(AssetLoadingState::AssetsLoaded), spawn_wagon)
+            .add_systems(OnEnter(AssetLoadingState::AssetsLoaded), spawn_train.run_if(not(resource_exists::<PendingRestore>)))
+            .add_systems(FixedUpdate,
+                         (
+                             load_track_around_bogies.run_if(not(resource_exists::<PendingRestore>)),
+                             assign_bogie_tracks,
+                         )
+                             .chain()
+                             .before(WagonPhysicsSet::Controls)
+                             .run_if(in_state(AssetLoadingState::AssetsLoaded)))
+            .add_systems(FixedUpdate, uncouple_wagons.before(WagonPhysicsSet::Controls))
 
-            // Step 1 - set forces
-            .add_systems(Update,
+            // Step 1 - read the track
+            .add_systems(FixedUpdate,
                          (
+                             mark_bogies_on_unloaded_track,
+                             store_previous_bogie_positions,
                              update_bogie_current_slope_angle,
-                             set_bogie_static_kinetic_forces.after(update_bogie_current_slope_angle),
-                             set_bogie_vertical_forces.after(update_bogie_current_slope_angle),
-                             set_bogie_horizontal_forces.after(update_bogie_current_slope_angle)
+                             update_bogie_current_curve_radius,
                          )
+                             .chain()
+                             .in_set(WagonPhysicsSet::ReadTrack)
+            )
+            // Step 2 - set forces
+            .add_systems(FixedUpdate,
+                         (
+                             count_wagon_bogies,
+                             transfer_cargo,
+                             update_wagon_masses,
+                             update_brake_pipes,
+                             update_triple_valves,
+                             set_brake_forces,
+                             set_locomotive_tractive_forces,
+                             set_bogie_static_kinetic_forces,
+                             set_bogie_vertical_forces,
+                             set_bogie_horizontal_forces,
+                             set_coupler_forces,
+                         )
+                             .chain()
                              .in_set(WagonPhysicsSet::SetForces)
-                             .run_if(in_state(AssetLoadingState::AssetsLoaded))
             )
-            // Step 2 - apply forces
+            // Step 3 - apply forces
             .add_systems(FixedUpdate,
                          (
                              apply_bogie_forces,
-                             apply_bogie_velocities,
                              sync_bogie_velocities,
-                             constrain_attached_bogies
+                             sync_wagon_velocities,
+                             apply_bogie_velocities,
+                             detect_derailments,
+                             derail_vehicles,
+                             solve_bogie_constraints,
+                             couple_touching_wagons,
+                             turn_wheels,
+                             count_physics_ticks,
                          )
+                             .chain()
                              .in_set(WagonPhysicsSet::ApplyForces)
-                             .run_if(in_state(AssetLoadingState::AssetsLoaded))
             )
 
-            .add_systems(Update, (update_bogie_transforms, sync_wagons_with_bogies))
-            .add_systems(Update, tracked_wagon_status_ui);
+            // Derailed vehicles are not on the track, so they keep moving while the track around the others is loading
+            .add_systems(FixedUpdate, update_free_bodies.run_if(in_state(AssetLoadingState::AssetsLoaded)))
+
+            .add_systems(Update, (update_bogie_transforms, sync_wagons_with_bogies).chain())
+            .add_systems(Update, place_loading_points.run_if(in_state(AssetLoadingState::AssetsLoaded)));
+
+        if self.headless {
+            return;
+        }
+
+        app
+            .add_systems(Update, (attach_wheelsets, update_wheelset_transforms).chain())
+            .add_systems(Update, (spawn_cargo_loads, update_cargo_loads).run_if(in_state(AssetLoadingState::AssetsLoaded)))
+            .init_resource::<WagonPlots>()
+            .add_systems(FixedUpdate, record_plot_samples.in_set(WagonPhysicsSet::ApplyForces).after(count_physics_ticks))
+            .add_systems(Update, (tracked_wagon_status_ui, tracked_wagon_plots_ui));
     }
 }
 
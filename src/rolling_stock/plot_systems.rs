@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Line, Plot, PlotPoints, Points};
+use crate::{noise, NoiseSettings, PHYSICS_TIMESTEP};
+use crate::rolling_stock::components::{AirBrake, AttachedToWagon, Bogie, BogiePhysics, TrackedWagon, WagonPhysics};
+use crate::rolling_stock::wagon_systems::PhysicsTick;
+use crate::world::train_tracks::Track;
+
+/// The number of physics steps between the samples of the plots.
+const PLOT_SAMPLE_INTERVAL: u64 = 6;
+/// The time shown by the plots in s.
+const PLOT_HISTORY: f32 = 60.;
+/// The distance of the route shown by the elevation profile ahead of and behind the tracked wagon in m.
+const PROFILE_DISTANCE_AHEAD: f32 = 2000.;
+const PROFILE_DISTANCE_BEHIND: f32 = 300.;
+/// The step in t between the points of the elevation profile.
+const PROFILE_STEP_T: f32 = 0.1;
+const PROFILE_COLOR: egui::Color32 = egui::Color32::from_rgb(150, 110, 70);
+const BOGIE_COLOR: egui::Color32 = egui::Color32::from_rgb(230, 60, 60);
+const PLOT_HEIGHT: f32 = 80.;
+
+/// A value of the tracked wagon plotted over time.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PlotSeries {
+    Velocity,
+    TractiveForce,
+    BrakingForce,
+    CouplerForce,
+    Grade,
+    BrakePipePressure,
+    BrakeCylinderPressure,
+}
+
+impl PlotSeries {
+    const ALL: [PlotSeries; 7] = [
+        PlotSeries::Velocity,
+        PlotSeries::TractiveForce,
+        PlotSeries::BrakingForce,
+        PlotSeries::CouplerForce,
+        PlotSeries::Grade,
+        PlotSeries::BrakePipePressure,
+        PlotSeries::BrakeCylinderPressure,
+    ];
+    /// The titles of the plots, the series with the same unit share a plot.
+    const CHARTS: [&'static str; 4] = ["Velocity (m/s)", "Forces (kN)", "Grade (%)", "Brake pressures (bar)"];
+
+    fn name(&self) -> &'static str {
+        match self {
+            PlotSeries::Velocity => "Velocity",
+            PlotSeries::TractiveForce => "Tractive force",
+            PlotSeries::BrakingForce => "Braking force",
+            PlotSeries::CouplerForce => "Coupler force",
+            PlotSeries::Grade => "Grade",
+            PlotSeries::BrakePipePressure => "Brake pipe",
+            PlotSeries::BrakeCylinderPressure => "Brake cylinder",
+        }
+    }
+
+    fn chart(&self) -> usize {
+        match self {
+            PlotSeries::Velocity => 0,
+            PlotSeries::TractiveForce | PlotSeries::BrakingForce | PlotSeries::CouplerForce => 1,
+            PlotSeries::Grade => 2,
+            PlotSeries::BrakePipePressure | PlotSeries::BrakeCylinderPressure => 3,
+        }
+    }
+
+    fn color(&self) -> egui::Color32 {
+        match self {
+            PlotSeries::Velocity => egui::Color32::from_rgb(80, 160, 255),
+            PlotSeries::TractiveForce => egui::Color32::from_rgb(90, 200, 90),
+            PlotSeries::BrakingForce => egui::Color32::from_rgb(230, 80, 80),
+            PlotSeries::CouplerForce => egui::Color32::from_rgb(230, 180, 60),
+            PlotSeries::Grade => PROFILE_COLOR,
+            PlotSeries::BrakePipePressure => egui::Color32::from_rgb(80, 200, 220),
+            PlotSeries::BrakeCylinderPressure => egui::Color32::from_rgb(220, 90, 200),
+        }
+    }
+}
+
+/// The recent samples of the tracked wagon, and the series selected for the plots.
+#[derive(Resource)]
+pub(crate) struct WagonPlots {
+    /// The wagon the samples were taken from. The samples are cleared when another wagon is tracked.
+    wagon: Option<Entity>,
+    /// The time of each sample and the values of the series in the order of `PlotSeries::ALL`.
+    samples: VecDeque<(f32, [f32; PlotSeries::ALL.len()])>,
+    enabled: [bool; PlotSeries::ALL.len()],
+    paused: bool,
+}
+
+impl Default for WagonPlots {
+    fn default() -> Self {
+        Self {
+            wagon: None,
+            samples: VecDeque::new(),
+            enabled: [true; PlotSeries::ALL.len()],
+            paused: false,
+        }
+    }
+}
+
+pub(crate) fn record_plot_samples(
+    mut plots: ResMut<WagonPlots>,
+    tick: Res<PhysicsTick>,
+    tracked_wagon_query: Query<(Entity, &WagonPhysics, &AirBrake), With<TrackedWagon>>,
+    bogie_query: Query<(&Bogie, &BogiePhysics, &AttachedToWagon)>,
+) {
+    if plots.paused || tick.0 % PLOT_SAMPLE_INTERVAL != 0 {
+        return;
+    }
+    let Ok((wagon_entity, wagon_physics, air_brake)) = tracked_wagon_query.get_single() else {
+        return;
+    };
+    if plots.wagon != Some(wagon_entity) {
+        plots.wagon = Some(wagon_entity);
+        plots.samples.clear();
+    }
+
+    let bogies: Vec<_> = bogie_query.iter()
+        .filter(|(_, _, attached_to)| attached_to.0 == wagon_entity)
+        .collect();
+    let coupler_force: f32 = bogies.iter().map(|(_, physics, _)| physics.coupler_force).sum();
+    let grade = bogies.iter()
+        .find(|(bogie, ..)| bogie.is_leading == Some(true))
+        .and_then(|(_, physics, _)| physics.current_slope_angle)
+        .map(|angle| angle.tan() * 100.)
+        .unwrap_or_default();
+
+    let time = tick.0 as f32 * PHYSICS_TIMESTEP;
+    let values = PlotSeries::ALL.map(|series| match series {
+        PlotSeries::Velocity => wagon_physics.velocity,
+        PlotSeries::TractiveForce => wagon_physics.tractive_force / 1000.,
+        PlotSeries::BrakingForce => wagon_physics.braking_force / 1000.,
+        PlotSeries::CouplerForce => coupler_force / 1000.,
+        PlotSeries::Grade => grade,
+        PlotSeries::BrakePipePressure => air_brake.brake_pipe_pressure,
+        PlotSeries::BrakeCylinderPressure => air_brake.brake_cylinder_pressure,
+    });
+    plots.samples.push_back((time, values));
+    while plots.samples.front().is_some_and(|(sample_time, _)| *sample_time < time - PLOT_HISTORY) {
+        plots.samples.pop_front();
+    }
+}
+
+/// Shows the recent values of the tracked wagon over time, and the elevation of the route around it
+/// with the bogies of the train on the same track marked.
+pub(crate) fn tracked_wagon_plots_ui(
+    mut egui_contexts: EguiContexts,
+    mut plots: ResMut<WagonPlots>,
+    noise_settings: Res<NoiseSettings>,
+    tracked_wagon_query: Query<Entity, With<TrackedWagon>>,
+    bogie_query: Query<(&Bogie, &AttachedToWagon)>,
+    track_query: Query<&Track>,
+) {
+    let Ok(wagon_entity) = tracked_wagon_query.get_single() else {
+        return;
+    };
+
+    egui::Window::new("Tracked Wagon Plots").default_open(false).show(egui_contexts.ctx_mut(), |ui| {
+        ui.set_width(350.);
+        ui.horizontal(|ui| {
+            let label = if plots.paused { "Resume" } else { "Pause" };
+            if ui.button(label).clicked() {
+                plots.paused = !plots.paused;
+            }
+        });
+        ui.horizontal_wrapped(|ui| {
+            for (index, series) in PlotSeries::ALL.iter().enumerate() {
+                ui.checkbox(&mut plots.enabled[index], egui::RichText::new(series.name()).color(series.color()));
+            }
+        });
+
+        // Time relative to the last sample, so that the plots scroll
+        let last_time = plots.samples.back().map(|(time, _)| *time).unwrap_or_default();
+        for (chart, title) in PlotSeries::CHARTS.iter().enumerate() {
+            let series: Vec<_> = PlotSeries::ALL.iter().enumerate()
+                .filter(|(index, series)| series.chart() == chart && plots.enabled[*index])
+                .collect();
+            if series.is_empty() {
+                continue;
+            }
+            ui.label(*title);
+            // Follows the samples as they scroll, so it can't be dragged or zoomed
+            Plot::new(title).height(PLOT_HEIGHT).allow_drag(false).allow_zoom(false).allow_scroll(false).x_axis_label("s").show(ui, |plot_ui| {
+                for (index, series) in series {
+                    let points: PlotPoints = plots.samples.iter().map(|(time, values)| [(time - last_time) as f64, values[index] as f64]).collect();
+                    plot_ui.line(Line::new(points).color(series.color()).name(series.name()));
+                }
+            });
+        }
+
+        ui.separator();
+        ui.label("Elevation (m)");
+        let leading_bogie = bogie_query.iter()
+            .find(|(bogie, attached_to)| attached_to.0 == wagon_entity && bogie.is_leading == Some(true))
+            .map(|(bogie, _)| bogie);
+        let Some((track_entity, start_t)) = leading_bogie.and_then(|bogie| Some((bogie.current_track?, bogie.position_on_track))) else {
+            ui.label("The tracked wagon is not on a track.");
+            return;
+        };
+        let Ok(track) = track_query.get(track_entity) else {
+            return;
+        };
+
+        let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+        // The points of the profile as (t, distance from the leading bogie, elevation), in the order of t
+        let walk = |step: f32, max_distance: f32| {
+            let mut points = Vec::new();
+            let mut distance = 0.;
+            let mut last_position = track.get_interpolated_position_at_t(start_t, &height_fn).map(|(position, _)| position);
+            let mut t = start_t;
+            while let Some(previous) = last_position {
+                points.push((t, distance * step.signum(), previous.y));
+                t += step;
+                last_position = track.get_interpolated_position_at_t(t, &height_fn).map(|(position, _)| position);
+                if let Some(position) = last_position {
+                    distance += position.xz().distance(previous.xz());
+                }
+                if distance > max_distance {
+                    break;
+                }
+            }
+            points
+        };
+        let mut profile = walk(-PROFILE_STEP_T, PROFILE_DISTANCE_BEHIND);
+        profile.reverse();
+        profile.pop();
+        profile.extend(walk(PROFILE_STEP_T, PROFILE_DISTANCE_AHEAD));
+
+        // Place the bogies by the distance at their t
+        let bogies: Vec<[f64; 2]> = bogie_query.iter()
+            .filter(|(bogie, _)| bogie.current_track == Some(track_entity))
+            .filter_map(|(bogie, _)| {
+                let index = profile.partition_point(|(t, ..)| *t < bogie.position_on_track);
+                let (t1, distance1, elevation1) = *profile.get(index)?;
+                let (t0, distance0, elevation0) = *profile.get(index.checked_sub(1)?)?;
+                let fraction = (bogie.position_on_track - t0) / (t1 - t0);
+                Some([(distance0 + (distance1 - distance0) * fraction) as f64, (elevation0 + (elevation1 - elevation0) * fraction) as f64])
+            })
+            .collect();
+        let line: PlotPoints = profile.iter().map(|(_, distance, elevation)| [*distance as f64, *elevation as f64]).collect();
+        Plot::new("Elevation").height(PLOT_HEIGHT).x_axis_label("m").show(ui, |plot_ui| {
+            plot_ui.line(Line::new(line).color(PROFILE_COLOR).name("Elevation"));
+            plot_ui.points(Points::new(bogies).radius(3.).color(BOGIE_COLOR).name("Bogies"));
+        });
+    });
+}
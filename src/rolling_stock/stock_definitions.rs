@@ -0,0 +1,75 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+use crate::rolling_stock::components::{CargoSpace, Coupler, RunningResistance, VehicleStability};
+
+/// The rolling stock defined in `definitions/rolling_stock.stock.ron`, mapped by id.
+#[derive(Asset, TypePath, Deserialize)]
+pub struct RollingStockSet {
+    /// The ids of the vehicles of the train spawned at the start of the route, from front to rear.
+    pub default_consist: Vec<String>,
+    pub definitions: HashMap<String, RollingStockDefinition>,
+}
+
+/// A type of vehicle: its models, masses, dimensions and equipment.
+#[derive(Clone, Deserialize)]
+pub struct RollingStockDefinition {
+    /// The asset path of the body scene.
+    pub model: String,
+    /// The asset path of the bogie scene.
+    pub bogie_model: String,
+    /// The mass of the empty body in kg, without the bogies.
+    pub mass: f32,
+    /// The mass of each of the two bogies in kg.
+    pub bogie_mass: f32,
+    /// The rolling radius of the wheels in m.
+    pub wheel_radius: f32,
+    /// The distance between the bogie pivots in m.
+    pub bogie_spacing: f32,
+    /// The height of the body origin above the bogie origins in m.
+    pub body_offset: f32,
+    /// The mass of the payload the vehicle can carry in kg.
+    #[serde(default)]
+    pub capacity: f32,
+    pub resistance: RunningResistance,
+    pub brake: BrakeDefinition,
+    pub coupler: Coupler,
+    pub stability: VehicleStability,
+    /// The space bulk cargo is loaded into, if the vehicle carries any.
+    #[serde(default)]
+    pub cargo_space: Option<CargoSpace>,
+    /// The traction equipment, if the vehicle is a locomotive.
+    #[serde(default)]
+    pub locomotive: Option<LocomotiveDefinition>,
+}
+
+impl RollingStockDefinition {
+    /// Returns the distance between the coupler faces in m.
+    pub fn length(&self) -> f32 {
+        self.bogie_spacing + 2. * self.coupler.overhang
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct BrakeDefinition {
+    /// The brake cylinder pressure of a full application in bar.
+    pub max_cylinder_pressure: f32,
+    /// The total brake block force per bar of brake cylinder pressure in N.
+    pub block_force_per_bar: f32,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct LocomotiveDefinition {
+    pub max_notch: u32,
+    /// The tractive effort at full throttle in the constant-force region in N.
+    pub max_tractive_effort: f32,
+    /// The power at the rails at full throttle in W.
+    pub max_power: f32,
+    /// The adhesion coefficient between the wheels and dry rails.
+    pub adhesion_coefficient: f32,
+    /// Whether the vehicle has the driver's brake valve controlling the brake pipe of the train.
+    #[serde(default = "default_has_brake_valve")]
+    pub has_brake_valve: bool,
+}
+
+fn default_has_brake_valve() -> bool { true }
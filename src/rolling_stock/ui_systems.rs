@@ -1,41 +1,159 @@
+use std::f32::consts::{FRAC_PI_2, PI};
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContexts};
 use bevy_egui::egui::emath;
-use crate::rolling_stock::components::{AttachedToWagon, Bogie, BogiePhysics, TrackedWagon, WagonPhysics};
+use crate::rolling_stock::components::{AirBrake, AttachedToWagon, Bogie, BogiePhysics, BrakeValvePosition, Cargo, CargoSpace, CargoTransfer, Coupling, Derailed, DriversBrakeValve, Locomotive, Reverser, TrackedWagon, Wagon, WagonPhysics};
+use crate::rolling_stock::cargo_systems::{LoadingPoint, LoadingPointKind};
+use crate::rolling_stock::coupler_systems::Uncouple;
+use crate::rolling_stock::locomotive_systems::RailCondition;
 use crate::rolling_stock::utils;
+use crate::world::train_tracks::Crossover;
 
 pub(crate) fn tracked_wagon_status_ui(
     mut egui_contexts: EguiContexts,
-    mut tracked_wagon_query: Query<(Entity, &mut WagonPhysics), (With<TrackedWagon>, Without<AttachedToWagon>)>,
+    mut uncouple_events: EventWriter<Uncouple>,
+    mut tracked_wagon_query: Query<(Entity, &Wagon, &WagonPhysics, &Transform, Option<&mut Locomotive>), (With<TrackedWagon>, Without<AttachedToWagon>)>,
     bogie_entity_query: Query<(Entity, &AttachedToWagon)>,
-    bogie_query: Query<(&Bogie, &BogiePhysics)>,
+    bogie_query: Query<(&Bogie, &BogiePhysics, &Transform)>,
+    mut crossover_query: Query<&mut Crossover>,
+    couplings_query: Query<(Entity, &Coupling)>,
+    mut rail_condition: ResMut<RailCondition>,
+    mut brake_valve_query: Query<&mut DriversBrakeValve, With<TrackedWagon>>,
+    air_brake_query: Query<&AirBrake>,
+    derailed_query: Query<&Derailed>,
+    cargo_query: Query<(&CargoSpace, &Cargo)>,
+    loading_points_query: Query<&LoadingPoint>,
 ) {
-    if tracked_wagon_query.is_empty() {
+    let Ok((wagon_entity, wagon, wagon_physics, wagon_transform, mut locomotive)) = tracked_wagon_query.get_single_mut() else {
         return;
-    }
-
-    let (wagon_entity, mut wagon_physics) = tracked_wagon_query.single_mut();
+    };
     let bogies = utils::get_attached_bogies(&wagon_entity, &bogie_entity_query);
 
     egui::Window::new("Tracked Wagon").show(egui_contexts.ctx_mut(), |ui| {
         ui.allocate_space(emath::Vec2::new(250., 0.));
         ui.set_max_width(250.0);
 
-        // Display controls for tractive and braking force
-        ui.add(egui::Slider::new(&mut wagon_physics.tractive_force, -300000.0..=300000.).text("Tractive force"));
-        ui.add(egui::Slider::new(&mut wagon_physics.braking_force, 0.0..=300000.).text("Braking force"));
+        // Display the locomotive controls and the braking force
+        if let Some(locomotive) = locomotive.as_mut() {
+            let max_notch = locomotive.max_notch;
+            ui.add(egui::Slider::new(&mut locomotive.notch, 0..=max_notch).text("Throttle notch"));
+            ui.horizontal(|ui| {
+                ui.label("Reverser:");
+                ui.radio_value(&mut locomotive.reverser, Reverser::Reverse, "Reverse");
+                ui.radio_value(&mut locomotive.reverser, Reverser::Neutral, "Neutral");
+                ui.radio_value(&mut locomotive.reverser, Reverser::Forward, "Forward");
+            });
+        }
+        if let Ok(mut brake_valve) = brake_valve_query.get_single_mut() {
+            ui.horizontal(|ui| {
+                ui.label("Brake valve:");
+                ui.radio_value(&mut brake_valve.position, BrakeValvePosition::Release, "Release");
+                ui.radio_value(&mut brake_valve.position, BrakeValvePosition::Lap, "Lap");
+                ui.radio_value(&mut brake_valve.position, BrakeValvePosition::Service, "Service");
+                ui.radio_value(&mut brake_valve.position, BrakeValvePosition::Emergency, "Emergency");
+            });
+        }
+        egui::ComboBox::from_label("Rail condition")
+            .selected_text(format!("{:?}", *rail_condition))
+            .show_ui(ui, |ui| {
+                for condition in RailCondition::ALL {
+                    ui.selectable_value(&mut *rail_condition, condition, format!("{:?}", condition));
+                }
+            });
+
+        // Display the switch setting of the next crossover ahead of the leading bogie.
+        let leading_bogie = bogies.iter()
+            .filter_map(|entity| bogie_query.get(*entity).ok())
+            .find(|(bogie, _, _)| bogie.is_leading == Some(true));
+        if let Some((leading_bogie, _, _)) = leading_bogie {
+            let next_crossover = crossover_query.iter_mut()
+                .filter(|crossover| leading_bogie.current_track.is_some_and(|track| crossover.connects(track)))
+                .filter(|crossover| crossover.segment_id >= leading_bogie.position_on_track.floor() as usize)
+                .min_by_key(|crossover| crossover.segment_id);
+            if let Some(mut crossover) = next_crossover {
+                let segment_id = crossover.segment_id;
+                let is_occupied = crossover.is_occupied(bogie_query.iter().map(|(bogie, ..)| bogie));
+                ui.add_enabled(!is_occupied, egui::Checkbox::new(&mut crossover.diverging, format!("Take crossover at segment {}", segment_id)))
+                    .on_disabled_hover_text("The switches can't be thrown while a bogie is on the crossover.");
+            }
+
+            let next_loading_point = loading_points_query.iter()
+                .filter(|point| point.segment_id >= leading_bogie.position_on_track.floor() as usize)
+                .min_by_key(|point| point.segment_id);
+            if let Some(loading_point) = next_loading_point {
+                let kind = match loading_point.kind {
+                    LoadingPointKind::Load(cargo_type) => format!("loads {:?}", cargo_type),
+                    LoadingPointKind::Unload => "unloads".to_string(),
+                };
+                ui.label(format!("Next loading point at segment {} {}", loading_point.segment_id, kind));
+            }
+        }
 
         ui.separator();
 
         // Display the status of the wagon.
+        if let Ok(derailed) = derailed_query.get(wagon_entity) {
+            ui.colored_label(egui::Color32::RED, format!("Derailed: {}", derailed.cause));
+        }
+        ui.label(format!("Type: {}", wagon.definition_id));
+        ui.label(format!("Capacity: {:.0}", wagon.capacity));
         ui.label(format!("Mass: {}", wagon_physics.mass));
+        if let Ok((cargo_space, cargo)) = cargo_query.get(wagon_entity) {
+            ui.label(format!("Cargo: {:?}, {:.0}% full ({:.0} kg)", cargo.cargo_type, cargo.fill_level * 100., cargo.get_mass(cargo_space)));
+            match cargo.transfer {
+                CargoTransfer::Loading => { ui.label("Loading..."); },
+                CargoTransfer::Unloading => { ui.label("Unloading..."); },
+                CargoTransfer::Idle => {},
+            }
+        }
         ui.label(format!("Velocity: {}", wagon_physics.velocity));
         ui.label(format!("Tractive force: {}", wagon_physics.tractive_force));
         ui.label(format!("Braking force: {}", wagon_physics.braking_force));
+        if let Some(locomotive) = &locomotive {
+            ui.label(format!("Demanded tractive force: {:.0}", locomotive.demanded_force));
+            ui.label(format!("Adhesion limit: {:.0}", locomotive.adhesion_limit));
+            if locomotive.wheel_slip {
+                ui.colored_label(egui::Color32::RED, "Wheel slip!");
+            }
+        }
+
+        // Display the consist and the couplers of the wagon.
+        let consist = utils::get_consist(wagon_entity, &couplings_query);
+        let position = consist.iter().position(|wagon| *wagon == wagon_entity).unwrap_or(0);
+        ui.label(format!("Consist: wagon {} of {}", position + 1, consist.len()));
+
+        // Display the air brake pressures, and the brake pipe pressure at the end of the consist to see the propagation.
+        if let Ok(air_brake) = air_brake_query.get(wagon_entity) {
+            ui.label(format!("Brake pipe: {:.2} bar", air_brake.brake_pipe_pressure));
+            ui.label(format!("Auxiliary reservoir: {:.2} bar", air_brake.auxiliary_reservoir_pressure));
+            ui.label(format!("Brake cylinder: {:.2} bar", air_brake.brake_cylinder_pressure));
+        }
+        if let Some(last_air_brake) = consist.last().and_then(|wagon| air_brake_query.get(*wagon).ok()) {
+            ui.label(format!("Brake pipe at the rear: {:.2} bar", last_air_brake.brake_pipe_pressure));
+        }
+        for (coupling_entity, coupling) in &couplings_query {
+            let name = if coupling.rear_wagon == wagon_entity {
+                "Front coupler"
+            } else if coupling.front_wagon == wagon_entity {
+                "Rear coupler"
+            } else {
+                continue;
+            };
+            ui.horizontal(|ui| {
+                ui.label(format!("{} force: {:.0}", name, coupling.force));
+                if ui.button("Uncouple").clicked() {
+                    uncouple_events.send(Uncouple(coupling_entity));
+                }
+            });
+        }
 
         // Display status of each of the attached bogies.
         for bogie_entity in bogies {
-            let (bogie, bogie_physics) = bogie_query.get(bogie_entity).unwrap();
+            let (bogie, bogie_physics, bogie_transform) = bogie_query.get(bogie_entity).unwrap();
+            // The yaw of the bogie against the body, which follows the chord between the bogies
+            // (the bogie model is symmetric, so its facing does not matter)
+            let (bogie_yaw, _, _) = (wagon_transform.rotation.inverse() * bogie_transform.rotation).to_euler(EulerRot::YXZ);
+            let bogie_yaw = (bogie_yaw + FRAC_PI_2).rem_euclid(PI) - FRAC_PI_2;
             ui.collapsing(if bogie.is_leading.unwrap() {"Leading bogie"} else {"Trailing bogie"}, |collapsing_ui| {
                 collapsing_ui.label(format!("Mass: {}", bogie_physics.mass));
                 collapsing_ui.label(format!("Velocity: {}", bogie_physics.velocity));
@@ -43,6 +161,8 @@ pub(crate) fn tracked_wagon_status_ui(
                 collapsing_ui.label(format!("Horizontal force: {}", bogie_physics.horizontal_force));
                 collapsing_ui.label(format!("Kinetic force: {}", bogie_physics.kinetic_force));
                 collapsing_ui.label(format!("Static force: {}", bogie_physics.static_force));
+                collapsing_ui.label(format!("Curve radius: {:.0}", bogie_physics.current_curve_radius.unwrap_or(f32::INFINITY)));
+                collapsing_ui.label(format!("Yaw against the body: {:.2} deg", bogie_yaw.to_degrees()));
             });
         }
     });
@@ -1,18 +1,30 @@
 use bevy::prelude::*;
-use crate::rolling_stock::components::{AttachedToWagon, BogiePhysics, WagonPhysics};
+use crate::rolling_stock::components::{AttachedToWagon, BogiePhysics, Coupling, SharedWithWagon, WagonPhysics};
+use crate::rolling_stock::wagon_systems::WagonBogieCounts;
 
-pub(crate) fn get_carried_mass(
+/// Returns the wagons resting on the bogie, each with the share of its mass and forces the bogie carries
+/// (one over the number of bogies carrying the wagon). An articulation bogie carries a share of both its wagons.
+pub(crate) fn get_carried_wagons(
     attached_to: Option<&AttachedToWagon>,
+    shared_with: Option<&SharedWithWagon>,
+    bogie_counts: &WagonBogieCounts,
+) -> Vec<(Entity, f32)> {
+    attached_to.map(|attached_to| attached_to.0).into_iter()
+        .chain(shared_with.map(|shared_with| shared_with.0))
+        .map(|wagon| (wagon, bogie_counts.0.get(&wagon).map_or(1., |count| 1. / *count as f32)))
+        .collect()
+}
+
+/// Returns the mass carried by the bogie: its own mass and its share of the wagons resting on it, including the cargo.
+pub(crate) fn get_carried_mass(
+    carried_wagons: &[(Entity, f32)],
     physics: &BogiePhysics,
     wagons_query: &Query<&WagonPhysics>,
 ) -> f32 {
-    if let Some(attached_to) = attached_to {
-        let wagon = wagons_query.get(attached_to.0).unwrap();
-        let carried_wagon_mass = wagon.mass / 2.;
-        carried_wagon_mass + physics.mass
-    } else {
-        physics.mass
-    }
+    let carried_wagon_mass: f32 = carried_wagons.iter()
+        .map(|(wagon, share)| wagons_query.get(*wagon).unwrap().mass * share)
+        .sum();
+    carried_wagon_mass + physics.mass
 }
 
 pub(crate) fn get_attached_bogies(
@@ -28,3 +40,27 @@ pub(crate) fn get_attached_bogies(
 
     result
 }
+
+/// Returns the wagons of the consist the given wagon belongs to, from the front to the rear.
+pub(crate) fn get_consist(
+    wagon: Entity,
+    couplings_query: &Query<(Entity, &Coupling)>,
+) -> Vec<Entity> {
+    let mut front = wagon;
+    while let Some((_, coupling)) = couplings_query.iter().find(|(_, coupling)| coupling.rear_wagon == front) {
+        if coupling.front_wagon == wagon {
+            break;
+        }
+        front = coupling.front_wagon;
+    }
+
+    let mut result = vec![front];
+    while let Some((_, coupling)) = couplings_query.iter().find(|(_, coupling)| coupling.front_wagon == result[result.len() - 1]) {
+        if result.contains(&coupling.rear_wagon) {
+            break;
+        }
+        result.push(coupling.rear_wagon);
+    }
+
+    result
+}
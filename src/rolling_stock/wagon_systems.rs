@@ -1,68 +1,214 @@
 use bevy::prelude::*;
 use bevy::utils::HashMap;
-use crate::assets::ModelAssets;
+use crate::assets::{DefinitionAssets, ModelAssets};
 use crate::rolling_stock::{BogieBundle, WagonBundle};
-use crate::rolling_stock::components::{AttachedToWagon, Bogie, BogiePhysics, TrackedWagon, Wagon, WagonPhysics};
-use crate::world::train_tracks::Track;
+use crate::rolling_stock::brake_systems::RUNNING_PIPE_PRESSURE;
+use crate::rolling_stock::components::{AirBrake, AttachedToWagon, Bogie, BogieDistanceConstraint, BogiePhysics, Cargo, CargoType, Coupling, Derailed, DriversBrakeValve, Locomotive, Reverser, SharedWithWagon, TrackNotLoaded, TrackedWagon, Wagon, WagonNumber, WagonPhysics, Wheels};
+use crate::rolling_stock::stock_definitions::{RollingStockDefinition, RollingStockSet};
+use crate::world::WorldSettings;
 
-pub(crate) fn spawn_wagon(
+
+/// The t value of the trailing bogie of the last wagon of the spawned train.
+const TRAIN_START_T: f32 = 2.;
+
+/// The number given to the next spawned wagon.
+#[derive(Resource, Default)]
+pub(crate) struct NextWagonNumber(pub(crate) u32);
+
+/// The number of bogies carrying each wagon, including the articulation bogies it shares. Counted at the start of each physics step.
+#[derive(Resource, Default)]
+pub(crate) struct WagonBogieCounts(pub(crate) HashMap<Entity, u32>);
+
+/// The number of physics steps simulated so far (or since the start of the restored save).
+#[derive(Resource, Default)]
+pub struct PhysicsTick(pub u64);
+
+pub fn count_physics_ticks(mut tick: ResMut<PhysicsTick>) {
+    tick.0 += 1;
+}
+
+/// The ids of the vehicles of the train spawned at the start of the route. Uses the default consist of the set if `None`.
+#[derive(Resource, Default)]
+pub(crate) struct ConsistSelection(pub(crate) Option<Vec<String>>);
+
+/// Spawns the selected consist at the start of the route. The first vehicle is tracked.
+pub(crate) fn spawn_train(
     mut commands: Commands,
-    model_assets: Res<ModelAssets>,
+    asset_server: Res<AssetServer>,
+    model_assets: Option<Res<ModelAssets>>,
+    definition_assets: Res<DefinitionAssets>,
+    rolling_stock_sets: Res<Assets<RollingStockSet>>,
+    consist_selection: Res<ConsistSelection>,
+    settings: Res<WorldSettings>,
 ) {
+    let Some(rolling_stock) = rolling_stock_sets.get(&definition_assets.rolling_stock) else {
+        warn!("Rolling stock definitions are not loaded, not spawning a train.");
+        return;
+    };
+
+    let definition_ids = consist_selection.0.as_ref().unwrap_or(&rolling_stock.default_consist);
+    let consist = spawn_consist(&mut commands, &asset_server, model_assets.as_deref(), rolling_stock, definition_ids, TRAIN_START_T, settings.route.node_length);
+    if let Some(first_wagon) = consist.first() {
+        commands.entity(*first_wagon).insert(TrackedWagon);
+    }
+}
+
+/// Spawns a train of coupled vehicles with the given definition ids, from front to rear, with the rear end at `rear_t`.
+/// Unknown ids are skipped. Returns the spawned wagons. The vehicles get no scenes without the model assets.
+/// `node_length` is the distance between the route nodes, used to place the vehicles.
+pub fn spawn_consist(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    model_assets: Option<&ModelAssets>,
+    rolling_stock: &RollingStockSet,
+    definition_ids: &[String],
+    rear_t: f32,
+    node_length: f32,
+) -> Vec<Entity> {
+    let definitions: Vec<(&String, &RollingStockDefinition)> = definition_ids.iter()
+        .filter_map(|id| match rolling_stock.definitions.get(id) {
+            Some(definition) => Some((id, definition)),
+            None => {
+                warn!("Unknown rolling stock {:?}, skipping it.", id);
+                None
+            },
+        })
+        .collect();
+
+    // Roughly convert the lengths to t, assuming the segments are as long as the distance between the route nodes.
+    let mut trailing_t = rear_t + definitions.iter().map(|(_, definition)| definition.length()).sum::<f32>() / node_length;
+    let mut front_wagon = None;
+    let mut wagons = Vec::new();
+    for (id, definition) in definitions {
+        trailing_t -= definition.length() / node_length;
+        let leading_t = trailing_t + definition.bogie_spacing / node_length;
+        let wagon = spawn_wagon(commands, asset_server, model_assets, id, definition, leading_t, trailing_t).wagon;
+
+        if let Some(front_wagon) = front_wagon {
+            commands.spawn(Coupling { front_wagon, rear_wagon: wagon, force: 0. });
+        }
+        front_wagon = Some(wagon);
+        wagons.push(wagon);
+    }
+
+    wagons
+}
+
+/// Returns the scene at the given path. Scenes that are not preloaded with the model assets are loaded on demand.
+fn get_scene(asset_server: &AssetServer, model_assets: Option<&ModelAssets>, path: &String) -> Handle<Scene> {
+    let Some(model_assets) = model_assets else {
+        return Handle::default();
+    };
+    model_assets.rolling_stock.get(path).cloned().unwrap_or_else(|| asset_server.load(path.clone()))
+}
+
+/// The entities of a spawned wagon.
+pub(crate) struct SpawnedWagon {
+    pub(crate) wagon: Entity,
+    /// The leading and the trailing bogie.
+    pub(crate) bogies: [Entity; 2],
+    /// The `BogieDistanceConstraint` between the bogies.
+    pub(crate) constraint: Entity,
+}
+
+pub(crate) fn spawn_wagon(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    model_assets: Option<&ModelAssets>,
+    id: &String,
+    definition: &RollingStockDefinition,
+    leading_t: f32,
+    trailing_t: f32,
+) -> SpawnedWagon {
     let wagon = commands.spawn(WagonBundle {
         wagon: Wagon {
-            distance_between_bogies: 12.,
+            definition_id: id.clone(),
+            tare_mass: definition.mass,
+            body_offset: definition.body_offset,
+            capacity: definition.capacity,
         },
         physics: WagonPhysics {
-            mass: 30000.,
+            mass: definition.mass,
             velocity: 0.0,
-            tractive_force: 10000.,
+            tractive_force: 0.,
             braking_force: 0.,
         },
         scene: SceneBundle {
-            scene: model_assets.train_gondola.clone(),
+            scene: get_scene(asset_server, model_assets, &definition.model),
             ..default()
         },
     })
-        .insert(TrackedWagon)
+        .insert(definition.coupler.clone())
+        .insert(definition.resistance.clone())
+        .insert(definition.stability.clone())
+        .insert(AirBrake::charged(RUNNING_PIPE_PRESSURE, definition.brake.max_cylinder_pressure, definition.brake.block_force_per_bar))
         .id();
-    
-    commands.spawn(BogieBundle {
-        bogie: Bogie {
-            is_leading: Some(true),
-            current_track: None,
-            position_on_track: 2.,
-        },
-        physics: BogiePhysics {
-            mass: 4700.0,
-            ..default()
-        },
-        scene: SceneBundle {
-            scene: model_assets.train_wagon_bogie.clone(),
-            ..default()
-        },
-    })
-        .insert(AttachedToWagon(wagon.clone()));
-    commands.spawn(BogieBundle {
-        bogie: Bogie {
-            is_leading: Some(false),
-            current_track: None,
-            position_on_track: 2.,
-        },
-        physics: BogiePhysics {
-            mass: 4700.0,
-            ..default()
-        },
-        scene: SceneBundle {
-            scene: model_assets.train_wagon_bogie.clone(),
-            ..default()
-        },
-    })
-        .insert(AttachedToWagon(wagon.clone()));
+    // Numbered when the commands are applied, so that the wagons are numbered in the order they were spawned
+    commands.add(move |world: &mut World| {
+        let mut next_number = world.resource_mut::<NextWagonNumber>();
+        let number = WagonNumber(next_number.0);
+        next_number.0 += 1;
+        world.entity_mut(wagon).insert(number);
+    });
+
+    if let Some(cargo_space) = &definition.cargo_space {
+        commands.entity(wagon).insert((cargo_space.clone(), Cargo::empty(CargoType::Coal)));
+    }
+
+    if let Some(locomotive) = &definition.locomotive {
+        commands.entity(wagon).insert(Locomotive {
+            notch: 1,
+            max_notch: locomotive.max_notch,
+            reverser: Reverser::Forward,
+            max_tractive_effort: locomotive.max_tractive_effort,
+            max_power: locomotive.max_power,
+            adhesion_coefficient: locomotive.adhesion_coefficient,
+            demanded_force: 0.,
+            adhesion_limit: 0.,
+            wheel_slip: false,
+        });
+        if locomotive.has_brake_valve {
+            commands.entity(wagon).insert(DriversBrakeValve::default());
+        }
+    }
+
+    let bogie_scene = get_scene(asset_server, model_assets, &definition.bogie_model);
+    let mut spawn_bogie = |is_leading: bool, t: f32| {
+        commands.spawn(BogieBundle {
+            bogie: Bogie {
+                is_leading: Some(is_leading),
+                current_track: None,
+                position_on_track: t,
+                previous_position_on_track: t,
+                on_crossover: false,
+            },
+            physics: BogiePhysics {
+                mass: definition.bogie_mass,
+                ..default()
+            },
+            scene: SceneBundle {
+                scene: bogie_scene.clone(),
+                ..default()
+            },
+        })
+            .insert(AttachedToWagon(wagon))
+            .insert(Wheels { radius: definition.wheel_radius, angle: 0. })
+            .id()
+    };
+    let leading_bogie = spawn_bogie(true, leading_t);
+    let trailing_bogie = spawn_bogie(false, trailing_t);
+
+    let constraint = commands.spawn(BogieDistanceConstraint {
+        leading_bogie,
+        trailing_bogie,
+        distance: definition.bogie_spacing,
+    }).id();
+
+    SpawnedWagon { wagon, bogies: [leading_bogie, trailing_bogie], constraint }
 }
 
 pub(crate) fn sync_bogie_velocities(
-    mut bogies_query: Query<(&Bogie, &mut BogiePhysics, &AttachedToWagon), Without<Wagon>>,
+    mut bogies_query: Query<(&Bogie, &mut BogiePhysics, &AttachedToWagon), (Without<Wagon>, Without<TrackNotLoaded>)>,
 ) {
     let mut bogie_pairs = HashMap::<Entity, Vec<Mut<BogiePhysics>>>::new();
     for (_, bogie_physics, attached_to) in &mut bogies_query {
@@ -82,96 +228,66 @@ pub(crate) fn sync_bogie_velocities(
     }
 }
 
-pub(crate) fn sync_wagons_with_bogies(
-    bogies_query: Query<(&Bogie, &BogiePhysics, &Transform, &AttachedToWagon), Without<Wagon>>,
-    mut wagons_query: Query<(&mut WagonPhysics, &mut Transform), (With<Wagon>, Without<Bogie>)>,
+/// Sets the velocity of each wagon to the average velocity of its bogies, for the systems of the next physics step.
+pub(crate) fn sync_wagon_velocities(
+    bogies_query: Query<(&BogiePhysics, &AttachedToWagon), (Without<Wagon>, Without<Derailed>)>,
+    mut wagons_query: Query<&mut WagonPhysics, Without<Derailed>>,
 ) {
-    //TODO: move the code for finding bogie pairs into a separate function
-
-    let mut bogie_pairs = HashMap::<Entity, Vec<(&Bogie, &BogiePhysics, &Transform)>>::new();
-    for (bogie, bogie_physics, bogie_transform, attached_to) in &bogies_query {
-        if !bogie_pairs.contains_key(&attached_to.0) {
-            bogie_pairs.insert(attached_to.0.clone(), Vec::new());
-        }
-        bogie_pairs.get_mut(&attached_to.0).unwrap().push((bogie, bogie_physics, bogie_transform));
+    let mut bogie_velocities = HashMap::<Entity, (f32, u32)>::new();
+    for (bogie_physics, attached_to) in &bogies_query {
+        let (total_velocity, count) = bogie_velocities.entry(attached_to.0).or_insert((0., 0));
+        *total_velocity += bogie_physics.velocity;
+        *count += 1;
     }
 
-    for (wagon, bogies) in &bogie_pairs {
-        let (mut wagon_physics, mut wagon_transform) = wagons_query.get_mut(wagon.clone()).unwrap();
-
-        let mut total_bogie_velocity = 0.0;
-
-        let mut leading_bogie_transform = None;
-        let mut trailing_bogie_transform = None;
-        for (bogie, bogie_physics, bogie_transform) in bogies {
-            total_bogie_velocity += bogie_physics.velocity;
-            if bogie.is_leading.unwrap() {
-                leading_bogie_transform = Some(bogie_transform);
-            } else {
-                trailing_bogie_transform = Some(bogie_transform);
-            }
-        }
-
-        let avg_bogie_velocity = total_bogie_velocity / bogies.len() as f32;
-        wagon_physics.velocity = avg_bogie_velocity;
-
-        if leading_bogie_transform.is_none() || trailing_bogie_transform.is_none() {
-            warn!("Unable to update transform for wagon {:?}: unable to determine the leading or the trailing bogie.", wagon);
-            continue;
+    for (wagon, (total_velocity, count)) in bogie_velocities {
+        if let Ok(mut wagon_physics) = wagons_query.get_mut(wagon) {
+            wagon_physics.velocity = total_velocity / count as f32;
         }
-        let leading_transform = leading_bogie_transform.unwrap();
-        let trailing_transform = trailing_bogie_transform.unwrap();
-
-        wagon_transform.translation = trailing_transform.translation + (leading_transform.translation - trailing_transform.translation) / 2.;
-        wagon_transform.look_at(leading_transform.translation, Vec3::Y);
-        // TODO: Make this (and other similar stuff) configurable (via a .ron file, for instance)
-        wagon_transform.translation.y += 0.75;
     }
 }
 
-pub(crate) fn constrain_attached_bogies(
-    mut bogies_query: Query<(&mut Bogie, &Transform, &AttachedToWagon)>,
-    wagons_query: Query<&Wagon>,
-    track_query: Query<&Track>,
+/// Counts the bogies carrying each wagon, for splitting the mass and the forces of the wagons between their bogies.
+pub(crate) fn count_wagon_bogies(
+    bogies_query: Query<(&AttachedToWagon, Option<&SharedWithWagon>)>,
+    mut bogie_counts: ResMut<WagonBogieCounts>,
 ) {
-    // Make sure this runs only if update_bogie_transforms can also run.
-    // TODO: Make a more comprehensive check or rework this somehow (maybe run criteria?)
-    if track_query.is_empty() {
-        return;
-    }
-
-    let mut bogie_pairs = HashMap::<Entity, Vec<(Mut<Bogie>, &Transform)>>::new();
-    for (bogie, bogie_transform, attached_to) in &mut bogies_query {
-        if !bogie_pairs.contains_key(&attached_to.0) {
-            bogie_pairs.insert(attached_to.0.clone(), Vec::new());
+    bogie_counts.0.clear();
+    for (attached_to, shared_with) in &bogies_query {
+        for wagon in std::iter::once(attached_to.0).chain(shared_with.map(|shared_with| shared_with.0)) {
+            *bogie_counts.0.entry(wagon).or_insert(0) += 1;
         }
-        bogie_pairs.get_mut(&attached_to.0).unwrap().push((bogie, bogie_transform));
     }
+}
 
-    for (wagon, bogies) in &mut bogie_pairs {
-        let wagon = wagons_query.get(wagon.clone()).unwrap();
-        let distance_between_bogies = wagon.distance_between_bogies;
-
-        let mut leading_bogie = None;
-        let mut trailing_bogie = None;
-        for bogie in bogies {
-            if bogie.0.is_leading.unwrap() {
-                leading_bogie = Some(bogie);
-            } else {
-                trailing_bogie = Some(bogie);
-            }
+/// Places each wagon between its foremost and rearmost bogie, including the articulation bogies it shares.
+pub(crate) fn sync_wagons_with_bogies(
+    bogies_query: Query<(&Bogie, &Transform, &AttachedToWagon, Option<&SharedWithWagon>), (Without<Wagon>, Without<Derailed>)>,
+    mut wagons_query: Query<(&Wagon, &mut Transform), (Without<Bogie>, Without<Derailed>)>,
+) {
+    let mut wagon_bogies = HashMap::<Entity, Vec<(&Bogie, &Transform)>>::new();
+    for (bogie, bogie_transform, attached_to, shared_with) in &bogies_query {
+        for wagon in std::iter::once(attached_to.0).chain(shared_with.map(|shared_with| shared_with.0)) {
+            wagon_bogies.entry(wagon).or_default().push((bogie, bogie_transform));
         }
+    }
 
-        let (_, leading_transform) = leading_bogie.unwrap();
-        let (trailing_bogie, trailing_transform) = trailing_bogie.unwrap();
+    for (wagon, bogies) in &wagon_bogies {
+        let Ok((wagon_component, mut wagon_transform)) = wagons_query.get_mut(*wagon) else {
+            continue;
+        };
 
-        let current_distance = leading_transform.translation.distance(trailing_transform.translation);
-        if current_distance < distance_between_bogies {
-            trailing_bogie.position_on_track -= 0.001 * (current_distance - distance_between_bogies).abs();
-            //println!("current_distance < distance_between_bogies; curr dist: {}, trailing t: {}", current_distance, trailing_bogie.position_on_track);
-        } else {
-            trailing_bogie.position_on_track += 0.001 * (current_distance - distance_between_bogies).abs();
-            //println!("current_distance > distance_between_bogies; curr dist: {}, trailing t: {}", current_distance, trailing_bogie.position_on_track);
+        let by_t = |(a, _): &&(&Bogie, &Transform), (b, _): &&(&Bogie, &Transform)| a.position_on_track.total_cmp(&b.position_on_track);
+        let (Some((_, leading_transform)), Some((_, trailing_transform))) = (bogies.iter().max_by(by_t), bogies.iter().min_by(by_t)) else {
+            continue;
+        };
+        if bogies.len() < 2 {
+            warn!("Unable to update transform for wagon {:?}: it is carried by a single bogie.", wagon);
+            continue;
         }
+
+        wagon_transform.translation = trailing_transform.translation + (leading_transform.translation - trailing_transform.translation) / 2.;
+        wagon_transform.look_at(leading_transform.translation, Vec3::Y);
+        wagon_transform.translation.y += wagon_component.body_offset;
     }
 }
@@ -0,0 +1,434 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use bevy::asset::ron;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::{NoiseSettings, Player};
+use crate::assets::{AssetLoadingState, DefinitionAssets, ModelAssets};
+use crate::config::WorldConfig;
+use crate::rolling_stock::components::{AirBrake, AttachedToWagon, Bogie, BodyOffset, BogieDistanceConstraint, BogiePhysics, Cargo, Coupling, Derailed, DerailmentCause, DriversBrakeValve, FreeBody, Locomotive, TrackedWagon, Wagon, WagonNumber, WagonPhysics, Wheels};
+use crate::rolling_stock::locomotive_systems::RailCondition;
+use crate::rolling_stock::stock_definitions::RollingStockSet;
+use crate::rolling_stock::wagon_systems::{spawn_train, spawn_wagon, PhysicsTick};
+use crate::world::WorldSettings;
+use crate::world::route_gen::Route;
+use crate::world::terrain::{Terrain, TerrainHole};
+use crate::world::train_tracks::{spawn_track_entities, Crossover, PlacementData, Track};
+
+/// The version of the save format. Saves with another version are rejected.
+pub const SAVE_VERSION: u32 = 1;
+/// The directory the saves made from the window are written to.
+const SAVE_DIRECTORY: &str = "saves";
+const SAVE_KEY: KeyCode = KeyCode::F5;
+
+/// Saves the world with F5 and restores the world from a save when the assets are loaded.
+pub struct SavePlugin {
+    /// Only restore, without saving from the keyboard (no window needed).
+    pub headless: bool,
+    /// The save to restore the world from, instead of spawning the configured train.
+    pub restore_from: Option<String>,
+}
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        if let Some(path) = &self.restore_from {
+            match WorldSave::load(path) {
+                Ok(save) => { app.insert_resource(PendingRestore(save)); },
+                Err(error) => warn!("Not restoring the world: {}", error),
+            }
+        }
+
+        app.add_systems(OnEnter(AssetLoadingState::AssetsLoaded),
+                        restore_world
+                            .after(spawn_track_entities)
+                            .after(spawn_train)
+                            .run_if(resource_exists::<PendingRestore>))
+            // Restores requested later on, e.g. when rewinding a replay
+            .add_systems(Update,
+                         restore_world
+                             .run_if(in_state(AssetLoadingState::AssetsLoaded))
+                             .run_if(resource_exists::<PendingRestore>));
+
+        if self.headless {
+            return;
+        }
+
+        app.add_systems(Update, save_on_key.run_if(in_state(AssetLoadingState::AssetsLoaded)));
+    }
+}
+
+/// The save the world is restored from once the assets are loaded. The physics waits until it is restored.
+#[derive(Resource)]
+pub(crate) struct PendingRestore(pub(crate) WorldSave);
+
+/// The state of the world and of the simulation: the config it was generated with, the route, the tracks,
+/// the terrain modifications and every vehicle.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WorldSave {
+    pub version: u32,
+    pub config: WorldConfig,
+    /// The number of physics steps simulated before the save.
+    #[serde(default)]
+    pub tick: u64,
+    /// The id of the first node in `route_points`. The nodes before it had been evicted.
+    #[serde(default)]
+    pub route_first_id: usize,
+    pub route_points: Vec<Vec3>,
+    /// Whether the route node with the same index is placed inside a tunnel.
+    pub tunnel_nodes: Vec<bool>,
+    /// The id of the last track segment built from the route.
+    pub last_segment_id: usize,
+    pub terrain_holes: Vec<TerrainHole>,
+    pub crossovers: Vec<CrossoverSave>,
+    pub(crate) rail_condition: RailCondition,
+    pub player_transform: Option<(Vec3, Quat)>,
+    /// In the order the wagons were spawned.
+    pub wagons: Vec<WagonSave>,
+    pub couplings: Vec<CouplingSave>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CrossoverSave {
+    pub segment_id: usize,
+    /// The indices of the tracks the crossover connects.
+    pub tracks: [usize; 2],
+    pub diverging: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WagonSave {
+    pub definition_id: String,
+    pub tracked: bool,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub physics: WagonPhysics,
+    pub air_brake: AirBrake,
+    pub cargo: Option<Cargo>,
+    pub locomotive: Option<Locomotive>,
+    pub brake_valve: Option<DriversBrakeValve>,
+    pub derailed: Option<DerailmentCause>,
+    pub free_body: Option<FreeBody>,
+    /// From the leading bogie to the trailing one.
+    pub bogies: Vec<BogieSave>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BogieSave {
+    /// The index of the track the bogie is on, `None` if derailed.
+    pub track: Option<usize>,
+    pub position_on_track: f32,
+    pub previous_position_on_track: f32,
+    /// Whether the bogie runs on the diverging rails of the crossover in its segment.
+    #[serde(default)]
+    pub on_crossover: bool,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub physics: BogiePhysics,
+    pub wheels: Wheels,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CouplingSave {
+    /// The index of the wagon ahead in `WorldSave::wagons`.
+    pub front_wagon: usize,
+    pub rear_wagon: usize,
+    pub force: f32,
+}
+
+/// Only the version of a save, read first so that saves in another format are rejected with a clear error.
+#[derive(Deserialize)]
+struct SaveVersion {
+    version: u32,
+}
+
+impl WorldSave {
+    /// Reads a save from a RON file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+        let version: SaveVersion = ron::from_str(&contents).map_err(|error| format!("could not parse {}: {}", path, error))?;
+        if version.version != SAVE_VERSION {
+            return Err(format!("{} is a version {} save, only version {} is supported", path, version.version, SAVE_VERSION));
+        }
+
+        let save: WorldSave = ron::from_str(&contents).map_err(|error| format!("could not parse {}: {}", path, error))?;
+        if save.route_points.len() < 2 || save.tunnel_nodes.len() != save.route_points.len() {
+            return Err(format!("{} has an invalid route", path));
+        }
+
+        Ok(save)
+    }
+
+    /// Writes the save to a RON file, creating the directory if needed.
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|error| format!("could not serialize the save: {}", error))?;
+        if let Some(directory) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(directory).map_err(|error| format!("could not create {}: {}", directory.display(), error))?;
+        }
+
+        std::fs::write(path, contents).map_err(|error| format!("could not write {}: {}", path, error))
+    }
+
+    /// Captures the current state of the world.
+    pub fn capture(world: &mut World) -> Self {
+        // The noise and the world settings in use take precedence over the config the app was started with
+        let mut config = world.get_resource::<WorldConfig>().cloned().unwrap_or_default();
+        config.noise = *world.resource::<NoiseSettings>();
+        config.world = world.resource::<WorldSettings>().clone();
+        config.restore_from = None;
+        config.save_to = None;
+        config.record_to = None;
+        config.replay_from = None;
+        config.telemetry_to = None;
+
+        let route = world.resource::<Route>();
+        let (route_first_id, route_points, tunnel_nodes) = (route.first_id(), route.points().to_vec(), route.tunnel_nodes().to_vec());
+        let last_segment_id = world.resource::<PlacementData>().current_segment_id();
+        let terrain_holes = world.resource::<Terrain>().holes().to_vec();
+        let rail_condition = *world.resource::<RailCondition>();
+        let tick = world.resource::<PhysicsTick>().0;
+
+        let track_indices: HashMap<Entity, usize> = world.query::<(Entity, &Track)>().iter(world)
+            .map(|(entity, track)| (entity, track.index()))
+            .collect();
+        let crossovers = world.query::<&Crossover>().iter(world)
+            .filter_map(|crossover| Some(CrossoverSave {
+                segment_id: crossover.segment_id,
+                tracks: [*track_indices.get(&crossover.tracks[0])?, *track_indices.get(&crossover.tracks[1])?],
+                diverging: crossover.diverging,
+            }))
+            .collect();
+
+        let player_transform = world.query_filtered::<&Transform, With<Player>>().get_single(world).ok()
+            .map(|transform| (transform.translation, transform.rotation));
+
+        let mut bogies: Vec<(Entity, Option<bool>, BogieSave)> = world.query::<(&Bogie, &BogiePhysics, &Wheels, &Transform, &AttachedToWagon)>()
+            .iter(world)
+            .map(|(bogie, physics, wheels, transform, attached_to)| (attached_to.0, bogie.is_leading, BogieSave {
+                track: bogie.current_track.and_then(|entity| track_indices.get(&entity).copied()),
+                position_on_track: bogie.position_on_track,
+                previous_position_on_track: bogie.previous_position_on_track,
+                on_crossover: bogie.on_crossover,
+                translation: transform.translation,
+                rotation: transform.rotation,
+                physics: physics.clone(),
+                wheels: wheels.clone(),
+            }))
+            .collect();
+        bogies.sort_by(|(_, a_leading, a), (_, b_leading, b)| {
+            b_leading.unwrap_or(false).cmp(&a_leading.unwrap_or(false))
+                .then(b.position_on_track.total_cmp(&a.position_on_track))
+        });
+
+        let mut wagon_query = world.query::<(Entity, &Wagon, &WagonPhysics, &Transform, &AirBrake, Option<&Cargo>, Option<&Locomotive>, Option<&DriversBrakeValve>, Option<&Derailed>, Option<&FreeBody>, Has<TrackedWagon>)>();
+        let wagon_entities = get_wagons_in_spawn_order(world);
+        let mut wagons = Vec::new();
+        for entity in &wagon_entities {
+            let (_, wagon, physics, transform, air_brake, cargo, locomotive, brake_valve, derailed, free_body, tracked) = wagon_query.get(world, *entity).unwrap();
+            let wagon_bogies = bogies.iter()
+                .filter(|(wagon_entity, ..)| wagon_entity == entity)
+                .map(|(_, _, bogie)| bogie.clone())
+                .collect();
+            wagons.push(WagonSave {
+                definition_id: wagon.definition_id.clone(),
+                tracked,
+                translation: transform.translation,
+                rotation: transform.rotation,
+                physics: physics.clone(),
+                air_brake: air_brake.clone(),
+                cargo: cargo.cloned(),
+                locomotive: locomotive.cloned(),
+                brake_valve: brake_valve.cloned(),
+                derailed: derailed.map(|derailed| derailed.cause),
+                free_body: free_body.cloned(),
+                bogies: wagon_bogies,
+            });
+        }
+
+        let wagon_index = |entity: Entity| wagon_entities.iter().position(|wagon| *wagon == entity);
+        let couplings = world.query::<&Coupling>().iter(world)
+            .filter_map(|coupling| Some(CouplingSave {
+                front_wagon: wagon_index(coupling.front_wagon)?,
+                rear_wagon: wagon_index(coupling.rear_wagon)?,
+                force: coupling.force,
+            }))
+            .collect();
+
+        WorldSave {
+            version: SAVE_VERSION,
+            config,
+            tick,
+            route_first_id,
+            route_points,
+            tunnel_nodes,
+            last_segment_id,
+            terrain_holes,
+            crossovers,
+            rail_condition,
+            player_transform,
+            wagons,
+            couplings,
+        }
+    }
+}
+
+/// Returns the wagons in the order they were spawned (which is also the order of the wagons in a save).
+pub(crate) fn get_wagons_in_spawn_order(world: &mut World) -> Vec<Entity> {
+    let mut wagons: Vec<(WagonNumber, Entity)> = world.query_filtered::<(&WagonNumber, Entity), With<Wagon>>().iter(world)
+        .map(|(number, entity)| (*number, entity))
+        .collect();
+    wagons.sort();
+    wagons.into_iter().map(|(_, entity)| entity).collect()
+}
+
+/// Returns the path of a new save in the save directory.
+fn get_save_path(seed: u32) -> String {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default();
+    format!("{}/{}-{}.save.ron", SAVE_DIRECTORY, seed, timestamp)
+}
+
+fn save_on_key(world: &mut World) {
+    if !world.get_resource::<ButtonInput<KeyCode>>().is_some_and(|input| input.just_pressed(SAVE_KEY)) {
+        return;
+    }
+
+    let save = WorldSave::capture(world);
+    let path = get_save_path(save.config.noise.seed);
+    match save.write(&path) {
+        Ok(()) => info!("Saved the world to {}", path),
+        Err(error) => warn!("Could not save the world: {}", error),
+    }
+}
+
+/// Rebuilds the route, the tracks and the terrain modifications from the pending save,
+/// and replaces the vehicles with the saved ones.
+fn restore_world(
+    mut commands: Commands,
+    pending_restore: Res<PendingRestore>,
+    mut route: ResMut<Route>,
+    mut placement_data: ResMut<PlacementData>,
+    mut terrain: ResMut<Terrain>,
+    mut rail_condition: ResMut<RailCondition>,
+    mut physics_tick: ResMut<PhysicsTick>,
+    track_query: Query<(Entity, &Track)>,
+    mut crossover_query: Query<&mut Crossover>,
+    vehicle_query: Query<Entity, Or<(With<Wagon>, With<Bogie>, With<BogieDistanceConstraint>, With<Coupling>)>>,
+    mut player_query: Query<&mut Transform, With<Player>>,
+    asset_server: Res<AssetServer>,
+    model_assets: Option<Res<ModelAssets>>,
+    definition_assets: Res<DefinitionAssets>,
+    rolling_stock_sets: Res<Assets<RollingStockSet>>,
+) {
+    let save = &pending_restore.0;
+    commands.remove_resource::<PendingRestore>();
+
+    // A world generated with the same config already contains the saved route (e.g. when rewinding a replay),
+    // only the vehicles and the switches are restored then
+    let keeps_route = route.contains(save.route_first_id, &save.route_points);
+    if !keeps_route {
+        route.restore(save.route_first_id, save.route_points.clone(), save.tunnel_nodes.clone());
+        placement_data.restore(save.last_segment_id.min(save.route_first_id + save.route_points.len() - 1));
+        for hole in &save.terrain_holes {
+            terrain.add_hole(*hole);
+        }
+        if let (Some((translation, rotation)), Ok(mut player_transform)) = (save.player_transform, player_query.get_single_mut()) {
+            player_transform.translation = translation;
+            player_transform.rotation = rotation;
+        }
+    }
+    *rail_condition = save.rail_condition;
+    physics_tick.0 = save.tick;
+    for entity in &vehicle_query {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let find_track = |index: usize| track_query.iter().find(|(_, track)| track.index() == index).map(|(entity, _)| entity);
+    // The crossovers laid after the save have their switches in the default position
+    for mut crossover in &mut crossover_query {
+        crossover.diverging = save.crossovers.iter()
+            .any(|saved| saved.segment_id == crossover.segment_id && saved.diverging);
+    }
+    for crossover in &save.crossovers {
+        if keeps_route && crossover_query.iter().any(|existing| existing.segment_id == crossover.segment_id) {
+            continue;
+        }
+        if let (Some(first_track), Some(second_track)) = (find_track(crossover.tracks[0]), find_track(crossover.tracks[1])) {
+            commands.spawn(Crossover {
+                segment_id: crossover.segment_id,
+                tracks: [first_track, second_track],
+                diverging: crossover.diverging,
+            });
+        }
+    }
+
+    let Some(rolling_stock) = rolling_stock_sets.get(&definition_assets.rolling_stock) else {
+        warn!("Rolling stock definitions are not loaded, not restoring the vehicles.");
+        return;
+    };
+    let mut wagons = Vec::new();
+    for wagon_save in &save.wagons {
+        let Some(definition) = rolling_stock.definitions.get(&wagon_save.definition_id) else {
+            warn!("Unknown rolling stock {:?} in the save, skipping it.", wagon_save.definition_id);
+            wagons.push(None);
+            continue;
+        };
+        // The leading and the trailing bogie are spawned at the first and the last saved bogie
+        let end_bogies = [wagon_save.bogies.first(), wagon_save.bogies.last()];
+        let bogie_t = |bogie: Option<&BogieSave>| bogie.map(|bogie| bogie.position_on_track).unwrap_or_default();
+        let spawned = spawn_wagon(&mut commands, &asset_server, model_assets.as_deref(), &wagon_save.definition_id, definition, bogie_t(end_bogies[0]), bogie_t(end_bogies[1]));
+        wagons.push(Some(spawned.wagon));
+
+        let mut wagon_commands = commands.entity(spawned.wagon);
+        wagon_commands.insert((
+            Transform::from_translation(wagon_save.translation).with_rotation(wagon_save.rotation),
+            wagon_save.physics.clone(),
+            wagon_save.air_brake.clone(),
+        ));
+        if let Some(cargo) = &wagon_save.cargo {
+            wagon_commands.insert(cargo.clone());
+        }
+        if let Some(locomotive) = &wagon_save.locomotive {
+            wagon_commands.insert(locomotive.clone());
+        }
+        if let Some(brake_valve) = &wagon_save.brake_valve {
+            wagon_commands.insert(brake_valve.clone());
+        }
+        if wagon_save.tracked {
+            wagon_commands.insert(TrackedWagon);
+        }
+        if let Some(cause) = wagon_save.derailed {
+            wagon_commands.insert((Derailed { cause }, wagon_save.free_body.clone().unwrap_or_default()));
+            commands.entity(spawned.constraint).despawn();
+        }
+
+        for (entity, bogie_save) in spawned.bogies.iter().zip(end_bogies).filter_map(|(entity, bogie_save)| Some((entity, bogie_save?))) {
+            let mut bogie_commands = commands.entity(*entity);
+            let bogie_transform = Transform::from_translation(bogie_save.translation).with_rotation(bogie_save.rotation);
+            bogie_commands.insert((
+                bogie_transform,
+                bogie_save.physics.clone(),
+                bogie_save.wheels.clone(),
+            ));
+            if let Some(cause) = wagon_save.derailed {
+                let wagon_transform = Transform::from_translation(wagon_save.translation).with_rotation(wagon_save.rotation);
+                bogie_commands.insert((Derailed { cause }, BodyOffset::between(&wagon_transform, &bogie_transform)));
+            }
+
+            // Bogies on a track that no longer exists are put on the first track by `assign_bogie_tracks`
+            let is_leading = *entity == spawned.bogies[0];
+            bogie_commands.insert(Bogie {
+                is_leading: Some(is_leading),
+                current_track: bogie_save.track.and_then(find_track),
+                position_on_track: bogie_save.position_on_track,
+                previous_position_on_track: bogie_save.previous_position_on_track,
+                on_crossover: bogie_save.on_crossover,
+            });
+        }
+    }
+
+    for coupling in &save.couplings {
+        if let (Some(Some(front_wagon)), Some(Some(rear_wagon))) = (wagons.get(coupling.front_wagon), wagons.get(coupling.rear_wagon)) {
+            commands.spawn(Coupling { front_wagon: *front_wagon, rear_wagon: *rear_wagon, force: coupling.force });
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use crate::{noise, NoiseSettings, PHYSICS_TIMESTEP};
+use crate::rolling_stock::WagonPhysicsSet;
+use crate::rolling_stock::components::{AttachedToWagon, Bogie, BogiePhysics, Coupling, Derailed, Wagon, WagonNumber, WagonPhysics};
+use crate::rolling_stock::wagon_systems::{count_physics_ticks, PhysicsTick};
+use crate::world::train_tracks::Track;
+
+const CSV_HEADER: &str = "time_s,tick,wagon,definition_id,track_t,elevation_m,grade,curvature_1_per_m,velocity_m_s,acceleration_m_s2,\
+tractive_force_n,braking_force_n,horizontal_force_n,vertical_force_n,kinetic_force_n,static_force_n,bogie_coupler_force_n,\
+front_coupling_force_n,rear_coupling_force_n,traction_energy_kwh,derailed";
+const JOULES_PER_KWH: f32 = 3.6e6;
+
+/// Samples the dynamics of every vehicle at a fixed rate and writes them to a CSV file, one row per vehicle and sample.
+/// The forces of the bogies are summed per vehicle.
+pub struct TelemetryPlugin {
+    /// The CSV file the telemetry is written to. Nothing is recorded if `None`.
+    pub path: Option<String>,
+    /// The number of samples per simulated second.
+    pub rate: f32,
+}
+
+impl Plugin for TelemetryPlugin {
+    fn build(&self, app: &mut App) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let writer = match Telemetry::create_writer(path) {
+            Ok(writer) => writer,
+            Err(error) => {
+                warn!("Not recording telemetry: {}", error);
+                return;
+            },
+        };
+
+        app
+            .insert_resource(Telemetry {
+                writer: Some(writer),
+                sample_interval: (1. / (self.rate * PHYSICS_TIMESTEP)).round().max(1.) as u64,
+                traction_energy: HashMap::new(),
+                sampled_velocities: HashMap::new(),
+            })
+            .add_systems(FixedUpdate,
+                         (accumulate_traction_energy, sample_telemetry)
+                             .chain()
+                             .in_set(WagonPhysicsSet::ApplyForces)
+                             .after(count_physics_ticks));
+    }
+}
+
+#[derive(Resource)]
+struct Telemetry {
+    /// Dropped after a write error.
+    writer: Option<BufWriter<File>>,
+    /// The number of physics steps between the samples.
+    sample_interval: u64,
+    /// The work done by the tractive force of each vehicle in J.
+    traction_energy: HashMap<Entity, f32>,
+    /// The velocity of each vehicle at the last sample, to derive the acceleration.
+    sampled_velocities: HashMap<Entity, f32>,
+}
+
+impl Telemetry {
+    fn create_writer(path: &str) -> Result<BufWriter<File>, String> {
+        if let Some(directory) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(directory).map_err(|error| format!("could not create {}: {}", directory.display(), error))?;
+        }
+        let mut writer = BufWriter::new(File::create(path).map_err(|error| format!("could not create {}: {}", path, error))?);
+        writeln!(writer, "{}", CSV_HEADER).map_err(|error| format!("could not write {}: {}", path, error))?;
+
+        Ok(writer)
+    }
+}
+
+fn accumulate_traction_energy(
+    mut telemetry: ResMut<Telemetry>,
+    wagon_query: Query<(Entity, &WagonPhysics)>,
+) {
+    for (entity, wagon_physics) in &wagon_query {
+        // Only the power delivered to the train counts, not the power of a locomotive working against its movement
+        let power = (wagon_physics.tractive_force * wagon_physics.velocity).max(0.);
+        *telemetry.traction_energy.entry(entity).or_default() += power * PHYSICS_TIMESTEP;
+    }
+}
+
+fn sample_telemetry(
+    mut telemetry: ResMut<Telemetry>,
+    tick: Res<PhysicsTick>,
+    noise_settings: Res<NoiseSettings>,
+    wagon_query: Query<(Entity, &Wagon, &WagonNumber, &WagonPhysics, Has<Derailed>)>,
+    bogie_query: Query<(&Bogie, &BogiePhysics, &AttachedToWagon)>,
+    track_query: Query<&Track>,
+    coupling_query: Query<&Coupling>,
+) {
+    if tick.0 % telemetry.sample_interval != 0 || telemetry.writer.is_none() {
+        return;
+    }
+
+    let height_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+    let sample_time = telemetry.sample_interval as f32 * PHYSICS_TIMESTEP;
+    let mut wagons: Vec<_> = wagon_query.iter().collect();
+    wagons.sort_by_key(|(_, _, number, ..)| **number);
+
+    let mut rows = String::new();
+    for (entity, wagon, number, wagon_physics, is_derailed) in wagons {
+        let bogies: Vec<_> = bogie_query.iter()
+            .filter(|(_, _, attached_to)| attached_to.0 == entity)
+            .collect();
+        let sum = |force: fn(&BogiePhysics) -> f32| bogies.iter().map(|(_, physics, _)| force(physics)).sum::<f32>();
+        let mean = |values: Vec<f32>| (!values.is_empty()).then(|| values.iter().sum::<f32>() / values.len() as f32);
+
+        let leading_bogie = bogies.iter()
+            .find(|(bogie, ..)| bogie.is_leading == Some(true))
+            .map(|(bogie, ..)| *bogie);
+        let elevation = leading_bogie
+            .and_then(|bogie| Some((track_query.get(bogie.current_track?).ok()?, bogie.position_on_track)))
+            .and_then(|(track, t)| track.get_interpolated_position_at_t(t, &height_fn))
+            .map(|(position, _)| position.y);
+        let grade = mean(bogies.iter().filter_map(|(_, physics, _)| physics.current_slope_angle.map(f32::tan)).collect());
+        let curvature = mean(bogies.iter().filter_map(|(_, physics, _)| physics.current_curve_radius.map(|radius| 1. / radius)).collect());
+        let acceleration = telemetry.sampled_velocities.insert(entity, wagon_physics.velocity)
+            .map(|previous_velocity| (wagon_physics.velocity - previous_velocity) / sample_time)
+            .unwrap_or_default();
+        let coupling_force = |is_front: bool| coupling_query.iter()
+            .find(|coupling| if is_front { coupling.rear_wagon == entity } else { coupling.front_wagon == entity })
+            .map(|coupling| coupling.force);
+        let traction_energy = telemetry.traction_energy.get(&entity).copied().unwrap_or_default() / JOULES_PER_KWH;
+
+        let optional = |value: Option<f32>| value.map(|value| value.to_string()).unwrap_or_default();
+        rows += &format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            tick.0 as f32 * PHYSICS_TIMESTEP,
+            tick.0,
+            number.0,
+            wagon.definition_id,
+            optional(leading_bogie.map(|bogie| bogie.position_on_track)),
+            optional(elevation),
+            optional(grade),
+            optional(curvature),
+            wagon_physics.velocity,
+            acceleration,
+            wagon_physics.tractive_force,
+            wagon_physics.braking_force,
+            sum(|physics| physics.horizontal_force),
+            sum(|physics| physics.vertical_force),
+            sum(|physics| physics.kinetic_force),
+            sum(|physics| physics.static_force),
+            sum(|physics| physics.coupler_force),
+            optional(coupling_force(true)),
+            optional(coupling_force(false)),
+            traction_energy,
+            is_derailed,
+        );
+    }
+
+    // Flushed on every sample, so that the file is complete up to the last sample if the app is closed
+    let result = telemetry.writer.as_mut()
+        .map(|writer| writer.write_all(rows.as_bytes()).and_then(|_| writer.flush()));
+    if let Some(Err(error)) = result {
+        warn!("Stopped recording telemetry: {}", error);
+        telemetry.writer = None;
+    }
+}
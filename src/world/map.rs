@@ -0,0 +1,227 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use bevy_egui::egui::emath;
+use crate::{noise, NoiseSettings, Player};
+use crate::camera::{CameraMode, CameraRig};
+use crate::rolling_stock::components::{TrackedWagon, Wagon};
+use crate::world::WorldSettings;
+use crate::world::route_gen::Route;
+use crate::world::terrain::{get_far_chunk_position, Terrain};
+
+const MAP_KEY: KeyCode = KeyCode::KeyM;
+/// The size of the minimap on the screen in px, and half the width of the area it shows in m.
+const MINIMAP_SIZE: f32 = 220.;
+const MINIMAP_EXTENT: f32 = 600.;
+/// The number of pixels per side of the rendered map images.
+const MINIMAP_RESOLUTION: usize = 128;
+const WORLD_MAP_RESOLUTION: usize = 320;
+/// The fraction of the extent the player can move before the minimap is rendered again.
+const MINIMAP_RERENDER_FRACTION: f32 = 0.1;
+/// The height above the terrain the camera is placed at when the map is clicked in m.
+const TELEPORT_HEIGHT: f32 = 40.;
+/// The direction the hillshading is lit from (x, z and up), from the north-west.
+const LIGHT_DIRECTION: Vec3 = Vec3::new(-1., 1., -1.);
+const LOW_COLOR: Vec3 = Vec3::new(0.35, 0.55, 0.25);
+const HIGH_COLOR: Vec3 = Vec3::new(0.6, 0.55, 0.45);
+const WATER_COLOR: Vec3 = Vec3::new(0.15, 0.3, 0.6);
+/// The height difference above the water level over which the terrain color changes from low to high in m.
+const COLOR_HEIGHT_RANGE: f32 = 80.;
+const ROUTE_COLOR: egui::Color32 = egui::Color32::from_rgb(40, 40, 40);
+const CHUNK_COLOR: egui::Color32 = egui::Color32::from_rgba_premultiplied(200, 200, 200, 100);
+const WAGON_COLOR: egui::Color32 = egui::Color32::from_rgb(230, 60, 60);
+const TRACKED_WAGON_COLOR: egui::Color32 = egui::Color32::from_rgb(250, 220, 60);
+const PLAYER_COLOR: egui::Color32 = egui::Color32::WHITE;
+
+/// A top-down image of the terrain around a point, rendered from the heightmap.
+#[derive(Default)]
+struct MapImage {
+    texture: Option<egui::TextureHandle>,
+    /// The world position (x, z) at the center of the image.
+    center: Vec2,
+    /// Half the width of the area shown in m.
+    extent: f32,
+}
+
+impl MapImage {
+    /// Renders the terrain with hillshading, and the water below the water level.
+    fn render(&mut self, ctx: &egui::Context, name: &str, center: Vec2, extent: f32, resolution: usize, noise_settings: NoiseSettings, water_level: f32) {
+        let height_fn = noise::get_heightmap_function(noise_settings, Vec3::ZERO);
+        let pixel_size = 2. * extent / resolution as f32;
+        // One pixel of margin on every side for the gradients of the hillshading
+        let heights: Vec<f32> = (0..resolution + 2)
+            .flat_map(|row| (0..resolution + 2).map(move |column| (row, column)))
+            .map(|(row, column)| {
+                let x = center.x - extent + (column as f32 - 0.5) * pixel_size;
+                let z = center.y - extent + (row as f32 - 0.5) * pixel_size;
+                height_fn(x as f64, z as f64) as f32
+            })
+            .collect();
+        let height_at = |row: usize, column: usize| heights[row * (resolution + 2) + column];
+
+        let light = LIGHT_DIRECTION.normalize();
+        let pixels = (1..=resolution)
+            .flat_map(|row| (1..=resolution).map(move |column| (row, column)))
+            .map(|(row, column)| {
+                let height = height_at(row, column);
+                let color = if height < water_level {
+                    WATER_COLOR
+                } else {
+                    let gradient = Vec2::new(
+                        height_at(row, column + 1) - height_at(row, column - 1),
+                        height_at(row + 1, column) - height_at(row - 1, column),
+                    ) / (2. * pixel_size);
+                    let normal = Vec3::new(-gradient.x, 1., -gradient.y).normalize();
+                    let shade = normal.dot(light).clamp(0., 1.);
+                    let base = LOW_COLOR.lerp(HIGH_COLOR, ((height - water_level) / COLOR_HEIGHT_RANGE).clamp(0., 1.));
+                    base * (0.4 + 0.6 * shade)
+                };
+                egui::Color32::from_rgb((color.x * 255.) as u8, (color.y * 255.) as u8, (color.z * 255.) as u8)
+            })
+            .collect();
+
+        let image = egui::ColorImage { size: [resolution, resolution], pixels };
+        match &mut self.texture {
+            Some(texture) => texture.set(image, egui::TextureOptions::LINEAR),
+            None => self.texture = Some(ctx.load_texture(name, image, egui::TextureOptions::LINEAR)),
+        }
+        self.center = center;
+        self.extent = extent;
+    }
+}
+
+/// The state of the minimap and of the world map.
+#[derive(Resource, Default)]
+pub(crate) struct MapView {
+    minimap: MapImage,
+    world_map: MapImage,
+    world_map_open: bool,
+    needs_rerender: bool,
+}
+
+/// The overlays drawn on top of the map images.
+struct MapOverlay<'a> {
+    route: &'a [Vec3],
+    /// The world rects (x, z) of the loaded far chunks.
+    chunks: Vec<egui::Rect>,
+    /// The positions of the wagons and whether they are tracked.
+    wagons: Vec<(Vec2, bool)>,
+    player: Option<(Vec2, Vec2)>,
+}
+
+/// Shows the minimap around the camera, and the world map of the loaded area when toggled with M.
+/// Clicking either moves the camera to the clicked point.
+pub(crate) fn map_ui(
+    mut egui_contexts: EguiContexts,
+    mut map_view: ResMut<MapView>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    noise_settings: Res<NoiseSettings>,
+    settings: Res<WorldSettings>,
+    route: Res<Route>,
+    terrain: Res<Terrain>,
+    wagon_query: Query<(&GlobalTransform, Has<TrackedWagon>), With<Wagon>>,
+    mut player_query: Query<&mut Transform, With<Player>>,
+    camera_rig: Option<ResMut<CameraRig>>,
+) {
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
+    let player_position = player_transform.translation.xz();
+    let ctx = egui_contexts.ctx_mut().clone();
+    let map_view = &mut *map_view;
+    map_view.needs_rerender |= noise_settings.is_changed();
+    if keyboard_input.just_pressed(MAP_KEY) {
+        map_view.world_map_open = !map_view.world_map_open;
+    }
+
+    let chunk_size = settings.far_chunk_size as f32;
+    let overlay = MapOverlay {
+        route: route.points(),
+        chunks: terrain.loaded_chunks.values()
+            .map(|chunk| {
+                let min = chunk.pos * chunk_size - Vec2::splat(chunk_size / 2.);
+                egui::Rect::from_min_size(egui::pos2(min.x, min.y), egui::vec2(chunk_size, chunk_size))
+            })
+            .collect(),
+        wagons: wagon_query.iter().map(|(transform, tracked)| (transform.translation().xz(), tracked)).collect(),
+        player: Some((player_position, (player_transform.forward().xz()).normalize_or_zero())),
+    };
+
+    let minimap = &mut map_view.minimap;
+    if map_view.needs_rerender || minimap.texture.is_none() || minimap.center.distance(player_position) > MINIMAP_EXTENT * MINIMAP_RERENDER_FRACTION {
+        minimap.render(&ctx, "minimap", player_position, MINIMAP_EXTENT, MINIMAP_RESOLUTION, *noise_settings, settings.water_level);
+    }
+    let mut clicked = None;
+    egui::Area::new(egui::Id::new("minimap"))
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(10., -10.))
+        .show(&ctx, |ui| {
+            clicked = clicked.or(draw_map(ui, &map_view.minimap, &overlay, MINIMAP_SIZE));
+        });
+
+    if map_view.world_map_open {
+        // Centered on the far chunk of the player and covering the render distance, so that it is only rendered again when entering another chunk
+        let center_chunk = get_far_chunk_position(&settings, player_position);
+        let center = center_chunk.as_vec2() * chunk_size;
+        let extent = (settings.far_render_distance as f32 + 0.5) * chunk_size;
+        let world_map = &mut map_view.world_map;
+        if map_view.needs_rerender || world_map.texture.is_none() || world_map.center != center || world_map.extent != extent {
+            world_map.render(&ctx, "world map", center, extent, WORLD_MAP_RESOLUTION, *noise_settings, settings.water_level);
+        }
+
+        let screen = ctx.screen_rect();
+        let size = screen.width().min(screen.height()) * 0.9;
+        egui::Area::new(egui::Id::new("world map"))
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(&ctx, |ui| {
+                clicked = clicked.or(draw_map(ui, &map_view.world_map, &overlay, size));
+            });
+    }
+    map_view.needs_rerender = false;
+
+    if let (Some(position), Ok(mut player_transform)) = (clicked, player_query.get_single_mut()) {
+        // The camera rig would move the camera back to the train
+        if let Some(mut camera_rig) = camera_rig {
+            camera_rig.mode = CameraMode::Free;
+        }
+        let height_fn = noise::get_heightmap_function(*noise_settings, Vec3::ZERO);
+        let height = (height_fn(position.x as f64, position.y as f64) as f32).max(settings.water_level);
+        player_transform.translation = Vec3::new(position.x, height + TELEPORT_HEIGHT, position.y);
+    }
+}
+
+/// Draws the map image with the overlays, returning the world position (x, z) that was clicked.
+fn draw_map(ui: &mut egui::Ui, image: &MapImage, overlay: &MapOverlay, size: f32) -> Option<Vec2> {
+    let texture = image.texture.as_ref()?;
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(size, size), egui::Sense::click());
+    let painter = ui.painter_at(rect);
+    painter.image(texture.id(), rect, egui::Rect::from_min_max(egui::pos2(0., 0.), egui::pos2(1., 1.)), egui::Color32::WHITE);
+
+    let world_min = image.center - Vec2::splat(image.extent);
+    let world_max = image.center + Vec2::splat(image.extent);
+    let to_screen = |position: Vec2| egui::pos2(
+        emath::remap(position.x, world_min.x..=world_max.x, rect.left()..=rect.right()),
+        emath::remap(position.y, world_min.y..=world_max.y, rect.top()..=rect.bottom()),
+    );
+
+    for chunk in &overlay.chunks {
+        let screen_rect = egui::Rect::from_two_pos(to_screen(Vec2::new(chunk.min.x, chunk.min.y)), to_screen(Vec2::new(chunk.max.x, chunk.max.y)));
+        painter.rect_stroke(screen_rect, 0., egui::Stroke::new(1., CHUNK_COLOR));
+    }
+    let route_points = overlay.route.iter().map(|point| to_screen(point.xz())).collect();
+    painter.add(egui::Shape::line(route_points, egui::Stroke::new(2., ROUTE_COLOR)));
+    for (position, tracked) in &overlay.wagons {
+        painter.circle_filled(to_screen(*position), 4., if *tracked { TRACKED_WAGON_COLOR } else { WAGON_COLOR });
+    }
+    if let Some((position, forward)) = overlay.player {
+        let screen_position = to_screen(position);
+        let direction = egui::vec2(forward.x, forward.y) * 12.;
+        painter.circle_filled(screen_position, 3., PLAYER_COLOR);
+        painter.line_segment([screen_position, screen_position + direction], egui::Stroke::new(2., PLAYER_COLOR));
+    }
+    painter.rect_stroke(rect, 0., ui.visuals().widgets.noninteractive.bg_stroke);
+
+    let click_position = response.interact_pointer_pos().filter(|_| response.clicked())?;
+    Some(Vec2::new(
+        emath::remap(click_position.x, rect.left()..=rect.right(), world_min.x..=world_max.x),
+        emath::remap(click_position.y, rect.top()..=rect.bottom(), world_min.y..=world_max.y),
+    ))
+}
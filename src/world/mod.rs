@@ -1,44 +1,145 @@
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 use crate::assets::AssetLoadingState;
 use crate::lines::LineMaterial;
 
+use crate::world::map::*;
 use crate::world::route_gen::*;
+use crate::world::route_profile::*;
 use crate::world::terrain::*;
+use crate::world::track_profiles::*;
 use crate::world::train_tracks::*;
+use crate::world::tunnels::*;
 
 pub mod terrain;
+mod map;
 pub mod route_gen;
+mod route_profile;
+pub mod track_profiles;
 pub mod train_tracks;
+pub mod tunnels;
 mod utils;
 
+/// The dimensions of the generated world.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldSettings {
+    /// The size of a far grid terrain chunk in meters.
+    pub far_chunk_size: u32,
+    /// The number of far grid chunks generated in each direction from the player chunk.
+    /// The route and the tracks are generated as far as the terrain.
+    pub far_render_distance: u32,
+    /// The number of near grid chunks along each side of a far grid chunk.
+    pub near_chunks_per_side: u32,
+    /// The height of the water plane in meters.
+    pub water_level: f32,
+    pub route: RouteSettings,
+}
+
+impl Default for WorldSettings {
+    fn default() -> Self {
+        Self {
+            far_chunk_size: 1000,
+            far_render_distance: 5,
+            near_chunks_per_side: 10,
+            water_level: -23.,
+            route: RouteSettings::default(),
+        }
+    }
+}
+
+/// The constraints the route is generated with.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RouteSettings {
+    /// The distance between each route node in meters.
+    pub node_length: f32,
+    /// The maximum allowed turn angle between each successive nodes in degrees.
+    pub max_turn_angle: i32,
+    /// The minimum depth of the terrain above the track for a route node to be placed in a tunnel in meters.
+    pub tunnel_depth_threshold: f32,
+    /// The maximum grade of the track inside a tunnel.
+    pub max_tunnel_grade: f32,
+    /// The extra cost of a tunnel node compared to a surface node, in slope units.
+    /// The higher this is, the steeper the detour the route will take before choosing to tunnel.
+    pub tunnel_node_cost: f32,
+}
+
+impl Default for RouteSettings {
+    fn default() -> Self {
+        Self {
+            node_length: 50.,
+            max_turn_angle: 5,
+            tunnel_depth_threshold: 12.,
+            max_tunnel_grade: 0.015,
+            tunnel_node_cost: 0.05,
+        }
+    }
+}
+
 /// Responsible for routing through terrain, generating terrain mesh, and placing rail tracks.
-pub(crate) struct WorldPlugin;
+#[derive(Default)]
+pub struct WorldPlugin {
+    /// Only generate the route and the track data, without the terrain and the track meshes (no renderer needed).
+    pub headless: bool,
+    pub settings: WorldSettings,
+    /// The id of the track profile to build the tracks with. Uses the default profile of the definitions if `None`.
+    pub track_profile: Option<String>,
+}
 
 impl Plugin for WorldPlugin {
     fn build(&self, app: &mut App) {
         app
-            .add_plugins(MaterialPlugin::<LineMaterial>::default())
-            .add_plugins(MaterialPlugin::<TerrainMaterial>::default())
-
+            .insert_resource(self.settings.clone())
             .insert_resource(Route::default())
             .insert_resource(Terrain::default())
             .insert_resource(PlacementData::default())
+            .insert_resource(TrackProfile::default())
+            .insert_resource(TrackProfileSelection(self.track_profile.clone()))
+            .insert_resource(TunnelData::default())
+            .add_event::<TrackSegmentPlaced>()
 
             // startup systems
             .add_systems(Startup, init_line_points)
+            .add_systems(OnEnter(AssetLoadingState::AssetsLoaded), (apply_track_profile, spawn_track_entities).chain())
+
+            // update systems
+            .add_systems(Update, build_route_path)
+            .add_systems(Update,
+                         (update_placement_data, update_track_entities, evict_track_data)
+                             .chain()
+                             .run_if(in_state(AssetLoadingState::AssetsLoaded)));
+
+        if self.headless {
+            return;
+        }
+
+        app
+            .add_plugins(MaterialPlugin::<LineMaterial>::default())
+            .add_plugins(MaterialPlugin::<TerrainMaterial>::default())
+
             .add_systems(OnEnter(AssetLoadingState::AssetsLoaded),(setup_terrain, setup_water))
             .add_systems(OnEnter(AssetLoadingState::AssetsLoaded),
-                         (spawn_track_entity, setup_track_data, setup_track_material))
+                         (setup_track_data, setup_track_material, setup_tunnel_data).after(apply_track_profile))
+
+            .init_resource::<RouteProfile>()
+            .init_resource::<MapView>()
 
-            // update systems
             .add_systems(Update, update_polyline_points)
-            .add_systems(Update, build_route_path)
+            .add_systems(Update, (route_profile_ui, map_ui).run_if(in_state(AssetLoadingState::AssetsLoaded)))
             .add_systems(Update,
                          (spawn_generated_chunks, generate_far_terrain, generate_near_terrain, remove_unused_terrain, update_water_plane, configure_terrain_images)
                              .run_if(in_state(AssetLoadingState::AssetsLoaded)))
             .add_systems(Update,
-                         (update_placement_data, update_track_entity, place_tracks)
+                         (place_tracks, place_tunnels, update_track_lod)
+                             .chain()
+                             .after(evict_track_data)
+                             .run_if(in_state(AssetLoadingState::AssetsLoaded)))
+            .add_systems(Update,
+                         punch_terrain_holes
+                             .after(place_tunnels)
+                             .before(generate_far_terrain)
+                             .before(remove_unused_terrain)
                              .run_if(in_state(AssetLoadingState::AssetsLoaded)));
     }
 }
-
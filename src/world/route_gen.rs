@@ -1,36 +1,97 @@
 use bevy::color::palettes::css::RED;
-use crate::{Assets, Component, Commands, default, MaterialMeshBundle, Mesh, noise, NoiseSettings, Player, Query, Res, ResMut, Transform, Vec2, Vec3, With, Entity, Resource};
+use bevy::prelude::*;
+use crate::{noise, NoiseSettings, Player};
 use crate::lines::{LineMaterial, LineStrip};
 use crate::world::terrain;
-use crate::world::terrain::{FAR_GRID_CHUNK_SIZE, is_within_far_render_distance};
-
-/// The distance between each route node
-const NODE_LENGTH: f32 = 50.;
-/// The maximum allowed turn angle between each successive nodes in degrees
-const MAX_TURN_ANGLE: i32 = 5;
+use crate::world::terrain::is_within_far_render_distance;
+use crate::world::{RouteSettings, WorldSettings};
 
 #[derive(Component)]
 pub(crate) struct RouteNode;
 
+/// The nodes of the route, in the order they were generated. Each pair of successive nodes is a track segment.
+/// Only the nodes from the oldest one still needed on are kept, the ones before it are evicted for good.
 #[derive(Resource)]
-pub(crate) struct Route {
+pub struct Route {
     pub id_counter: usize,
+    /// The id of the first kept node, i.e. the number of evicted nodes.
+    first_id: usize,
     points: Vec<Vec3>,
+    /// Whether the node with the same index is placed inside a tunnel.
+    /// The height of a tunnel node is the designed track height rather than the terrain height.
+    tunnel_nodes: Vec<bool>,
     points_changed: bool,
 }
 
 impl Route {
-    pub fn get_last_id(&self) -> usize {
-        self.id_counter - 1
+    pub fn get_point(&self, id: usize) -> Option<&Vec3> {
+        self.points.get(id.checked_sub(self.first_id)?)
     }
 
-    pub fn get_point(&self, id: usize) -> Option<&Vec3> {
-        if id >= self.points.len() { None }
-        else { Some(&self.points[id]) }
+    pub fn is_tunnel_node(&self, id: usize) -> bool {
+        id.checked_sub(self.first_id).and_then(|index| self.tunnel_nodes.get(index)).copied().unwrap_or(false)
+    }
+
+    /// Returns the id of the first kept node.
+    pub fn first_id(&self) -> usize {
+        self.first_id
+    }
+
+    /// Whether the segment with the given id runs (at least partially) through a tunnel, i.e. any of its ends is a tunnel node.
+    pub fn is_tunnel_segment(&self, id: usize) -> bool {
+        self.is_tunnel_node(id) || self.is_tunnel_node(id + 1)
     }
 
-    pub fn get_cloned_points(&self) -> Vec<Vec3> {
-        self.points.clone()
+    /// Returns the kept nodes, starting with the one with id `first_id`.
+    pub fn points(&self) -> &[Vec3] {
+        &self.points
+    }
+
+    pub(crate) fn tunnel_nodes(&self) -> &[bool] {
+        &self.tunnel_nodes
+    }
+
+    /// Checks whether the given nodes, starting with the one with id `first_id`, are part of the route.
+    pub(crate) fn contains(&self, first_id: usize, points: &[Vec3]) -> bool {
+        first_id >= self.first_id && self.points.get(first_id - self.first_id..).is_some_and(|kept| kept.starts_with(points))
+    }
+
+    /// Generates the next node, continuing in the direction of the last two nodes.
+    pub(crate) fn extend(&mut self, noise_settings: NoiseSettings, route_settings: &RouteSettings) {
+        let noise_fn = noise::get_heightmap_function(noise_settings, Vec3::ZERO);
+        let last_route_point = *self.get_point(self.id_counter - 1).unwrap();
+        let route_point_before_last = self.get_point(self.id_counter - 2).unwrap();
+        let route_vector = Vec2::new(last_route_point.x - route_point_before_last.x, last_route_point.z - route_point_before_last.z);
+        let world_vector = Vec2::new(1.0, 0.0);
+        let angle = (route_vector.dot(world_vector) / (route_vector.length() * 1.0)).acos().to_degrees() as i32;
+
+        let (next_route_point, next_in_tunnel) = find_next_path_node(noise_fn, route_settings, last_route_point, angle, route_settings.max_turn_angle, 1);
+        self.points.push(next_route_point);
+        self.tunnel_nodes.push(next_in_tunnel);
+        self.id_counter += 1;
+        self.points_changed = true;
+    }
+
+    /// Drops the nodes before the given id. The last two nodes are always kept, so that the route can continue.
+    pub(crate) fn evict_before(&mut self, id: usize) {
+        let count = id.saturating_sub(self.first_id).min(self.points.len().saturating_sub(2));
+        if count == 0 {
+            return;
+        }
+        self.points.drain(..count);
+        self.tunnel_nodes.drain(..count);
+        self.first_id += count;
+        self.points_changed = true;
+    }
+
+    /// Replaces the route with the given nodes (e.g. from a save), the first one having the id `first_id`.
+    /// The route continues from the last node.
+    pub(crate) fn restore(&mut self, first_id: usize, points: Vec<Vec3>, tunnel_nodes: Vec<bool>) {
+        self.id_counter = first_id + points.len();
+        self.first_id = first_id;
+        self.points = points;
+        self.tunnel_nodes = tunnel_nodes;
+        self.points_changed = true;
     }
 }
 
@@ -38,7 +99,9 @@ impl Default for Route {
     fn default() -> Self {
         Self {
             id_counter: 0,
+            first_id: 0,
             points: Vec::new(),
+            tunnel_nodes: Vec::new(),
             points_changed: false,
         }
     }
@@ -48,46 +111,63 @@ impl Default for Route {
 pub(crate) fn init_line_points(
     mut route_res: ResMut<Route>,
     noise_settings: Res<NoiseSettings>,
+    settings: Res<WorldSettings>,
 ) {
-    let noise_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings.clone(), Vec3::ZERO);
+    let noise_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
     let starting_point_2d = Vec2::new(0., 0.);
     let starting_height = noise_fn(starting_point_2d.x as f64, starting_point_2d.y as f64) as f32;
     let starting_point = Vec3::new(starting_point_2d.x, starting_height + 1., starting_point_2d.y);
 
-    let next_point = find_next_path_node(noise_fn, starting_point, 0, 180, 5);
+    let (next_point, next_in_tunnel) = find_next_path_node(noise_fn, &settings.route, starting_point, 0, 180, 5);
 
     route_res.points.insert(0, starting_point);
     route_res.points.insert(1, next_point);
+    route_res.tunnel_nodes.insert(0, false);
+    route_res.tunnel_nodes.insert(1, next_in_tunnel);
     route_res.id_counter = 2;
     route_res.points_changed = true;
 }
 
+/// Draws the route nodes within the render distance as a line.
 pub(crate) fn update_polyline_points(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<LineMaterial>>,
     mut route_res: ResMut<Route>,
+    mut drawn_from_chunk: Local<Option<IVec2>>,
 
+    player_query: Query<&Transform, With<Player>>,
     route_node_query: Query<Entity, With<RouteNode>>,
+    settings: Res<WorldSettings>,
 ) {
-    if !route_res.points_changed { return; }
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
+    let player_chunk_pos = terrain::get_far_chunk_position(&settings, Vec2::new(player_transform.translation.x, player_transform.translation.z));
+    if !route_res.points_changed && *drawn_from_chunk == Some(player_chunk_pos) { return; }
 
+    // The mesh and material assets are freed together with the entity holding their handles.
     for entity in &route_node_query {
         commands.entity(entity).despawn();
-        //TODO: also remove associated meshes, this might be causing a memory leak.
     }
 
-    let point_array: Vec<Vec3> = route_res.get_cloned_points();
-
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Mesh::from(LineStrip {
-            points: point_array,
-        })),
-        material: materials.add(LineMaterial { color: RED.into() }),
-        ..default()
-    }).insert(RouteNode);
+    let is_in_range = |point: &Vec3| is_within_far_render_distance(&settings, &Vec2::new(point.x, point.z), &player_chunk_pos);
+    let first_id = route_res.points.iter().position(is_in_range);
+    let last_id = route_res.points.iter().rposition(is_in_range);
+    if let (Some(first_id), Some(last_id)) = (first_id, last_id) {
+        let point_array: Vec<Vec3> = route_res.points[first_id..=last_id].to_vec();
+
+        commands.spawn(MaterialMeshBundle {
+            mesh: meshes.add(Mesh::from(LineStrip {
+                points: point_array,
+            })),
+            material: materials.add(LineMaterial { color: RED.into() }),
+            ..default()
+        }).insert(RouteNode);
+    }
 
     route_res.points_changed = false;
+    *drawn_from_chunk = Some(player_chunk_pos);
 }
 
 /// Builds the track route for the generated chunks.
@@ -96,51 +176,62 @@ pub(crate) fn build_route_path(
 
     player_query: Query<&Transform, With<Player>>,
     noise_settings: Res<NoiseSettings>,
+    settings: Res<WorldSettings>,
 ) {
-    let noise_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings.clone(), Vec3::ZERO);
     let current_node_id = route_res.id_counter;
 
-    let player_transform = player_query.single();
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
     let player_world_position = Vec2::new(player_transform.translation.x, player_transform.translation.z);
 
-    let player_chunk_pos = terrain::get_far_chunk_position(player_world_position);
+    let player_chunk_pos = terrain::get_far_chunk_position(&settings, player_world_position);
     let last_route_point = route_res.get_point(current_node_id - 1).unwrap().clone();
     // Do not proceed if outside of render distance
-    if !is_within_far_render_distance(&Vec2::new(last_route_point.x, last_route_point.z), &player_chunk_pos) {
+    if !is_within_far_render_distance(&settings, &Vec2::new(last_route_point.x, last_route_point.z), &player_chunk_pos) {
         return;
     }
 
-    let route_point_before_last = route_res.get_point(current_node_id - 2).unwrap();
-    let route_vector = Vec2::new(last_route_point.x - route_point_before_last.x, last_route_point.z - route_point_before_last.z);
-    let world_vector = Vec2::new(1.0, 0.0);
-    let angle = (route_vector.dot(world_vector) / (route_vector.length() * 1.0)).acos().to_degrees() as i32;
-
-    let next_route_point = find_next_path_node(noise_fn, last_route_point, angle, MAX_TURN_ANGLE, 1);
-    route_res.points.insert(current_node_id, next_route_point);
-    route_res.id_counter += 1;
-    route_res.points_changed = true;
+    route_res.extend(*noise_settings, &settings.route);
 }
 
-/// Calculates the next node in the route path by taking the route with lowest slope
-pub(crate) fn find_next_path_node<F>(noise_fn: F, starting_point: Vec3, starting_absolute_angle_deg: i32, max_angle_deg: i32, angle_step_deg: usize) -> Vec3
+/// Calculates the next node in the route path by taking the route with the lowest cost.
+/// For every direction, the node can either follow the terrain (costing its slope) or, if the terrain
+/// rises more than `tunnel_depth_threshold` above a track with a limited grade, go through a tunnel
+/// (costing its grade plus `tunnel_node_cost`). This lets the route tunnel through a hill instead of taking a detour.
+///
+/// Returns the position of the node and whether it is placed inside a tunnel.
+pub(crate) fn find_next_path_node<F>(noise_fn: F, route_settings: &RouteSettings, starting_point: Vec3, starting_absolute_angle_deg: i32, max_angle_deg: i32, angle_step_deg: usize) -> (Vec3, bool)
     where F: Fn(f64, f64) -> f64 {
-    let mut result = Vec3::ZERO;
-    let mut current_min_slope = 1000.; // arbitrarily large number
+    let mut result = (Vec3::ZERO, false);
+    let mut current_min_cost = 1000.; // arbitrarily large number
     let starting_point_2d = Vec2::new(starting_point.x, starting_point.z);
     for angle_deg in ((starting_absolute_angle_deg - max_angle_deg)..(starting_absolute_angle_deg + max_angle_deg + 1)).step_by(angle_step_deg) {
         let angle_rad = f32::to_radians(angle_deg as f32);
-        let x = NODE_LENGTH * angle_rad.cos();
-        let y = NODE_LENGTH * angle_rad.sin();
+        let x = route_settings.node_length * angle_rad.cos();
+        let y = route_settings.node_length * angle_rad.sin();
         let this_pos = Vec2::new(x, y) + starting_point_2d;
+        let dist = this_pos.distance(starting_point_2d);
 
         let height_here = noise_fn(this_pos.x as f64, this_pos.y as f64) as f32;
         //if height_here <= WATER_LEVEL {
         //    continue;
         //}
-        let slope = calc_absolute_slope(this_pos.distance(starting_point_2d), starting_point.y, height_here);
-        if slope < current_min_slope {
-            current_min_slope = slope;
-            result = Vec3::new(this_pos.x, height_here, this_pos.y);
+        let slope = calc_absolute_slope(dist, starting_point.y, height_here);
+        if slope < current_min_cost {
+            current_min_cost = slope;
+            result = (Vec3::new(this_pos.x, height_here, this_pos.y), false);
+        }
+
+        // Try tunneling: keep the track as close to the terrain as the tunnel grade allows.
+        let max_height_change = route_settings.max_tunnel_grade * dist;
+        let tunnel_height = starting_point.y + (height_here - starting_point.y).clamp(-max_height_change, max_height_change);
+        if height_here - tunnel_height > route_settings.tunnel_depth_threshold {
+            let tunnel_cost = calc_absolute_slope(dist, starting_point.y, tunnel_height) + route_settings.tunnel_node_cost;
+            if tunnel_cost < current_min_cost {
+                current_min_cost = tunnel_cost;
+                result = (Vec3::new(this_pos.x, tunnel_height, this_pos.y), true);
+            }
         }
     }
 
@@ -0,0 +1,213 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Line, Plot, PlotPoints, Polygon, VLine};
+use crate::{noise, NoiseSettings, Player};
+use crate::camera::{CameraMode, CameraRig};
+use crate::rolling_stock::cargo_systems::LoadingPoint;
+use crate::world::route_gen::Route;
+use crate::world::train_tracks::sample_route_segment;
+
+/// The number of points sampled per segment for the profile.
+const PROFILE_SAMPLES_PER_SEGMENT: u32 = 8;
+/// Where the camera is placed when the profile is clicked: behind the clicked point along the route and above it, in m.
+const CAMERA_DISTANCE_BEHIND: f32 = 40.;
+const CAMERA_HEIGHT: f32 = 30.;
+const PLOT_HEIGHT: f32 = 80.;
+/// The height of the track above the terrain from which it is marked as a bridge, in m.
+const BRIDGE_CLEARANCE: f32 = 3.;
+const TERRAIN_COLOR: egui::Color32 = egui::Color32::from_rgb(150, 110, 70);
+const TRACK_COLOR: egui::Color32 = egui::Color32::from_rgb(210, 210, 210);
+const TUNNEL_COLOR: egui::Color32 = egui::Color32::from_rgba_premultiplied(60, 60, 60, 90);
+const BRIDGE_COLOR: egui::Color32 = egui::Color32::from_rgba_premultiplied(30, 60, 120, 90);
+const LOADING_POINT_COLOR: egui::Color32 = egui::Color32::from_rgb(230, 180, 60);
+
+/// A point on the midline of the route.
+struct ProfilePoint {
+    /// The horizontal distance from the start of the route in m.
+    distance: f32,
+    /// The position of the midline at the designed track height.
+    position: Vec3,
+    terrain_height: f32,
+    segment_id: usize,
+}
+
+/// The profile of the whole route generated so far. Extended as the route grows, and sampled again when the noise changes.
+#[derive(Resource, Default)]
+pub(crate) struct RouteProfile {
+    points: Vec<ProfilePoint>,
+    /// The id of the last sampled segment.
+    last_segment_id: usize,
+    /// The end node of the last sampled segment. A restored route may go elsewhere.
+    last_node: Option<Vec3>,
+    needs_resample: bool,
+}
+
+impl RouteProfile {
+    fn update(&mut self, route: &Route, noise_settings: NoiseSettings) {
+        // The sampled points are kept after the route nodes are evicted, so that the profile still covers the whole route
+        let is_same_route = self.last_node.map_or(true, |node| {
+            self.last_segment_id + 1 < route.first_id() || route.get_point(self.last_segment_id + 1) == Some(&node)
+        });
+        if self.needs_resample || !is_same_route {
+            *self = RouteProfile::default();
+        }
+
+        let height_fn = noise::get_heightmap_function(noise_settings, Vec3::ZERO);
+        // A resampled profile starts at the first segment that can still be built
+        let mut next_segment_id = (self.last_segment_id + 1).max(route.first_id() + 1);
+        while let Some(samples) = sample_route_segment(route, next_segment_id, noise_settings, PROFILE_SAMPLES_PER_SEGMENT) {
+            self.last_segment_id = next_segment_id;
+            self.last_node = route.get_point(next_segment_id + 1).copied();
+            next_segment_id += 1;
+            // The first point of a segment is the last point of the previous one
+            let skipped = if self.points.is_empty() { 0 } else { 1 };
+            for sample in samples.into_iter().skip(skipped) {
+                let distance = self.points.last()
+                    .map(|last| last.distance + last.position.xz().distance(sample.position.xz()))
+                    .unwrap_or_default();
+                self.points.push(ProfilePoint {
+                    distance,
+                    position: sample.position,
+                    terrain_height: height_fn(sample.position.x as f64, sample.position.z as f64) as f32,
+                    segment_id: self.last_segment_id,
+                });
+            }
+        }
+    }
+
+    /// Returns the shaded spans of x where the points fulfill the predicate.
+    fn spans_where(&self, color: egui::Color32, predicate: impl Fn(&ProfilePoint) -> bool) -> Vec<(egui::Color32, f32, f32)> {
+        let mut spans = Vec::new();
+        let mut span_start = None;
+        for point in &self.points {
+            match (predicate(point), span_start) {
+                (true, None) => span_start = Some(point.distance),
+                (false, Some(start)) => {
+                    spans.push((color, start, point.distance));
+                    span_start = None;
+                },
+                _ => {},
+            }
+        }
+        if let (Some(start), Some(last)) = (span_start, self.points.last()) {
+            spans.push((color, start, last.distance));
+        }
+        spans
+    }
+}
+
+/// Plots the lines over the distance along the route, with the spans shaded in their color over the range of the lines
+/// and the loading points as vertical lines. Returns the distance that was clicked.
+fn profile_plot(
+    ui: &mut egui::Ui,
+    id: &str,
+    lines: Vec<(&str, egui::Color32, Vec<[f64; 2]>)>,
+    spans: &[(egui::Color32, f32, f32)],
+    loading_points: &[f32],
+) -> Option<f32> {
+    let (min, max) = lines.iter()
+        .flat_map(|(_, _, points)| points)
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), point| (min.min(point[1]), max.max(point[1])));
+    if !min.is_finite() {
+        return None;
+    }
+
+    Plot::new(id).height(PLOT_HEIGHT).x_axis_label("m").show(ui, |plot_ui| {
+        for (color, start, end) in spans {
+            let (start, end) = (*start as f64, *end as f64);
+            let corners: PlotPoints = vec![[start, min], [end, min], [end, max], [start, max]].into();
+            plot_ui.polygon(Polygon::new(corners).fill_color(*color).stroke(egui::Stroke::NONE));
+        }
+        for distance in loading_points {
+            plot_ui.vline(VLine::new(*distance).color(LOADING_POINT_COLOR));
+        }
+        for (name, color, points) in lines {
+            plot_ui.line(Line::new(points).color(color).name(name));
+        }
+        let clicked_position = plot_ui.pointer_coordinate().filter(|_| plot_ui.response().clicked())?;
+        Some(clicked_position.x as f32)
+    }).inner
+}
+
+/// Shows the height, the grade and the curvature along the whole route, with the tunnels, the bridges and the loading points
+/// marked. There are no stations, the loading points stand in for them. Clicking a plot moves the camera to that point of the route.
+pub(crate) fn route_profile_ui(
+    mut egui_contexts: EguiContexts,
+    mut profile: ResMut<RouteProfile>,
+    route: Res<Route>,
+    noise_settings: Res<NoiseSettings>,
+    loading_point_query: Query<&LoadingPoint>,
+    mut player_query: Query<&mut Transform, With<Player>>,
+    camera_rig: Option<ResMut<CameraRig>>,
+) {
+    // Remembered until the window is open, the profile is only sampled while it is shown
+    profile.needs_resample |= noise_settings.is_changed();
+
+    let mut clicked_distance = None;
+    egui::Window::new("Route Profile").default_open(false).show(egui_contexts.ctx_mut(), |ui| {
+        ui.set_width(600.);
+        profile.update(&route, *noise_settings);
+        let Some(last_point) = profile.points.last() else {
+            ui.label("The route is not generated yet.");
+            return;
+        };
+        ui.label(format!("{} segments, {:.2} km", profile.last_segment_id, last_point.distance / 1000.));
+        ui.horizontal_wrapped(|ui| {
+            ui.colored_label(TERRAIN_COLOR, "Terrain");
+            ui.colored_label(TRACK_COLOR, "Track");
+            ui.colored_label(TUNNEL_COLOR.to_opaque(), "Tunnel");
+            ui.colored_label(BRIDGE_COLOR.to_opaque(), "Bridge");
+            ui.colored_label(LOADING_POINT_COLOR, "Loading point (station)");
+        });
+
+        let mut spans = profile.spans_where(TUNNEL_COLOR, |point| route.is_tunnel_segment(point.segment_id));
+        spans.extend(profile.spans_where(BRIDGE_COLOR, |point| point.position.y - point.terrain_height > BRIDGE_CLEARANCE));
+        let loading_points: Vec<f32> = loading_point_query.iter()
+            .filter_map(|loading_point| profile.points.iter().find(|point| point.segment_id == loading_point.segment_id))
+            .map(|point| point.distance)
+            .collect();
+
+        let points = &profile.points;
+        let terrain = points.iter().map(|point| [point.distance as f64, point.terrain_height as f64]).collect();
+        let track = points.iter().map(|point| [point.distance as f64, point.position.y as f64]).collect();
+        let grade = points.windows(2)
+            .filter(|pair| pair[1].distance > pair[0].distance)
+            .map(|pair| [pair[0].distance as f64, ((pair[1].position.y - pair[0].position.y) / (pair[1].distance - pair[0].distance) * 100.) as f64])
+            .collect();
+        // The signed curvature of the circle through three successive points
+        let curvature = points.windows(3)
+            .map(|triple| {
+                let (a, b, c) = (triple[0].position.xz(), triple[1].position.xz(), triple[2].position.xz());
+                let lengths = a.distance(b) * b.distance(c) * c.distance(a);
+                let curvature = if lengths > 0.001 { 2. * (b - a).perp_dot(c - a) / lengths } else { 0. };
+                [triple[1].distance as f64, (curvature * 1000.) as f64]
+            })
+            .collect();
+
+        ui.label("Height (m)");
+        let height_lines = vec![("Terrain", TERRAIN_COLOR, terrain), ("Track", TRACK_COLOR, track)];
+        clicked_distance = clicked_distance.or(profile_plot(ui, "Height", height_lines, &spans, &loading_points));
+        ui.label("Grade (%)");
+        clicked_distance = clicked_distance.or(profile_plot(ui, "Grade", vec![("Grade", TRACK_COLOR, grade)], &spans, &loading_points));
+        ui.label("Curvature (1/km, the sign gives the direction of the curve)");
+        clicked_distance = clicked_distance.or(profile_plot(ui, "Curvature", vec![("Curvature", TRACK_COLOR, curvature)], &spans, &loading_points));
+        ui.label("Click a plot to move the camera there.");
+    });
+
+    let (Some(distance), Ok(mut player_transform)) = (clicked_distance, player_query.get_single_mut()) else {
+        return;
+    };
+    // Back to the fly camera, the other camera modes stay with the train
+    if let Some(mut camera_rig) = camera_rig {
+        camera_rig.mode = CameraMode::Free;
+    }
+    let index = profile.points.partition_point(|point| point.distance < distance).min(profile.points.len() - 1);
+    let point = &profile.points[index];
+    let previous_point = &profile.points[index.saturating_sub(1)];
+    let next_point = &profile.points[(index + 1).min(profile.points.len() - 1)];
+    let direction = (next_point.position - previous_point.position).with_y(0.).normalize_or_zero();
+    // Above the terrain, so that tunnels are seen from outside
+    let target = point.position.with_y(point.position.y.max(point.terrain_height));
+    player_transform.translation = target - direction * CAMERA_DISTANCE_BEHIND + Vec3::Y * CAMERA_HEIGHT;
+    player_transform.look_at(target, Vec3::Y);
+}
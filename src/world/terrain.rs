@@ -13,16 +13,17 @@ use bevy::reflect::{TypePath};
 use bevy::render::render_asset::RenderAssetUsages;
 use bevy::render::texture::{ImageAddressMode, ImageSamplerDescriptor};
 
-use crate::{Mesh, Vec2, Component, Indices, Vec3, PrimitiveTopology, Player, Transform, Commands, Assets, ResMut, Res, StandardMaterial, default, MaterialMeshBundle, Handle, With, Entity, NoiseSettings, Image, Vec4, RenderAssets, noise};
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssets;
+use serde::{Deserialize, Serialize};
+use crate::{noise, NoiseSettings, Player};
 use crate::assets::{TextureAssets};
+use crate::world::WorldSettings;
 
-pub const FAR_GRID_CHUNK_SIZE: u32 = 1000; // in meters
-pub const FAR_GRID_RENDER_DISTANCE: u32 = 5; // far grid chunks
-
-pub const NEAR_GRID_CHUNK_SIZE: u32 = FAR_GRID_CHUNK_SIZE / 10; // in meters
-pub const NEAR_GRID_RENDER_DISTANCE: u32 = 2; // far grid chunks
-
-pub const WATER_LEVEL: f32 = -23.;
+/// The distance between the vertices of the far grid chunk meshes in meters.
+const FAR_VERTEX_SPACING: u32 = 200;
+/// The distance between the vertices of the near grid chunk meshes in meters.
+const NEAR_VERTEX_SPACING: u32 = 20;
 
 #[derive(Default)]
 pub(crate) struct FarChunkData {
@@ -43,9 +44,22 @@ pub(crate) struct FarChunkData {
     pub(crate) midline_exit_node_id: Option<usize>,
 }
 
+/// A circular area (in world space) in which no terrain triangles are generated, i.e. around tunnel portals.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct TerrainHole {
+    pub center: Vec2,
+    pub radius: f32,
+}
+
+impl TerrainHole {
+    fn contains(&self, point: Vec2) -> bool {
+        self.center.distance(point) <= self.radius
+    }
+}
+
 /// The main terrain resource
 #[derive(Resource)]
-pub(crate) struct Terrain {
+pub struct Terrain {
     /// The ID counter for unique chunk IDs.
     id_counter: u64,
 
@@ -59,6 +73,11 @@ pub(crate) struct Terrain {
     grass_texture_handle: Option<Handle<Image>>,
     /// Texture handle for rock.
     rock_texture_handle: Option<Handle<Image>>,
+
+    /// The holes that are cut out of every generated chunk mesh.
+    holes: Vec<TerrainHole>,
+    /// The holes added since the last run of `punch_terrain_holes`. The chunk meshes they overlap get rebuilt.
+    pending_holes: Vec<TerrainHole>,
 }
 
 impl Default for Terrain {
@@ -71,6 +90,9 @@ impl Default for Terrain {
             terrain_material_handle: None,
             grass_texture_handle: None,
             rock_texture_handle: None,
+
+            holes: Vec::new(),
+            pending_holes: Vec::new(),
         }
     }
 }
@@ -82,6 +104,32 @@ impl Terrain {
 
         result
     }
+
+    /// Drops the data of the loaded chunks, so that they are generated again (e.g. with new noise settings).
+    pub fn reload_chunks(&mut self) {
+        self.loaded_chunks.clear();
+    }
+
+    /// Returns the holes cut into the terrain so far.
+    pub fn holes(&self) -> &[TerrainHole] {
+        &self.holes
+    }
+
+    /// Drops the holes for which the predicate returns false.
+    pub(crate) fn retain_holes(&mut self, keep: impl Fn(&TerrainHole) -> bool) {
+        self.holes.retain(|hole| keep(hole));
+        self.pending_holes.retain(|hole| keep(hole));
+    }
+
+    /// Cuts a hole into the terrain. The meshes of the already generated chunks overlapping the hole are rebuilt.
+    /// Holes that have already been cut (e.g. by a portal being placed again) are ignored.
+    pub(crate) fn add_hole(&mut self, hole: TerrainHole) {
+        if self.holes.iter().any(|existing| existing.center == hole.center && existing.radius == hole.radius) {
+            return;
+        }
+        self.holes.push(hole);
+        self.pending_holes.push(hole);
+    }
 }
 
 #[derive(Component)]
@@ -132,6 +180,7 @@ pub(crate) fn setup_water(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut standard_materials: ResMut<Assets<StandardMaterial>>,
+    settings: Res<WorldSettings>,
 ) {
     // Spawn the water plane
     let plane_material = StandardMaterial {
@@ -141,8 +190,9 @@ pub(crate) fn setup_water(
         reflectance: 0.6,
         ..StandardMaterial::default()
     };
-    let plane_pos = Vec3::new(-1. * FAR_GRID_CHUNK_SIZE as f32 / 2., WATER_LEVEL, -1. * FAR_GRID_CHUNK_SIZE as f32 / 2.);
-    let plane_scale = Vec3::new((FAR_GRID_CHUNK_SIZE * FAR_GRID_RENDER_DISTANCE * 2) as f32 - FAR_GRID_CHUNK_SIZE as f32 * 0., 1., (FAR_GRID_CHUNK_SIZE * FAR_GRID_RENDER_DISTANCE * 2) as f32 - FAR_GRID_CHUNK_SIZE as f32 * 0.);
+    let chunk_size = settings.far_chunk_size as f32;
+    let plane_pos = Vec3::new(-1. * chunk_size / 2., settings.water_level, -1. * chunk_size / 2.);
+    let plane_scale = Vec3::new((settings.far_chunk_size * settings.far_render_distance * 2) as f32, 1., (settings.far_chunk_size * settings.far_render_distance * 2) as f32);
     commands.spawn(PbrBundle {
         mesh: meshes.add(Mesh::from(Plane3d::default())),
         material: standard_materials.add(plane_material),
@@ -155,10 +205,14 @@ pub(crate) fn setup_water(
 pub(crate) fn update_water_plane(
     mut water_plane_transform_query: Query<&mut Transform, (With<WaterPlane>, Without<Player>)>,
     player_transform_query: Query<&Transform, (With<Player>, Without<WaterPlane>)>,
+    settings: Res<WorldSettings>,
 ) {
-    let mut water_plane_transform = water_plane_transform_query.single_mut();
-    let player_translation = player_transform_query.single().translation;
-    water_plane_transform.translation = Vec3::new(player_translation.x - FAR_GRID_CHUNK_SIZE as f32 / 2., WATER_LEVEL, player_translation.z - FAR_GRID_CHUNK_SIZE as f32 / 2.);
+    let (Ok(mut water_plane_transform), Ok(player_transform)) = (water_plane_transform_query.get_single_mut(), player_transform_query.get_single()) else {
+        return;
+    };
+    let player_translation = player_transform.translation;
+    let half_chunk_size = settings.far_chunk_size as f32 / 2.;
+    water_plane_transform.translation = Vec3::new(player_translation.x - half_chunk_size, settings.water_level, player_translation.z - half_chunk_size);
 }
 
 /// A messy workaround to set sampler address modes for the terrain textures (needed to sample without UVs)
@@ -188,18 +242,23 @@ pub(crate) fn generate_far_terrain(
 
     mut commands: Commands,
     noise_settings: Res<NoiseSettings>,
+    settings: Res<WorldSettings>,
 ) {
     // Get player position first since terrain gen will be based on it
-    let player_transform = player_query.single();
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
     let player_world_position = Vec2::new(player_transform.translation.x, player_transform.translation.z);
-    let player_chunk = get_far_chunk_position(player_world_position);
+    let player_chunk = get_far_chunk_position(&settings, player_world_position);
 
     // Spawn threads for the chunks that need to be generated
     let thread_pool = AsyncComputeTaskPool::get();
-    for x in (player_chunk.x - FAR_GRID_RENDER_DISTANCE as i32)..(player_chunk.x + FAR_GRID_RENDER_DISTANCE as i32) {
-        for y in (player_chunk.y - FAR_GRID_RENDER_DISTANCE as i32)..(player_chunk.y + FAR_GRID_RENDER_DISTANCE as i32) {
+    let chunk_size = settings.far_chunk_size;
+    let render_distance = settings.far_render_distance as i32;
+    for x in (player_chunk.x - render_distance)..(player_chunk.x + render_distance) {
+        for y in (player_chunk.y - render_distance)..(player_chunk.y + render_distance) {
             let chunk = Vec2::new(x as f32, y as f32);
-            let chunk_world_position = (chunk * FAR_GRID_CHUNK_SIZE as f32) - Vec2::splat(FAR_GRID_CHUNK_SIZE as f32 / 2.);
+            let chunk_world_position = (chunk * chunk_size as f32) - Vec2::splat(chunk_size as f32 / 2.);
             // check first if the chunk is already loaded
             if terrain_res.loaded_chunks.values().any(|d| &d.pos == &chunk) { continue }
 
@@ -207,12 +266,9 @@ pub(crate) fn generate_far_terrain(
 
             // Calculate meshes asynchronously
             let noise_settings = noise_settings.clone();
+            let holes = terrain_res.holes.clone();
             let task = thread_pool.spawn(async move {
-                let noise_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings, Vec3::ZERO);
-
-                let (vertices, indices) = mesh_data_from_noise(noise_fn, FAR_GRID_CHUNK_SIZE + 1, FAR_GRID_CHUNK_SIZE + 1, 200, chunk_world_position);
-                let normals = calculate_normals(&vertices, &indices);
-                let mesh = build_mesh(vertices, indices, normals);
+                let mesh = build_chunk_mesh(noise_settings, chunk_size, FAR_VERTEX_SPACING, chunk_world_position, &holes);
 
                 (current_id.clone(), GenerateChunkMeshTaskType::FarGrid, chunk_world_position, mesh)
             });
@@ -230,14 +286,18 @@ pub(crate) fn generate_near_terrain(
 
     mut commands: Commands,
     noise_settings: Res<NoiseSettings>,
+    settings: Res<WorldSettings>,
 ) {
     // Get player position first since terrain gen will be based on it
-    let player_transform = player_query.single();
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
     let player_world_position = Vec2::new(player_transform.translation.x, player_transform.translation.z);
-    let player_chunk = get_far_chunk_position(player_world_position);
+    let player_chunk = get_far_chunk_position(&settings, player_world_position);
 
     // Spawn threads for the chunks that need to be generated
     let thread_pool = AsyncComputeTaskPool::get();
+    let holes = terrain_res.holes.clone();
     for (far_chunk_id, mut far_chunk_data) in terrain_res.loaded_chunks.iter_mut() {
         // do not generate near grid for this chunk if it's not ready yet
         if !far_chunk_data.flagged {
@@ -245,7 +305,7 @@ pub(crate) fn generate_near_terrain(
         }
 
         // do not generate near grid for this chunk if it is outside the far grid render distance (counting from the player chunk)
-        let far_chunk_pos = far_chunk_data.pos * FAR_GRID_CHUNK_SIZE as f32 - Vec2::splat(FAR_GRID_CHUNK_SIZE as f32 / 2.);
+        let far_chunk_pos = far_chunk_data.pos * settings.far_chunk_size as f32 - Vec2::splat(settings.far_chunk_size as f32 / 2.);
         //if !is_within_far_render_distance(&far_chunk_pos, &player_chunk) {
         //    continue;
         //}
@@ -254,20 +314,17 @@ pub(crate) fn generate_near_terrain(
         far_chunk_data.flagged = false;
         far_chunk_data.generating_near_chunks = true;
 
-        let num_near_chunks = 10;
-        let near_chunk_size = FAR_GRID_CHUNK_SIZE as i32 / num_near_chunks;
+        let num_near_chunks = settings.near_chunks_per_side as i32;
+        let near_chunk_size = settings.far_chunk_size as i32 / num_near_chunks;
         for x in 0..num_near_chunks {
             for y in 0..num_near_chunks {
                 let near_chunk_world_position = far_chunk_pos + Vec2::new((x * near_chunk_size) as f32, (y * near_chunk_size) as f32);
 
                 let noise_settings = noise_settings.clone();
                 let chunk_id = far_chunk_id.clone();
+                let holes = holes.clone();
                 let task = thread_pool.spawn(async move {
-                    let noise_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings, Vec3::ZERO);
-
-                    let (vertices, indices) = mesh_data_from_noise(noise_fn, near_chunk_size as u32 + 1, near_chunk_size as u32 + 1, 20,near_chunk_world_position);
-                    let normals = calculate_normals(&vertices, &indices);
-                    let mesh = build_mesh(vertices, indices, normals);
+                    let mesh = build_chunk_mesh(noise_settings, near_chunk_size as u32, NEAR_VERTEX_SPACING, near_chunk_world_position, &holes);
 
                     (chunk_id, GenerateChunkMeshTaskType::NearGrid, near_chunk_world_position, mesh)
                 });
@@ -351,17 +408,20 @@ pub(crate) fn remove_unused_terrain(
     mut meshes: ResMut<Assets<Mesh>>,
     player_query: Query<&Transform, With<Player>>,
     chunks: Query<(Entity, &FarGridTerrainChunk)>,
+    settings: Res<WorldSettings>,
 ) {
-    let player_transform = player_query.single();
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
     let player_position = Vec2::new(player_transform.translation.x, player_transform.translation.z);
 
-    let player_chunk_x = ((player_position.x + FAR_GRID_CHUNK_SIZE as f32 / 2.) / FAR_GRID_CHUNK_SIZE as f32).floor() as i32;
-    let player_chunk_y = ((player_position.y + FAR_GRID_CHUNK_SIZE as f32 / 2.) / FAR_GRID_CHUNK_SIZE as f32).floor() as i32;
+    let player_chunk = get_far_chunk_position(&settings, player_position);
+    let render_distance = settings.far_render_distance as f32;
 
     for (chunk_entity, chunk) in &chunks {
         let chunk_data = terrain_res.loaded_chunks.get(&chunk.0).unwrap();
-        if (chunk_data.pos.x < player_chunk_x as f32 - FAR_GRID_RENDER_DISTANCE as f32 || chunk_data.pos.x > player_chunk_x as f32 + FAR_GRID_RENDER_DISTANCE as f32)
-            || chunk_data.pos.y < player_chunk_y as f32 - FAR_GRID_RENDER_DISTANCE as f32 || chunk_data.pos.y > player_chunk_y as f32 + FAR_GRID_RENDER_DISTANCE as f32 {
+        if (chunk_data.pos.x < player_chunk.x as f32 - render_distance || chunk_data.pos.x > player_chunk.x as f32 + render_distance)
+            || chunk_data.pos.y < player_chunk.y as f32 - render_distance || chunk_data.pos.y > player_chunk.y as f32 + render_distance {
             commands.entity(chunk_entity).despawn();
 
             let mesh_handle = &chunk_data.mesh_handle;
@@ -372,18 +432,66 @@ pub(crate) fn remove_unused_terrain(
     }
 }
 
-pub(crate) fn get_far_chunk_position(world_position: Vec2) -> IVec2 {
-    let chunk_x = ((world_position.x + FAR_GRID_CHUNK_SIZE as f32 / 2.) / FAR_GRID_CHUNK_SIZE as f32).floor() as i32;
-    let chunk_y = ((world_position.y + FAR_GRID_CHUNK_SIZE as f32 / 2.) / FAR_GRID_CHUNK_SIZE as f32).floor() as i32;
+/// Cuts the holes added since the last run into the chunk meshes they overlap. The meshes are rebuilt in place, so the chunks
+/// stay visible. Waits for the chunks being generated, as they only have the holes known when their generation started.
+pub(crate) fn punch_terrain_holes(
+    mut terrain_res: ResMut<Terrain>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    far_chunks: Query<(&Transform, &Handle<Mesh>), With<FarGridTerrainChunk>>,
+    near_chunks: Query<(&Transform, &Handle<Mesh>), With<NearGridTerrainChunk>>,
+    mesh_gen_tasks: Query<(), With<GenerateChunkMeshTask>>,
+    noise_settings: Res<NoiseSettings>,
+    settings: Res<WorldSettings>,
+) {
+    if terrain_res.pending_holes.is_empty() || !mesh_gen_tasks.is_empty() {
+        return;
+    }
+    let pending_holes = std::mem::take(&mut terrain_res.pending_holes);
+
+    let far_chunk_size = settings.far_chunk_size;
+    let near_chunk_size = far_chunk_size / settings.near_chunks_per_side;
+    let far = far_chunks.iter().map(|(transform, mesh_handle)| (transform, mesh_handle, far_chunk_size, FAR_VERTEX_SPACING));
+    let near = near_chunks.iter().map(|(transform, mesh_handle)| (transform, mesh_handle, near_chunk_size, NEAR_VERTEX_SPACING));
+    for (transform, mesh_handle, chunk_size, vertex_spacing) in far.chain(near) {
+        let chunk_min = transform.translation.xz();
+        let overlaps = pending_holes.iter().any(|hole| {
+            let closest_point = hole.center.clamp(chunk_min, chunk_min + Vec2::splat(chunk_size as f32));
+            closest_point.distance(hole.center) <= hole.radius
+        });
+        if overlaps {
+            meshes.insert(mesh_handle, build_chunk_mesh(*noise_settings, chunk_size, vertex_spacing, chunk_min, &terrain_res.holes));
+        }
+    }
+}
+
+/// Builds the mesh of a square chunk with the given side length in meters, starting at the given world position.
+fn build_chunk_mesh(noise_settings: NoiseSettings, chunk_size: u32, vertex_spacing: u32, position: Vec2, holes: &[TerrainHole]) -> Mesh {
+    let noise_fn = noise::get_heightmap_function(noise_settings, Vec3::ZERO);
+    let (vertices, indices) = mesh_data_from_noise(noise_fn, chunk_size + 1, chunk_size + 1, vertex_spacing, position, holes);
+    let normals = calculate_normals(&vertices, &indices);
+    build_mesh(vertices, indices, normals)
+}
+
+/// Returns the far grid chunk the given world position (x, z) is in.
+pub fn get_far_chunk_position(settings: &WorldSettings, world_position: Vec2) -> IVec2 {
+    let chunk_size = settings.far_chunk_size as f32;
+    let chunk_x = ((world_position.x + chunk_size / 2.) / chunk_size).floor() as i32;
+    let chunk_y = ((world_position.y + chunk_size / 2.) / chunk_size).floor() as i32;
 
     IVec2::new(chunk_x, chunk_y)
 }
 
-pub(crate) fn is_within_far_render_distance(point: &Vec2, from_chunk_pos: &IVec2) -> bool {
-    let min_x = (from_chunk_pos.x - FAR_GRID_RENDER_DISTANCE as i32) * FAR_GRID_CHUNK_SIZE as i32;
-    let max_x = (from_chunk_pos.x + FAR_GRID_RENDER_DISTANCE as i32) * FAR_GRID_CHUNK_SIZE as i32;
-    let min_y = (from_chunk_pos.y - FAR_GRID_RENDER_DISTANCE as i32) * FAR_GRID_CHUNK_SIZE as i32;
-    let max_y = (from_chunk_pos.y + FAR_GRID_RENDER_DISTANCE as i32) * FAR_GRID_CHUNK_SIZE as i32;
+pub fn is_within_far_render_distance(settings: &WorldSettings, point: &Vec2, from_chunk_pos: &IVec2) -> bool {
+    is_within_far_chunk_distance(settings, point, from_chunk_pos, settings.far_render_distance)
+}
+
+/// Checks whether the point is at most `distance` far grid chunks away from the given chunk.
+pub fn is_within_far_chunk_distance(settings: &WorldSettings, point: &Vec2, from_chunk_pos: &IVec2, distance: u32) -> bool {
+    let chunk_size = settings.far_chunk_size as i32;
+    let min_x = (from_chunk_pos.x - distance as i32) * chunk_size;
+    let max_x = (from_chunk_pos.x + distance as i32) * chunk_size;
+    let min_y = (from_chunk_pos.y - distance as i32) * chunk_size;
+    let max_y = (from_chunk_pos.y + distance as i32) * chunk_size;
 
     if point.x > max_x as f32 || point.x < min_x as f32 {
         false
@@ -441,8 +549,8 @@ fn calculate_normals(vertices: &Vec<[f32; 3]>, indices: &Vec<u32>) -> Vec<[f32;
     normals.iter().map(|v| [v.x, v.y, v.z]).collect()
 }
 
-/// Generates mesh data (vertices, indices) from a noise function
-fn mesh_data_from_noise<F>(noise_fn: F, mesh_width: u32, mesh_height: u32, vertex_subdivision: u32, offset: Vec2) -> (Vec<[f32; 3]>, Vec<u32>)
+/// Generates mesh data (vertices, indices) from a noise function, leaving out the triangles whose centers are inside any of the holes
+fn mesh_data_from_noise<F>(noise_fn: F, mesh_width: u32, mesh_height: u32, vertex_subdivision: u32, offset: Vec2, holes: &[TerrainHole]) -> (Vec<[f32; 3]>, Vec<u32>)
     where F: Fn(f64, f64) -> f64 {
     let vertex_count_x = mesh_width / vertex_subdivision + 2;
     let vertex_count_z = mesh_height / vertex_subdivision + 2;
@@ -475,6 +583,19 @@ fn mesh_data_from_noise<F>(noise_fn: F, mesh_width: u32, mesh_height: u32, verte
     vertices.reverse();
     indices.reverse();
 
+    if !holes.is_empty() {
+        indices = indices.chunks(3)
+            .filter(|triangle| {
+                let center = triangle.iter()
+                    .map(|index| Vec2::new(vertices[*index as usize][0], vertices[*index as usize][2]))
+                    .sum::<Vec2>() / 3. + offset;
+                !holes.iter().any(|hole| hole.contains(center))
+            })
+            .flatten()
+            .copied()
+            .collect();
+    }
+
     (vertices, indices)
 }
 
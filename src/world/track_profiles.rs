@@ -0,0 +1,218 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+use crate::assets::DefinitionAssets;
+use crate::world::train_tracks::TrackPathPoint;
+
+/// The gauge the bogie model was built for. Bogies on other gauges are scaled laterally.
+pub(crate) const BOGIE_MODEL_GAUGE: f32 = 1.435;
+
+/// The track profiles defined in `definitions/track.profiles.ron`, mapped by name.
+#[derive(Asset, TypePath, Deserialize)]
+pub struct TrackProfileSet {
+    /// The name of the profile used when no other profile is selected.
+    pub default_profile: String,
+    pub profiles: HashMap<String, TrackProfile>,
+}
+
+/// The name of the track profile the route should be built with. Uses the default profile of the set if `None`.
+#[derive(Resource, Default)]
+pub(crate) struct TrackProfileSelection(pub(crate) Option<String>);
+
+/// The simplified PBR parameters of a track component.
+#[derive(Clone, Deserialize)]
+pub struct TrackMaterialDefinition {
+    /// The sRGB base color.
+    pub base_color: (f32, f32, f32),
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "default_roughness")]
+    pub perceptual_roughness: f32,
+}
+
+fn default_roughness() -> f32 { 0.9 }
+
+impl TrackMaterialDefinition {
+    pub fn to_standard_material(&self) -> StandardMaterial {
+        StandardMaterial {
+            base_color: Color::srgb(self.base_color.0, self.base_color.1, self.base_color.2),
+            metallic: self.metallic,
+            perceptual_roughness: self.perceptual_roughness,
+            ..default()
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TrackMaterialDefinitions {
+    pub rail: TrackMaterialDefinition,
+    pub sleeper: TrackMaterialDefinition,
+    pub ballast: TrackMaterialDefinition,
+}
+
+impl Default for TrackMaterialDefinitions {
+    fn default() -> Self {
+        Self {
+            rail: TrackMaterialDefinition { base_color: (0.45, 0.42, 0.4), metallic: 0.9, perceptual_roughness: 0.35 },
+            sleeper: TrackMaterialDefinition { base_color: (0.62, 0.6, 0.56), metallic: 0., perceptual_roughness: 0.9 },
+            ballast: TrackMaterialDefinition { base_color: (0.4, 0.37, 0.34), metallic: 0., perceptual_roughness: 1. },
+        }
+    }
+}
+
+/// The dimensions of the track components in meters. The heights are measured down from the top of the rails.
+#[derive(Resource, Clone, Deserialize)]
+#[serde(default)]
+pub struct TrackProfile {
+    /// The distance between the inner faces of the rail heads.
+    pub gauge: f32,
+    pub rail_height: f32,
+    pub rail_head_width: f32,
+    pub rail_foot_width: f32,
+    pub sleeper_length: f32,
+    pub sleeper_width: f32,
+    pub sleeper_height: f32,
+    /// The distance between the centers of successive sleepers.
+    pub sleeper_spacing: f32,
+    /// The width of the flat top of the ballast bed.
+    pub ballast_width: f32,
+    /// The horizontal run of the ballast shoulders per meter of height.
+    pub ballast_shoulder_slope: f32,
+    /// The height of the top of the rails above the route (the terrain, or the designed height inside tunnels).
+    pub track_elevation: f32,
+    /// The height of the outer rail above the inner one in curves (the superelevation).
+    /// Only used by the derailment checks for now, the rails are laid level.
+    pub cant: f32,
+    /// The number of subdivisions each track segment is sampled with.
+    pub subdivisions: u32,
+    /// The number of parallel tracks laid on the ballast bed.
+    pub tracks: u32,
+    /// The distance between the midlines of neighbouring tracks.
+    pub track_spacing: f32,
+    /// The number of track segments between crossovers on multi-track routes. Zero disables crossovers.
+    pub crossover_interval: u32,
+    pub materials: TrackMaterialDefinitions,
+}
+
+impl Default for TrackProfile {
+    /// Standard gauge single track with UIC 60 rails on concrete sleepers.
+    fn default() -> Self {
+        Self {
+            gauge: 1.435,
+            rail_height: 0.172,
+            rail_head_width: 0.072,
+            rail_foot_width: 0.15,
+            sleeper_length: 2.6,
+            sleeper_width: 0.3,
+            sleeper_height: 0.22,
+            sleeper_spacing: 0.6,
+            ballast_width: 3.6,
+            ballast_shoulder_slope: 1.5,
+            track_elevation: 1.,
+            cant: 0.1,
+            subdivisions: 20,
+            tracks: 1,
+            track_spacing: 4.5,
+            crossover_interval: 10,
+            materials: TrackMaterialDefinitions::default(),
+        }
+    }
+}
+
+impl TrackProfile {
+    /// The lateral offsets of the track midlines from the route midline.
+    pub fn track_offsets(&self) -> Vec<f32> {
+        let first_offset = (self.tracks.max(1) - 1) as f32 * self.track_spacing / -2.;
+        (0..self.tracks.max(1)).map(|i| first_offset + i as f32 * self.track_spacing).collect()
+    }
+
+    /// Returns the index of the first of the two neighbouring tracks connected by a crossover on the given segment, if there is one.
+    /// Successive crossovers connect successive pairs of tracks.
+    pub fn crossover_tracks(&self, segment_id: usize) -> Option<usize> {
+        if self.tracks < 2 || self.crossover_interval == 0 || segment_id == 0 || segment_id % self.crossover_interval as usize != 0 {
+            return None;
+        }
+
+        Some((segment_id / self.crossover_interval as usize - 1) % (self.tracks as usize - 1))
+    }
+
+    /// The lateral offsets of the rail centers from the track midline.
+    pub(crate) fn rail_offsets(&self) -> [f32; 2] {
+        let offset = (self.gauge + self.rail_head_width) / 2.;
+        [-offset, offset]
+    }
+
+    /// A simplified flat-bottom rail cross-section (foot, web and head), counterclockwise.
+    pub(crate) fn rail_profile(&self, lateral_offset: f32) -> Vec<Vec2> {
+        let foot_half_width = self.rail_foot_width / 2.;
+        let head_half_width = self.rail_head_width / 2.;
+        let web_half_width = self.rail_head_width / 4.;
+        let foot_top = -self.rail_height * 0.85;
+        let head_bottom = -self.rail_height * 0.3;
+
+        [
+            (-foot_half_width, -self.rail_height), (foot_half_width, -self.rail_height), (foot_half_width, foot_top),
+            (web_half_width, foot_top), (web_half_width, head_bottom), (head_half_width, head_bottom), (head_half_width, 0.),
+            (-head_half_width, 0.), (-head_half_width, head_bottom), (-web_half_width, head_bottom), (-web_half_width, foot_top),
+            (-foot_half_width, foot_top),
+        ]
+            .map(|(x, y)| Vec2::new(x + lateral_offset, y))
+            .to_vec()
+    }
+
+    /// The ballast bed cross-section, counterclockwise. The sleepers are half embedded in it,
+    /// and its shoulders go down below the terrain.
+    pub(crate) fn ballast_profile(&self) -> Vec<Vec2> {
+        let top = -self.rail_height - self.sleeper_height / 2.;
+        let bottom = -self.track_elevation - 0.3;
+        let top_half_width = self.ballast_width / 2.;
+        let bottom_half_width = top_half_width + (top - bottom) * self.ballast_shoulder_slope;
+
+        vec![
+            Vec2::new(-bottom_half_width, bottom), Vec2::new(bottom_half_width, bottom),
+            Vec2::new(top_half_width, top), Vec2::new(-top_half_width, top),
+        ]
+    }
+
+    /// Spaces the sleeper transforms evenly (by distance) along the given path, for a track at the given lateral offset.
+    pub(crate) fn sleeper_transforms(&self, path: &[TrackPathPoint], lateral_offset: f32) -> Vec<Transform> {
+        let mut result = Vec::new();
+        let sleeper_offset = Vec3::new(lateral_offset, -self.rail_height - self.sleeper_height / 2., 0.);
+
+        let mut next_sleeper_distance = self.sleeper_spacing / 2.;
+        let mut covered_distance = 0.;
+        for pair in path.windows(2) {
+            let length = pair[0].position.distance(pair[1].position);
+            while next_sleeper_distance <= covered_distance + length {
+                let fraction = (next_sleeper_distance - covered_distance) / length;
+                let rotation = pair[0].rotation.slerp(pair[1].rotation, fraction);
+                let position = pair[0].position.lerp(pair[1].position, fraction) + rotation * sleeper_offset;
+                result.push(Transform::from_translation(position).with_rotation(rotation));
+                next_sleeper_distance += self.sleeper_spacing;
+            }
+            covered_distance += length;
+        }
+
+        result
+    }
+}
+
+/// Sets the `TrackProfile` resource to the selected profile from the loaded profile set.
+pub(crate) fn apply_track_profile(
+    mut track_profile: ResMut<TrackProfile>,
+    selection: Res<TrackProfileSelection>,
+    definition_assets: Res<DefinitionAssets>,
+    profile_sets: Res<Assets<TrackProfileSet>>,
+) {
+    let Some(profile_set) = profile_sets.get(&definition_assets.track_profiles) else {
+        warn!("Track profiles are not loaded, using the default track profile.");
+        return;
+    };
+
+    let name = selection.0.as_ref().unwrap_or(&profile_set.default_profile);
+    if let Some(profile) = profile_set.profiles.get(name) {
+        *track_profile = profile.clone();
+    } else {
+        warn!("Unknown track profile {:?}, using the default track profile.", name);
+    }
+}
@@ -1,47 +1,219 @@
-use bevy::gltf::{Gltf, GltfMesh};
+use std::ops::RangeInclusive;
 use bevy::prelude::*;
-use bevy::utils::hashbrown::HashMap;
+use bevy::utils::hashbrown::{HashMap, HashSet};
 use bevy_extrude_mesh::bezier::{BezierCurve};
-use bevy_extrude_mesh::extrude;
-use bevy_extrude_mesh::extrude::ExtrudeShape;
-use crate::assets::{ModelAssets};
-use crate::{noise, NoiseSettings};
+use crate::{noise, NoiseSettings, Player};
+use crate::rolling_stock::components::Bogie;
 use crate::world::route_gen::Route;
-use crate::world::terrain::FAR_GRID_CHUNK_SIZE;
+use crate::world::terrain;
+use crate::world::terrain::Terrain;
+use crate::world::track_profiles::TrackProfile;
+use crate::world::utils;
+use crate::world::WorldSettings;
 
-const NUM_SUBDIVISIONS: u32 = 20;
-const TRACK_ELEVATION: f32 = 1.;
+/// The distance from the player within which the sleepers of a track segment are spawned.
+const SLEEPER_LOD_DISTANCE: f32 = 400.;
+/// The distance from the player within which the rails of a track segment are visible.
+/// Further away, only the ballast bed is rendered.
+const RAIL_LOD_DISTANCE: f32 = 1500.;
+/// The number of far grid chunks beyond the render distance after which track data is evicted.
+/// Keeps segments near the edge of the render distance from being evicted and regenerated repeatedly.
+const EVICTION_MARGIN: u32 = 1;
+/// The number of segments around each bogie whose sampled data is kept, even when they are far from the player.
+const BOGIE_SEGMENT_MARGIN: usize = 3;
+/// The number of pieces the curve of a tunnel segment is split into to measure the arc length the designed height is interpolated by.
+const TUNNEL_ARC_LENGTH_SAMPLES: u32 = 64;
+
+/// The distance-based level of detail of a placed track segment.
+#[derive(Component)]
+pub(crate) struct TrackLod {
+    /// The world position of the segment start, used to measure the distance to the player.
+    center: Vec3,
+    rails: Vec<Entity>,
+    sleeper_transforms: Vec<Transform>,
+    /// The entity with the merged mesh of the sleepers, if they are currently spawned.
+    sleepers: Option<Entity>,
+    rails_visible: bool,
+}
+
+/// The designed track heights of a segment that (at least partially) runs through a tunnel.
+/// Inside a tunnel the track does not follow the terrain, but is interpolated between the heights of its nodes.
+#[derive(Clone, Copy)]
+struct TunnelSpan {
+    start_height: f32,
+    end_height: f32,
+}
+
+impl TunnelSpan {
+    fn height_at(&self, local_t: f32) -> f32 {
+        self.start_height + (self.end_height - self.start_height) * local_t.clamp(0., 1.)
+    }
+}
 
 #[derive(Clone)]
 struct TrackSegment {
     id: usize,
     curve: BezierCurve,
     world_translation: Vec3,
+    tunnel: Option<TunnelSpan>,
+}
+
+impl TrackSegment {
+    /// Returns the height function of this segment in local coordinates, i.e. the noise function for surface segments
+    /// and the interpolated designed height for tunnel segments.
+    fn height_function(&self, noise_settings: NoiseSettings, offset: Vec3) -> Box<dyn Fn(f64, f64) -> f64> {
+        if let Some(tunnel) = self.tunnel {
+            // The height is interpolated by the share of the arc length up to the point, like the local t of `SampledTrackSegment`.
+            // The curve is relative to the segment start, so the point is projected onto the nearest piece of the sampled curve.
+            let samples: Vec<Vec2> = (0..=TUNNEL_ARC_LENGTH_SAMPLES)
+                .map(|i| self.curve.get_oriented_point(i as f32 / TUNNEL_ARC_LENGTH_SAMPLES as f32).position.xz())
+                .collect();
+            let mut lengths = vec![0.];
+            for pair in samples.windows(2) {
+                lengths.push(lengths[lengths.len() - 1] + pair[0].distance(pair[1]));
+            }
+            let total_length = lengths[lengths.len() - 1].max(f32::EPSILON);
+            Box::new(move |x: f64, y: f64| -> f64 {
+                let point = Vec2::new(x as f32, y as f32);
+                let (length, _) = samples.windows(2).zip(&lengths)
+                    .map(|(pair, start_length)| {
+                        let piece = pair[1] - pair[0];
+                        let along = ((point - pair[0]).dot(piece) / piece.length_squared().max(f32::EPSILON)).clamp(0., 1.);
+                        (start_length + along * piece.length(), point.distance_squared(pair[0] + piece * along))
+                    })
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap();
+                (tunnel.height_at(length / total_length) + offset.y) as f64
+            })
+        } else {
+            Box::new(noise::get_heightmap_function(noise_settings, offset))
+        }
+    }
+
+    /// Samples `num_samples + 1` evenly spaced points (in curve space) along the midline.
+    fn sample(&self, noise_settings: NoiseSettings, num_samples: u32) -> Vec<TrackPathPoint> {
+        let world_pos = self.world_translation;
+        let height_fn = self.height_function(noise_settings, Vec3::new(world_pos.x, 0., world_pos.z));
+
+        (0..=num_samples)
+            .map(|i| {
+                let point = self.curve.get_oriented_point(i as f32 / num_samples as f32);
+                let mut position = point.position + world_pos;
+                position.y = height_fn(point.position.x as f64, point.position.z as f64) as f32;
+
+                TrackPathPoint { position, rotation: point.rotation }
+            })
+            .collect()
+    }
+}
+
+/// A sampled point on the midline of a placed track segment, in world space.
+pub struct TrackPathPoint {
+    /// The position of the midline at the designed height (excluding the track elevation).
+    pub position: Vec3,
+    pub rotation: Quat,
 }
 
 #[derive(Resource, Default)]
-pub(crate) struct PlacementData {
-    track_shape: Option<ExtrudeShape>,
-    track_material: Option<Handle<StandardMaterial>>,
+pub struct PlacementData {
+    rail_material: Option<Handle<StandardMaterial>>,
+    sleeper_material: Option<Handle<StandardMaterial>>,
+    ballast_material: Option<Handle<StandardMaterial>>,
+    /// The mesh of a single sleeper, merged into one mesh per segment.
+    sleeper_mesh: Option<Mesh>,
 
+    /// The segments near the player. Evicted segments are rebuilt from the route nodes when needed.
     segments: Vec<TrackSegment>,
-    last_placed_segment_id: usize,
-    last_used_node_id: usize,
+    /// The id of the last segment built from the route.
+    last_segment_id: usize,
+    /// The segment entities currently placed in the world, mapped by segment id.
+    placed_segments: HashMap<usize, Entity>,
+}
+
+/// Sent when the meshes of a track segment have been placed.
+#[derive(Event)]
+pub struct TrackSegmentPlaced {
+    pub segment_id: usize,
+    /// The segment entity, whose children are despawned together with it when the segment is evicted.
+    pub entity: Entity,
+    /// The world translation of the segment entity.
+    pub translation: Vec3,
 }
 
 struct SampledTrackSegment {
     curve: BezierCurve,
     world_translation: Vec3,
+    tunnel: Option<TunnelSpan>,
+}
+
+impl SampledTrackSegment {
+    /// Returns the world position (excluding the track elevation) and rotation at the given position inside this segment.
+    fn get_point_at_local_t<F: Fn(f64, f64) -> f64>(&self, local_t: f32, height_fn: &F) -> (Vec3, Quat) {
+        let actual_t = self.curve.map(local_t);
+        let point = self.curve.get_oriented_point(actual_t);
+        let mut position = point.position + self.world_translation;
+        position.y = match self.tunnel {
+            Some(tunnel) => tunnel.height_at(local_t),
+            None => height_fn(position.x as f64, position.z as f64) as f32,
+        };
+
+        (position, point.rotation)
+    }
 }
 
 #[derive(Component, Default)]
-pub(crate) struct Track {
+pub struct Track {
     /// Used to sample the t value (used by train bogies).
+    /// Only the segments near the player or a bogie are kept, the rest is evicted and sampled again when needed.
     segments: HashMap<u32, SampledTrackSegment>,
-    last_used_segment_id: usize,
+
+    /// The number of this track on the route, counting from the leftmost track.
+    index: usize,
+    /// The lateral offset of this track from the route midline (non-zero on multi-track routes).
+    lateral_offset: f32,
+    /// The height of the top of the rails above the route.
+    elevation: f32,
+    /// The step (in t) used to sample the slope of the track.
+    slope_sample_step: f32,
+    /// The distance between the inner faces of the rails.
+    gauge: f32,
+    /// The distance between the wheel contact points on the two rails (the centers of the rail heads).
+    contact_width: f32,
+    /// The height of the outer rail above the inner one in curves.
+    cant: f32,
 }
 
 impl Track {
+    pub fn new(track_profile: &TrackProfile, index: usize) -> Self {
+        Self {
+            index,
+            lateral_offset: track_profile.track_offsets()[index],
+            elevation: track_profile.track_elevation,
+            slope_sample_step: 1. / track_profile.subdivisions as f32,
+            gauge: track_profile.gauge,
+            contact_width: track_profile.gauge + track_profile.rail_head_width,
+            cant: track_profile.cant,
+            ..default()
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn gauge(&self) -> f32 {
+        self.gauge
+    }
+
+    pub fn contact_width(&self) -> f32 {
+        self.contact_width
+    }
+
+    /// Returns the angle the track is canted at in curves.
+    pub fn cant_angle(&self) -> f32 {
+        (self.cant / self.contact_width).clamp(-1., 1.).asin()
+    }
+
     fn get_segment_at_t(&self, t: f32) -> Option<&SampledTrackSegment> {
         assert!(t >= 0., "t wasn't a positive number (shouldn't actually happen)");
         let lower_bound = t.floor() as u32;
@@ -49,22 +221,41 @@ impl Track {
         self.segments.get(&lower_bound)
     }
 
+    /// Checks whether the segment at the given t and its neighbours are sampled.
+    pub fn is_loaded_around_t(&self, t: f32) -> bool {
+        let segment_id = t.max(0.).floor() as u32;
+        (segment_id.saturating_sub(1).max(1)..=segment_id + 1).all(|id| self.segments.contains_key(&id))
+    }
+
     pub fn get_interpolated_position_at_t<F: Fn(f64, f64) -> f64>(&self, t: f32, height_fn: &F) -> Option<(Vec3, Quat)> {
-        let segment = self.get_segment_at_t(t);
-        if let Some(segment) = segment {
-            let lower_bound = t.floor();
-            let local_t = t - lower_bound;
+        self.get_position_at_t_with_offset(t, height_fn, |_| self.lateral_offset)
+    }
 
-            let actual_t = segment.curve.map(local_t);
-            let mut point = segment.curve.get_oriented_point(actual_t);
-            point.position += segment.world_translation;
-            point.position.y = height_fn(point.position.x as f64, point.position.z as f64) as f32;
-            point.position.y += TRACK_ELEVATION;
+    /// Returns the position and rotation at `t`, at the lateral offset from the route midline given by the function
+    /// of the position inside the segment in curve space.
+    fn get_position_at_t_with_offset<F: Fn(f64, f64) -> f64, O: Fn(f32) -> f32>(&self, t: f32, height_fn: &F, offset: O) -> Option<(Vec3, Quat)> {
+        let segment = self.get_segment_at_t(t)?;
+        let local_t = t - t.floor();
+        let (mut position, rotation) = segment.get_point_at_local_t(local_t, height_fn);
+        position += rotation * Vec3::new(offset(segment.curve.map(local_t)), 0., 0.);
+        position.y += self.elevation;
 
-            Some((point.position, point.rotation))
-        } else {
-            None
+        Some((position, rotation))
+    }
+
+    /// Returns the horizontal radius of the circle through three successive points from the given t on,
+    /// or infinity if the track is straight.
+    pub fn get_curve_radius_at_t<F: Fn(f64, f64) -> f64>(&self, t: f32, height_fn: &F) -> Option<f32> {
+        let step = self.slope_sample_step;
+        let a = self.get_interpolated_position_at_t(t, height_fn)?.0.xz();
+        let b = self.get_interpolated_position_at_t(t + step, height_fn)?.0.xz();
+        let c = self.get_interpolated_position_at_t(t + 2. * step, height_fn)?.0.xz();
+
+        let double_area = (b - a).perp_dot(c - a).abs();
+        if double_area < 0.001 {
+            return Some(f32::INFINITY);
         }
+        Some(a.distance(b) * b.distance(c) * c.distance(a) / (2. * double_area))
     }
 
     pub fn get_slope_angle_at_t<F: Fn(f64, f64) -> f64>(&self, t: f32, height_fn: &F) -> Option<f32> {
@@ -73,32 +264,21 @@ impl Track {
         if let Some(segment) = segment {
             let lower_bound = t.floor();
             let local_t = t - lower_bound;
+            let (this_pos, _) = segment.get_point_at_local_t(local_t, height_fn);
 
-            let actual_t = segment.curve.map(local_t);
-            let mut this_pos = segment.curve.get_oriented_point(actual_t).position;
-            this_pos += segment.world_translation;
-            this_pos.y = height_fn(this_pos.x as f64, this_pos.z as f64) as f32;
-
-            let mut new_pos;
-            let step = 1. / NUM_SUBDIVISIONS as f32;
+            let new_pos;
+            let step = self.slope_sample_step;
             let new_t = t + step;
             if new_t.floor() == lower_bound {
-                let new_local_t = new_t - new_t.floor();
-                let new_actual_t = segment.curve.map(new_local_t);
-                new_pos = segment.curve.get_oriented_point(new_actual_t).position;
-                new_pos += segment.world_translation;
+                new_pos = segment.get_point_at_local_t(new_t - new_t.floor(), height_fn).0;
             } else {
                 let new_segment = self.get_segment_at_t(new_t);
                 if let Some(new_segment) = new_segment {
-                    let new_local_t = new_t - new_t.floor();
-                    let new_actual_t = new_segment.curve.map(new_local_t);
-                    new_pos = new_segment.curve.get_oriented_point(new_actual_t).position;
-                    new_pos += new_segment.world_translation;
+                    new_pos = new_segment.get_point_at_local_t(new_t - new_t.floor(), height_fn).0;
                 } else {
                     return None;
                 }
             }
-            new_pos.y = height_fn(new_pos.x as f64, new_pos.z as f64) as f32;
 
             let sine = (new_pos.y - this_pos.y) / Vec3::distance(this_pos, new_pos);
             Some(sine.asin())
@@ -108,106 +288,369 @@ impl Track {
     }
 }
 
+/// A crossover between two neighbouring tracks, spanning a whole track segment. Its diverging rails lead from the first
+/// track at the start of the segment to the second track at its end, so only bogies running forward on the first track
+/// or backward on the second one can take it.
+/// Crossovers are standalone entities, so their switch state outlives the eviction of the segment meshes.
+#[derive(Component)]
+pub struct Crossover {
+    /// The id of the segment (the integer part of t) the crossover spans.
+    pub segment_id: usize,
+    /// The track entities the crossover connects.
+    pub tracks: [Entity; 2],
+    /// Whether the switches are set to lead bogies onto the other track.
+    pub diverging: bool,
+}
+
+impl Crossover {
+    pub fn connects(&self, track: Entity) -> bool {
+        self.tracks.contains(&track)
+    }
+
+    /// Whether any of the bogies is inside the segment of the crossover on one of its tracks.
+    /// The switches can't be thrown under a bogie.
+    pub fn is_occupied<'a>(&self, mut bogies: impl Iterator<Item = &'a Bogie>) -> bool {
+        bogies.any(|bogie| {
+            bogie.position_on_track.floor() as usize == self.segment_id && bogie.current_track.is_some_and(|track| self.connects(track))
+        })
+    }
+
+    /// Returns the position and rotation of the midline of the diverging rails at `t`.
+    fn get_diverging_position_at_t<F: Fn(f64, f64) -> f64>(&self, first_track: &Track, second_track: &Track, t: f32, height_fn: &F) -> Option<(Vec3, Quat)> {
+        let offsets = [first_track.lateral_offset, second_track.lateral_offset];
+        first_track.get_position_at_t_with_offset(t, height_fn, |curve_t| crossover_offset(offsets, curve_t))
+    }
+}
+
+/// Returns the lateral offset of the diverging rails of a crossover from the route midline, given the offsets of the
+/// tracks it connects and the position inside the segment in curve space.
+fn crossover_offset(track_offsets: [f32; 2], curve_t: f32) -> f32 {
+    let weight = curve_t * curve_t * (3. - 2. * curve_t); // smoothstep
+    track_offsets[0] + (track_offsets[1] - track_offsets[0]) * weight
+}
+
+/// Returns the path of the diverging rails of a crossover along the sampled midline of its segment.
+fn crossover_path(path: &[TrackPathPoint], track_offsets: [f32; 2]) -> Vec<TrackPathPoint> {
+    path.iter().enumerate()
+        .map(|(i, point)| {
+            let offset = crossover_offset(track_offsets, i as f32 / (path.len() - 1) as f32);
+            TrackPathPoint { position: point.position + point.rotation * Vec3::new(offset, 0., 0.), rotation: point.rotation }
+        })
+        .collect()
+}
+
+/// Follows the track from `old_t` to `new_t` across the segment boundaries, taking the diverging crossovers whose switches
+/// are met from the front, i.e. at the start of the segment on the first track or at its end on the second track.
+/// Returns the track and whether the position at `new_t` is on the diverging rails of a crossover.
+pub fn follow_track(crossovers: &Query<&Crossover>, mut track: Entity, mut on_crossover: bool, old_t: f32, new_t: f32) -> (Entity, bool) {
+    let crossover_at = |segment_id: i64, track: Entity| {
+        crossovers.iter().find(|crossover| crossover.segment_id as i64 == segment_id && crossover.connects(track))
+    };
+
+    let mut segment_id = old_t.floor() as i64;
+    let new_segment_id = new_t.floor() as i64;
+    while segment_id != new_segment_id {
+        let forward = new_segment_id > segment_id;
+        // The diverging rails end on the second track at the end of the segment, and on the first one at its start
+        if on_crossover {
+            if let Some(crossover) = crossover_at(segment_id, track) {
+                track = if forward { crossover.tracks[1] } else { crossover.tracks[0] };
+            }
+            on_crossover = false;
+        }
+
+        segment_id += if forward { 1 } else { -1 };
+        if let Some(crossover) = crossover_at(segment_id, track) {
+            let facing_track = if forward { crossover.tracks[0] } else { crossover.tracks[1] };
+            on_crossover = crossover.diverging && track == facing_track;
+        }
+    }
+
+    (track, on_crossover)
+}
+
+/// Returns the position and rotation of the rail midline at `t` on the given track,
+/// or on the diverging rails of the crossover in the segment of `t` if `on_crossover` is set.
+pub fn get_line_position_at_t<F: Fn(f64, f64) -> f64>(
+    tracks: &Query<&Track>,
+    crossovers: &Query<&Crossover>,
+    track_entity: Entity,
+    on_crossover: bool,
+    t: f32,
+    height_fn: &F,
+) -> Option<(Vec3, Quat)> {
+    let track = tracks.get(track_entity).ok()?;
+    let crossover = crossovers.iter()
+        .find(|crossover| on_crossover && crossover.segment_id == t.floor() as usize && crossover.connects(track_entity));
+    let Some(crossover) = crossover else {
+        return track.get_interpolated_position_at_t(t, height_fn);
+    };
+
+    let first_track = tracks.get(crossover.tracks[0]).ok()?;
+    let second_track = tracks.get(crossover.tracks[1]).ok()?;
+    crossover.get_diverging_position_at_t(first_track, second_track, t, height_fn)
+}
+
+/// Returns the position and rotation of the rail midline at `t` for the bogie, following the track and the crossovers
+/// from the current position of the bogie.
+pub fn get_bogie_line_position_at_t<F: Fn(f64, f64) -> f64>(
+    tracks: &Query<&Track>,
+    crossovers: &Query<&Crossover>,
+    bogie: &Bogie,
+    t: f32,
+    height_fn: &F,
+) -> Option<(Vec3, Quat)> {
+    let (track, on_crossover) = follow_track(crossovers, bogie.current_track?, bogie.on_crossover, bogie.position_on_track, t);
+    get_line_position_at_t(tracks, crossovers, track, on_crossover, t, height_fn)
+}
+
 impl PlacementData {
-    fn current_segment_id(&self) -> usize {
-        if self.segments.is_empty() {
-            0
-        } else {
-            self.segments[self.segments.len() - 1].id
+    pub fn current_segment_id(&self) -> usize {
+        self.last_segment_id
+    }
+
+    /// Returns the ids of the segments that can be built from the kept route nodes.
+    pub fn segment_ids(&self, route: &Route) -> RangeInclusive<usize> {
+        (route.first_id() + 1)..=self.last_segment_id
+    }
+
+    /// Makes sure the segment with the given id is loaded, rebuilding it from the route if it was evicted.
+    /// Returns false if the segment has not been built yet.
+    fn ensure_segment(&mut self, route: &Route, id: usize) -> bool {
+        if id == 0 || id > self.last_segment_id {
+            return false;
         }
+        if self.segments.iter().any(|seg| seg.id == id) {
+            return true;
+        }
+
+        match build_track_segment(route, id) {
+            Some(segment) => {
+                self.segments.push(segment);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Builds the segment after the last built one from the route. Returns its id, or `None` if the route is not that long yet.
+    fn build_next_segment(&mut self, route: &Route) -> Option<usize> {
+        let id = self.last_segment_id + 1;
+        self.segments.push(build_track_segment(route, id)?);
+        self.last_segment_id = id;
+        Some(id)
+    }
+
+    /// Continues building the segments after the given one (e.g. after the route was restored from a save).
+    /// The segments up to it are rebuilt from the route when they are needed.
+    pub(crate) fn restore(&mut self, last_segment_id: usize) {
+        self.segments.clear();
+        self.last_segment_id = last_segment_id;
+    }
+
+    pub fn is_tunnel_segment(&self, id: usize) -> bool {
+        self.segments.iter().any(|seg| seg.id == id && seg.tunnel.is_some())
+    }
+
+    /// Returns the ids of the loaded segments that run through a tunnel.
+    pub fn tunnel_segment_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.segments.iter().filter(|seg| seg.tunnel.is_some()).map(|seg| seg.id)
+    }
+
+    /// Samples `num_samples + 1` evenly spaced points (in curve space) along the midline of a segment.
+    pub fn sample_segment(&self, id: usize, noise_settings: NoiseSettings, num_samples: u32) -> Option<Vec<TrackPathPoint>> {
+        let segment = self.segments.iter().find(|seg| seg.id == id)?;
+        Some(segment.sample(noise_settings, num_samples))
     }
 }
 
-pub(crate) fn spawn_track_entity(
+pub(crate) fn spawn_track_entities(
     mut commands: Commands,
+    track_profile: Res<TrackProfile>,
 ) {
-    commands.spawn_empty()
-        .insert(Track::default());
+    for index in 0..track_profile.track_offsets().len() {
+        commands.spawn_empty()
+            .insert(Track::new(&track_profile, index));
+    }
 }
 
 pub(crate) fn setup_track_data(
     mut data_res: ResMut<PlacementData>,
-    model_assets: Res<ModelAssets>,
-
-    meshes: Res<Assets<Mesh>>,
-    gltf_assets: Res<Assets<Gltf>>,
-    gltf_mesh_assets: Res<Assets<GltfMesh>>,
+    track_profile: Res<TrackProfile>,
 ) {
-    if let Some(gltf) = gltf_assets.get(&model_assets.track_cross_section) {
-        let track_gltf_mesh = gltf_mesh_assets.get(&gltf.meshes[0]).unwrap();
-        let track_mesh = meshes.get(&track_gltf_mesh.primitives[0].mesh).unwrap();
-        let extrude_shape = ExtrudeShape::from_mesh(track_mesh);
-        data_res.track_shape = Some(extrude_shape);
-    }
+    let sleeper_mesh = Cuboid::new(track_profile.sleeper_length, track_profile.sleeper_height, track_profile.sleeper_width);
+    data_res.sleeper_mesh = Some(sleeper_mesh.into());
 }
 
 pub(crate) fn setup_track_material(
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut data_res: ResMut<PlacementData>,
+    track_profile: Res<TrackProfile>,
 ) {
-    let material = StandardMaterial {
-        ..default()
-    };
-    let handle = materials.add(material);
-
-    data_res.track_material = Some(handle);
+    let definitions = &track_profile.materials;
+    data_res.rail_material = Some(materials.add(definitions.rail.to_standard_material()));
+    data_res.sleeper_material = Some(materials.add(definitions.sleeper.to_standard_material()));
+    data_res.ballast_material = Some(materials.add(definitions.ballast.to_standard_material()));
 }
 
-pub(crate) fn update_track_entity(
+/// Samples the segments needed by each track (the ones near the player or a bogie), one segment per track per run,
+/// and evicts the sampled segments that are no longer needed.
+pub(crate) fn update_track_entities(
     mut track_query: Query<&mut Track>,
-    placement_data_res: Res<PlacementData>,
+    mut placement_data_res: ResMut<PlacementData>,
+    route_res: Res<Route>,
     noise_settings: Res<NoiseSettings>,
+    player_query: Query<&Transform, With<Player>>,
+    bogie_query: Query<&Bogie>,
+    settings: Res<WorldSettings>,
 ) {
-    if placement_data_res.segments.is_empty() {
+    if placement_data_res.current_segment_id() == 0 {
         return;
     }
+    let Some(player_chunk) = get_player_chunk(&settings, &player_query) else {
+        return;
+    };
+    let segments_near_bogies = get_segments_near_bogies(&bogie_query);
 
-    // TODO: add support for multiple tracks
-    let mut track = track_query.single_mut();
+    let segment_ids = placement_data_res.segment_ids(&route_res);
+    for mut track in &mut track_query {
+        track.segments.retain(|id, _| {
+            segments_near_bogies.contains(&(*id as usize))
+                || is_segment_in_range(&settings, &route_res, *id as usize, &player_chunk, settings.far_render_distance + EVICTION_MARGIN)
+        });
 
-    if placement_data_res.current_segment_id() <= track.last_used_segment_id {
-        return;
+        let id_to_sample = segment_ids.clone().find(|id| {
+            !track.segments.contains_key(&(*id as u32))
+                && (segments_near_bogies.contains(id) || is_segment_in_range(&settings, &route_res, *id, &player_chunk, settings.far_render_distance))
+        });
+        let Some(id_to_sample) = id_to_sample else {
+            continue;
+        };
+
+        if !sample_track_segment(&mut track, &mut placement_data_res, &route_res, id_to_sample, *noise_settings) {
+            warn!("Could not build track segment {} from the route", id_to_sample);
+        }
     }
+}
 
-    let segment = placement_data_res.segments.iter().find(|seg| seg.id == track.last_used_segment_id + 1);
-    if segment.is_none() {
-        println!("update_track_entity: no segment with id of track.last_used_segment_id + 1");
-        return;
+/// Samples the segment with the given id onto the track, building it from the route if needed.
+/// Returns false if the segment can't be built.
+fn sample_track_segment(track: &mut Track, placement_data: &mut PlacementData, route: &Route, id: usize, noise_settings: NoiseSettings) -> bool {
+    if !placement_data.ensure_segment(route, id) {
+        return false;
     }
-    let mut cloned_segment = segment.unwrap().clone();
+    let mut cloned_segment = placement_data.segments.iter().find(|seg| seg.id == id).unwrap().clone();
     let world_pos = cloned_segment.world_translation;
-    let height_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings.clone(), Vec3::new(world_pos.x, -world_pos.y + 0.3, world_pos.z));
+    let height_fn = cloned_segment.height_function(noise_settings, Vec3::new(world_pos.x, -world_pos.y + 0.3, world_pos.z));
 
     cloned_segment.curve.calculate_arc_lengths_with_custom_height_function(&height_fn);
 
     let sampled_segment = SampledTrackSegment {
         curve: cloned_segment.curve,
         world_translation: cloned_segment.world_translation,
+        tunnel: cloned_segment.tunnel,
     };
     track.segments.insert(cloned_segment.id as u32, sampled_segment);
-    track.last_used_segment_id += 1;
+    true
+}
+
+/// Returns the ids of the segments around the bogies, which are sampled and kept even when they are far from the player.
+fn get_segments_near_bogies(bogie_query: &Query<&Bogie>) -> HashSet<usize> {
+    bogie_query.iter()
+        .flat_map(|bogie| {
+            let bogie_segment = bogie.position_on_track.max(0.).floor() as usize;
+            bogie_segment.saturating_sub(BOGIE_SEGMENT_MARGIN)..=bogie_segment + BOGIE_SEGMENT_MARGIN
+        })
+        .collect()
+}
+
+/// Generates the route, builds the segments and samples them on every track around the bogies before each physics step.
+/// The loading in `Update` only advances by a node and a segment per frame, so otherwise the track the physics runs on
+/// would depend on the frame rate.
+pub(crate) fn load_track_around_bogies(
+    mut commands: Commands,
+    mut track_query: Query<(Entity, &mut Track)>,
+    mut placement_data: ResMut<PlacementData>,
+    mut route: ResMut<Route>,
+    bogie_query: Query<&Bogie>,
+    noise_settings: Res<NoiseSettings>,
+    settings: Res<WorldSettings>,
+    track_profile: Res<TrackProfile>,
+) {
+    let segments_near_bogies = get_segments_near_bogies(&bogie_query);
+    let Some(&last_needed) = segments_near_bogies.iter().max() else {
+        return;
+    };
+
+    // A segment is built from the two nodes after its start too
+    while route.id_counter <= last_needed + 2 {
+        route.extend(*noise_settings, &settings.route);
+    }
+    let tracks: Vec<(Entity, usize)> = track_query.iter().map(|(entity, track)| (entity, track.index())).collect();
+    while placement_data.last_segment_id < last_needed {
+        let Some(id) = placement_data.build_next_segment(&route) else {
+            break;
+        };
+        spawn_crossover(&mut commands, &track_profile, id, &tracks);
+    }
+
+    let segment_ids = placement_data.segment_ids(&route);
+    for (_, mut track) in &mut track_query {
+        for id in segments_near_bogies.iter().filter(|id| segment_ids.contains(id)) {
+            if !track.segments.contains_key(&(*id as u32)) && !sample_track_segment(&mut track, &mut placement_data, &route, *id, *noise_settings) {
+                warn!("Could not build track segment {} from the route", id);
+            }
+        }
+    }
 }
 
-// Updates the placement data one TrackSegment per run.
+// Builds the next TrackSegment from the route one per run, and spawns its crossover if there is one.
 pub(crate) fn update_placement_data(
+    mut commands: Commands,
     mut data_res: ResMut<PlacementData>,
     route_res: Res<Route>,
+    track_profile: Res<TrackProfile>,
+    track_query: Query<(Entity, &Track)>,
 ) {
-    let id_to_add = if data_res.last_used_node_id == 0 { 2 } else { data_res.last_used_node_id + 1 };
-    if route_res.get_last_id() <= 2 || route_res.get_last_id() == data_res.last_used_node_id || route_res.get_point(id_to_add + 1).is_none() {
+    if let Some(id) = data_res.build_next_segment(&route_res) {
+        let tracks: Vec<(Entity, usize)> = track_query.iter().map(|(entity, track)| (entity, track.index())).collect();
+        spawn_crossover(&mut commands, &track_profile, id, &tracks);
+    }
+}
+
+/// Lays a crossover on the segment with the given id between a pair of neighbouring tracks every `crossover_interval` segments.
+/// The tracks are given as (entity, index).
+fn spawn_crossover(commands: &mut Commands, track_profile: &TrackProfile, segment_id: usize, tracks: &[(Entity, usize)]) {
+    let Some(first_track_index) = track_profile.crossover_tracks(segment_id) else {
         return;
+    };
+    let find_track = |index: usize| tracks.iter().find(|(_, track_index)| *track_index == index).map(|(entity, _)| *entity);
+    if let (Some(first_track), Some(second_track)) = (find_track(first_track_index), find_track(first_track_index + 1)) {
+        commands.spawn(Crossover {
+            segment_id,
+            tracks: [first_track, second_track],
+            diverging: false,
+        });
+    }
+}
+
+/// Builds the segment with the given id from the route nodes. The segment starts at the node with the same id.
+/// Returns `None` if the route does not have enough nodes yet.
+fn build_track_segment(route: &Route, id: usize) -> Option<TrackSegment> {
+    if id == 0 {
+        return None;
     }
 
     // We need at least four points to get the direction stuff right.
-    let next_node = route_res.get_point(id_to_add + 1).unwrap();
-    let new_node = route_res.get_point(id_to_add).unwrap();
-    let last_node = route_res.get_point(id_to_add - 1).unwrap();
-    let previous_node = route_res.get_point(id_to_add - 2).unwrap();
+    let next_node = *route.get_point(id + 2)?;
+    let new_node = *route.get_point(id + 1)?;
+    let last_node = *route.get_point(id)?;
+    let previous_node = *route.get_point(id - 1)?;
 
     // Calculate the bezier points (the vertices will be positioned relative to zero)
     let mut bezier_start = Vec3::ZERO;
-    let mut bezier_end = new_node.clone() - last_node.clone();
-    let (mut bezier_control1, mut bezier_control2) = find_control_points(last_node.clone(), new_node.clone(), Some(previous_node.clone()), Some(next_node.clone()), last_node.clone());
+    let mut bezier_end = new_node - last_node;
+    let (mut bezier_control1, mut bezier_control2) = find_control_points(last_node, new_node, Some(previous_node), Some(next_node), last_node);
 
     bezier_start.y = 0.;
     bezier_end.y = 0.;
@@ -215,51 +658,232 @@ pub(crate) fn update_placement_data(
     bezier_control2.y = 0.;
     let bezier_curve = BezierCurve::new(vec![bezier_start, bezier_control1, bezier_control2, bezier_end], None);
 
-    // Push the new segment to the resource
-    let segment = TrackSegment {
-        id: data_res.current_segment_id() + 1,
+    let tunnel = if route.is_tunnel_segment(id) {
+        Some(TunnelSpan { start_height: last_node.y, end_height: new_node.y })
+    } else {
+        None
+    };
+
+    Some(TrackSegment {
+        id,
         curve: bezier_curve,
-        world_translation: last_node.clone(),
+        world_translation: last_node,
+        tunnel,
+    })
+}
+
+/// Samples the midline of the segment with the given id like `PlacementData::sample_segment`, but builds the segment
+/// from the route, so that segments far from the player can be sampled too. Returns `None` if the route is not that long yet.
+pub fn sample_route_segment(route: &Route, id: usize, noise_settings: NoiseSettings, num_samples: u32) -> Option<Vec<TrackPathPoint>> {
+    Some(build_track_segment(route, id)?.sample(noise_settings, num_samples))
+}
+
+/// Checks whether the segment with the given id starts at most `distance` far grid chunks away from the player chunk.
+fn is_segment_in_range(settings: &WorldSettings, route: &Route, id: usize, player_chunk: &IVec2, distance: u32) -> bool {
+    route.get_point(id)
+        .is_some_and(|point| terrain::is_within_far_chunk_distance(settings, &point.xz(), player_chunk, distance))
+}
+
+/// Returns the far grid chunk of the player, if there is a player.
+fn get_player_chunk(settings: &WorldSettings, player_query: &Query<&Transform, With<Player>>) -> Option<IVec2> {
+    let player_translation = player_query.get_single().ok()?.translation;
+    Some(terrain::get_far_chunk_position(settings, player_translation.xz()))
+}
+
+/// Despawns the placed track segments and drops the segment data outside of the render distance (plus `EVICTION_MARGIN`).
+/// They are rebuilt from the route when the player returns. The route nodes and the crossovers before the oldest segment
+/// still in range or near a bogie are dropped for good, and so are the terrain holes outside of the eviction distance.
+pub(crate) fn evict_track_data(
+    mut commands: Commands,
+    mut placement_data: ResMut<PlacementData>,
+    mut route_res: ResMut<Route>,
+    mut terrain: ResMut<Terrain>,
+    crossover_query: Query<(Entity, &Crossover)>,
+    bogie_query: Query<&Bogie>,
+    player_query: Query<&Transform, With<Player>>,
+    settings: Res<WorldSettings>,
+) {
+    let Some(player_chunk) = get_player_chunk(&settings, &player_query) else {
+        return;
     };
-    data_res.segments.push(segment);
-    data_res.last_used_node_id = id_to_add;
+    let eviction_distance = settings.far_render_distance + EVICTION_MARGIN;
+
+    let first_in_range = placement_data.segment_ids(&route_res)
+        .find(|id| is_segment_in_range(&settings, &route_res, *id, &player_chunk, eviction_distance));
+    let first_near_bogie = bogie_query.iter()
+        .filter(|bogie| bogie.current_track.is_some())
+        .map(|bogie| (bogie.position_on_track.max(0.).floor() as usize).saturating_sub(BOGIE_SEGMENT_MARGIN))
+        .min();
+    if let Some(oldest_needed) = first_in_range.into_iter().chain(first_near_bogie).min() {
+        // A segment is built from the node before its start too
+        route_res.evict_before(oldest_needed.saturating_sub(1));
+        for (entity, crossover) in &crossover_query {
+            if crossover.segment_id <= route_res.first_id() {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+    // The holes are cut again when their tunnel segment is placed. The portals of a kept segment can lie past its start, hence the extra chunk.
+    terrain.retain_holes(|hole| terrain::is_within_far_chunk_distance(&settings, &hole.center, &player_chunk, eviction_distance + 1));
+
+    placement_data.placed_segments.retain(|id, entity| {
+        let keep = is_segment_in_range(&settings, &route_res, *id, &player_chunk, eviction_distance);
+        if !keep {
+            commands.entity(*entity).despawn_recursive();
+        }
+        keep
+    });
+    placement_data.segments.retain(|seg| is_segment_in_range(&settings, &route_res, seg.id, &player_chunk, eviction_distance));
 }
 
 pub(crate) fn place_tracks(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut placement_data: ResMut<PlacementData>,
+    track_profile: Res<TrackProfile>,
     noise_settings: Res<NoiseSettings>,
+    route_res: Res<Route>,
+    player_query: Query<&Transform, With<Player>>,
+    mut placed_events: EventWriter<TrackSegmentPlaced>,
+    settings: Res<WorldSettings>,
 ) {
-    if placement_data.track_shape.is_none() || placement_data.track_material.is_none() {
+    if placement_data.rail_material.is_none() || placement_data.ballast_material.is_none() {
         return;
     }
-    if placement_data.last_placed_segment_id + 1 > placement_data.current_segment_id() {
+
+    // Place the first segment within the render distance that is not placed yet
+    let Some(player_chunk) = get_player_chunk(&settings, &player_query) else {
+        return;
+    };
+    let id_to_place = placement_data.segment_ids(&route_res).find(|id| {
+        !placement_data.placed_segments.contains_key(id) && is_segment_in_range(&settings, &route_res, *id, &player_chunk, settings.far_render_distance)
+    });
+    let Some(id_to_place) = id_to_place else {
+        return;
+    };
+    if !placement_data.ensure_segment(&route_res, id_to_place) {
         return;
     }
 
-    let id_to_place = placement_data.last_placed_segment_id + 1;
+    // Sample the path relative to the segment start, with the top of the rails at zero height
+    let world_pos = placement_data.segments.iter().find(|seg| seg.id == id_to_place).unwrap().world_translation;
+    let path: Vec<TrackPathPoint> = placement_data.sample_segment(id_to_place, noise_settings.clone(), track_profile.subdivisions).unwrap()
+        .into_iter()
+        .map(|point| TrackPathPoint { position: point.position - world_pos, rotation: point.rotation })
+        .collect();
 
-    let segment = placement_data.segments.iter().find(|seg| seg.id == id_to_place).unwrap();
+    let mut translation = world_pos;
+    translation.y += track_profile.track_elevation;
 
-    // Generate the path using the noise function as the height function
-    let world_pos = segment.world_translation;
-    let height_fn = noise::get_heightmap_function(FAR_GRID_CHUNK_SIZE as f32, noise_settings.clone(), Vec3::new(world_pos.x, -world_pos.y, world_pos.z));
-    let path = segment.curve.generate_path_with_custom_height_function(NUM_SUBDIVISIONS, height_fn);
+    let ballast_mesh = utils::extrude_profile(&track_profile.ballast_profile(), true, &path);
+    let mut rail_meshes = Vec::new();
+    let mut sleeper_transforms = Vec::new();
+    for track_offset in track_profile.track_offsets() {
+        for rail_offset in track_profile.rail_offsets() {
+            rail_meshes.push(utils::extrude_profile(&track_profile.rail_profile(track_offset + rail_offset), true, &path));
+        }
+        sleeper_transforms.extend(track_profile.sleeper_transforms(&path, track_offset));
+    }
 
-    let mut translation = segment.world_translation;
-    translation.y += TRACK_ELEVATION;
+    // Lay the rails of the crossover, if there is one on this segment
+    let track_offsets = track_profile.track_offsets();
+    if let Some(first_track_index) = track_profile.crossover_tracks(id_to_place) {
+        let crossover_offsets = [track_offsets[first_track_index], track_offsets[first_track_index + 1]];
+        let crossover_path = crossover_path(&path, crossover_offsets);
+        for rail_offset in track_profile.rail_offsets() {
+            rail_meshes.push(utils::extrude_profile(&track_profile.rail_profile(rail_offset), true, &crossover_path));
+        }
+        sleeper_transforms.extend(track_profile.sleeper_transforms(&crossover_path, 0.));
+    }
+
+    let mut rails = Vec::new();
+    let mut segment_entity = commands.spawn(SpatialBundle::from_transform(Transform::from_translation(translation)));
+    segment_entity.with_children(|parent| {
+        parent.spawn(PbrBundle {
+            mesh: meshes.add(ballast_mesh),
+            material: placement_data.ballast_material.clone().unwrap(),
+            ..default()
+        });
+        for rail_mesh in rail_meshes {
+            let rail = parent.spawn(PbrBundle {
+                mesh: meshes.add(rail_mesh),
+                material: placement_data.rail_material.clone().unwrap(),
+                ..default()
+            }).id();
+            rails.push(rail);
+        }
+    });
+    segment_entity.insert(TrackLod {
+        center: world_pos,
+        rails,
+        sleeper_transforms,
+        sleepers: None,
+        rails_visible: true,
+    });
 
-    let mesh = extrude::extrude(placement_data.track_shape.as_ref().unwrap(), &path);
-    let handle = meshes.add(mesh);
-    commands.spawn(PbrBundle {
-        mesh: handle,
-        material: placement_data.track_material.clone().unwrap(),
-        transform: Transform::from_translation(translation),
-        ..default()
+    placement_data.placed_segments.insert(id_to_place, segment_entity.id());
+    placed_events.send(TrackSegmentPlaced {
+        segment_id: id_to_place,
+        entity: segment_entity.id(),
+        translation,
     });
+}
+
+/// Spawns and despawns the sleepers, and shows and hides the rails, depending on the distance of each track segment to the player.
+pub(crate) fn update_track_lod(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    placement_data: Res<PlacementData>,
+    player_query: Query<&Transform, With<Player>>,
+    mut lod_query: Query<(Entity, &mut TrackLod)>,
+    mut visibility_query: Query<&mut Visibility>,
+) {
+    let (Some(sleeper_mesh), Some(sleeper_material)) = (&placement_data.sleeper_mesh, &placement_data.sleeper_material) else {
+        return;
+    };
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
+    let player_position = player_transform.translation;
+
+    for (segment_entity, mut lod) in &mut lod_query {
+        let distance = lod.center.distance(player_position);
+
+        let sleepers_needed = distance <= SLEEPER_LOD_DISTANCE;
+        if sleepers_needed && lod.sleepers.is_none() {
+            // One mesh per segment, so that the sleepers take one draw call instead of one each
+            let sleepers_mesh = lod.sleeper_transforms.iter()
+                .map(|transform| sleeper_mesh.clone().transformed_by(*transform))
+                .reduce(|mut merged, sleeper| {
+                    merged.merge(&sleeper);
+                    merged
+                });
+            if let Some(sleepers_mesh) = sleepers_mesh {
+                let sleepers = commands.spawn(PbrBundle {
+                        mesh: meshes.add(sleepers_mesh),
+                        material: sleeper_material.clone(),
+                        ..default()
+                    })
+                    .set_parent(segment_entity)
+                    .id();
+                lod.sleepers = Some(sleepers);
+            }
+        } else if !sleepers_needed {
+            if let Some(sleepers) = lod.sleepers.take() {
+                commands.entity(sleepers).despawn_recursive();
+            }
+        }
 
-    placement_data.last_placed_segment_id = id_to_place;
+        let rails_visible = distance <= RAIL_LOD_DISTANCE;
+        if rails_visible != lod.rails_visible {
+            for rail in &lod.rails {
+                if let Ok(mut visibility) = visibility_query.get_mut(*rail) {
+                    *visibility = if rails_visible { Visibility::Inherited } else { Visibility::Hidden };
+                }
+            }
+            lod.rails_visible = rails_visible;
+        }
+    }
 }
 
 fn find_control_points(start: Vec3, end: Vec3, previous: Option<Vec3>, next: Option<Vec3>, centered_at: Vec3) -> (Vec3, Vec3) {
@@ -298,3 +922,228 @@ fn line_properties(point1: Vec2, point2: Vec2) -> (f32, f32) {
         f32::atan2(length_y, length_x), //angle
     )
 }
+
+/// Spawns the tracks of the profile along a route through the given points, with every segment sampled.
+/// The route is laid level (like in a tunnel), so that the heights don't depend on the terrain.
+#[cfg(test)]
+pub(crate) fn spawn_level_tracks(world: &mut World, profile: &TrackProfile, points: &[Vec3]) -> Vec<Entity> {
+    let mut route = Route::default();
+    route.restore(0, points.to_vec(), vec![true; points.len()]);
+
+    let segments: Vec<TrackSegment> = (1..points.len() - 2).map(|id| build_track_segment(&route, id).unwrap()).collect();
+    (0..profile.track_offsets().len())
+        .map(|index| {
+            let mut track = Track::new(profile, index);
+            for segment in &segments {
+                let mut curve = segment.curve.clone();
+                curve.calculate_arc_lengths_with_custom_height_function(&|_, _| 0.);
+                let sampled_segment = SampledTrackSegment { curve, world_translation: segment.world_translation, tunnel: segment.tunnel };
+                track.segments.insert(segment.id as u32, sampled_segment);
+            }
+            world.spawn(track).id()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use super::*;
+
+    const CROSSOVER_SEGMENT: usize = 2;
+    const PATH_SAMPLES: u32 = 200;
+    /// The accepted distance of the bogies from the laid rails in m.
+    const TOLERANCE: f32 = 0.01;
+
+    struct DoubleTrack {
+        profile: TrackProfile,
+        segments: Vec<TrackSegment>,
+        tracks: [Entity; 2],
+    }
+
+    impl DoubleTrack {
+        /// Spawns two level tracks on a curved route with a diverging crossover in the middle segment.
+        fn spawn(world: &mut World) -> Self {
+            let points: Vec<Vec3> = (0..7)
+                .map(|i| Vec3::new((i as f32 * 0.15).sin(), 0., 1. - (i as f32 * 0.15).cos()) * 400.)
+                .collect();
+            let mut route = Route::default();
+            route.restore(0, points.clone(), vec![true; points.len()]);
+
+            let profile = TrackProfile { tracks: 2, ..default() };
+            let segments: Vec<TrackSegment> = (1..=3).map(|id| build_track_segment(&route, id).unwrap()).collect();
+            let tracks: [Entity; 2] = spawn_level_tracks(world, &profile, &points).try_into().unwrap();
+            world.spawn(Crossover { segment_id: CROSSOVER_SEGMENT, tracks, diverging: true });
+
+            Self { profile, segments, tracks }
+        }
+
+        /// The laid rail midline of the segments at the given lateral offset (a function of the position in curve space).
+        fn laid_path(&self, segment_ids: &[usize], offset: impl Fn(f32) -> f32) -> Vec<Vec3> {
+            self.segments.iter()
+                .filter(|segment| segment_ids.contains(&segment.id))
+                .flat_map(|segment| {
+                    let path = segment.sample(NoiseSettings::default(), PATH_SAMPLES);
+                    let count = path.len();
+                    path.into_iter().enumerate().map(move |(i, point)| (point, i as f32 / (count - 1) as f32))
+                })
+                .map(|(point, curve_t)| point.position + point.rotation * Vec3::new(offset(curve_t), 0., 0.) + Vec3::Y * self.profile.track_elevation)
+                .collect()
+        }
+
+        fn track_path(&self, index: usize) -> Vec<Vec3> {
+            let offset = self.profile.track_offsets()[index];
+            self.laid_path(&[1, 2, 3], |_| offset)
+        }
+
+        fn diverging_path(&self) -> Vec<Vec3> {
+            let offsets = self.profile.track_offsets();
+            let crossover_path = crossover_path(&self.segments[CROSSOVER_SEGMENT - 1].sample(NoiseSettings::default(), PATH_SAMPLES), [offsets[0], offsets[1]]);
+            crossover_path.iter().map(|point| point.position + Vec3::Y * self.profile.track_elevation).collect()
+        }
+    }
+
+    /// Moves a bogie through the given t values, returning its positions and the track it ends up on.
+    fn run_bogie(world: &mut World, track: Entity, t_values: Vec<f32>) -> (Vec<(f32, Vec3)>, Entity) {
+        world.run_system_once(move |tracks: Query<&Track>, crossovers: Query<&Crossover>| {
+            let mut bogie = Bogie { current_track: Some(track), position_on_track: t_values[0], ..default() };
+            let mut positions = Vec::new();
+            for t in &t_values {
+                let (track, on_crossover) = follow_track(&crossovers, bogie.current_track.unwrap(), bogie.on_crossover, bogie.position_on_track, *t);
+                bogie = Bogie { current_track: Some(track), position_on_track: *t, on_crossover, ..default() };
+                let (position, _) = get_line_position_at_t(&tracks, &crossovers, track, on_crossover, *t, &|_, _| 0.).unwrap();
+                positions.push((*t, position));
+            }
+            (positions, bogie.current_track.unwrap())
+        })
+    }
+
+    fn distance_to_path(position: Vec3, path: &[Vec3]) -> f32 {
+        path.windows(2)
+            .map(|pair| {
+                let chord = pair[1] - pair[0];
+                let fraction = ((position - pair[0]).dot(chord) / chord.length_squared()).clamp(0., 1.);
+                position.distance(pair[0] + chord * fraction)
+            })
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn t_values(forward: bool) -> Vec<f32> {
+        let start = CROSSOVER_SEGMENT as f32 - 0.3;
+        let values: Vec<f32> = (0..=160).map(|i| start + i as f32 * 0.01).collect();
+        if forward { values } else { values.into_iter().rev().collect() }
+    }
+
+    /// Checks that the positions before the crossover are on the `from` path, inside it on the `through` path,
+    /// and after it on the `to` path.
+    fn assert_on_paths(positions: &[(f32, Vec3)], forward: bool, from: &[Vec3], through: &[Vec3], to: &[Vec3]) {
+        for (t, position) in positions {
+            let segment = t.floor() as usize;
+            let path = match (segment == CROSSOVER_SEGMENT, (segment > CROSSOVER_SEGMENT) == forward) {
+                (true, _) => through,
+                (false, true) => to,
+                (false, false) => from,
+            };
+            let distance = distance_to_path(*position, path);
+            assert!(distance < TOLERANCE, "the bogie at t = {} is {} m away from the laid rails", t, distance);
+        }
+    }
+
+    #[test]
+    fn bogies_follow_the_laid_crossover_rails() {
+        let mut world = World::new();
+        let double_track = DoubleTrack::spawn(&mut world);
+        let [first_track, second_track] = double_track.tracks;
+        let first_path = double_track.track_path(0);
+        let second_path = double_track.track_path(1);
+        let diverging_path = double_track.diverging_path();
+
+        // Forward on the first track and backward on the second one the switches are facing, so the bogie crosses over
+        let (positions, track) = run_bogie(&mut world, first_track, t_values(true));
+        assert_on_paths(&positions, true, &first_path, &diverging_path, &second_path);
+        assert_eq!(track, second_track);
+
+        let (positions, track) = run_bogie(&mut world, second_track, t_values(false));
+        assert_on_paths(&positions, false, &second_path, &diverging_path, &first_path);
+        assert_eq!(track, first_track);
+
+        // Otherwise the bogie trails through the switches and stays on its track
+        let (positions, track) = run_bogie(&mut world, second_track, t_values(true));
+        assert_on_paths(&positions, true, &second_path, &second_path, &second_path);
+        assert_eq!(track, second_track);
+
+        let (positions, track) = run_bogie(&mut world, first_track, t_values(false));
+        assert_on_paths(&positions, false, &first_path, &first_path, &first_path);
+        assert_eq!(track, first_track);
+    }
+
+    #[test]
+    fn curve_radius_adds_up_to_the_turn_of_the_segment() {
+        let mut world = World::new();
+        let profile = TrackProfile::default();
+        let (radius, angle_per_node) = (400., 0.15);
+        let curved_points: Vec<Vec3> = (0..7)
+            .map(|i| Vec3::new((i as f32 * angle_per_node).sin(), 0., 1. - (i as f32 * angle_per_node).cos()) * radius)
+            .collect();
+        let straight_points: Vec<Vec3> = (0..7).map(|i| Vec3::new(i as f32 * 60., 0., 0.)).collect();
+        let curved_track = spawn_level_tracks(&mut world, &profile, &curved_points)[0];
+        let straight_track = spawn_level_tracks(&mut world, &profile, &straight_points)[0];
+        let step = 1. / profile.subdivisions as f32;
+
+        // The segments are not circular arcs, but they leave the nodes along the circle, so each of them turns by the
+        // angle between its nodes: the sum of the distance over the radius along the segment
+        let (turn, straight_radius) = world.run_system_once(move |tracks: Query<&Track>| {
+            let track = tracks.get(curved_track).unwrap();
+            let height_fn = |_, _| 0.;
+            let turn: f32 = (0..=profile.subdivisions)
+                .map(|i| {
+                    let t = 2. + i as f32 * step;
+                    let position_at = |t: f32| track.get_interpolated_position_at_t(t, &height_fn).unwrap().0.xz();
+                    let distance = (position_at(t - step).distance(position_at(t)) + position_at(t).distance(position_at(t + step))) / 2.;
+                    let weight = if i == 0 || i == profile.subdivisions { 0.5 } else { 1. };
+                    weight * distance / track.get_curve_radius_at_t(t - step, &height_fn).unwrap()
+                })
+                .sum();
+            (turn, tracks.get(straight_track).unwrap().get_curve_radius_at_t(2.5, &height_fn).unwrap())
+        });
+
+        assert!((turn - angle_per_node).abs() < angle_per_node * 0.02, "the segment turns by {} rad instead of {} rad", turn, angle_per_node);
+        assert_eq!(straight_radius, f32::INFINITY);
+    }
+
+    #[test]
+    fn bogies_reversing_on_a_crossover_stay_on_its_rails() {
+        let mut world = World::new();
+        let double_track = DoubleTrack::spawn(&mut world);
+        let first_path = double_track.track_path(0);
+        let diverging_path = double_track.diverging_path();
+
+        // Into the middle of the crossover and back
+        let mut t_values: Vec<f32> = t_values(true).into_iter().take(80).collect();
+        t_values.extend(t_values.clone().into_iter().rev());
+        let (positions, track) = run_bogie(&mut world, double_track.tracks[0], t_values);
+        assert_on_paths(&positions, true, &first_path, &diverging_path, &first_path);
+        assert_eq!(track, double_track.tracks[0]);
+    }
+
+    #[test]
+    fn tunnel_heights_are_interpolated_by_the_arc_length() {
+        // A tunnel segment rising by 6 m along a sharp curve, where the arc length and the chord differ most
+        let points: Vec<Vec3> = (0..4)
+            .map(|i| Vec3::new((i as f32 * 0.8).sin() * 100., i as f32 * 2., (1. - (i as f32 * 0.8).cos()) * 100.))
+            .collect();
+        let mut route = Route::default();
+        route.restore(0, points, vec![true; 4]);
+        let segment = build_track_segment(&route, 1).unwrap();
+        let height_fn = segment.height_function(NoiseSettings::default(), Vec3::ZERO);
+        let mut curve = segment.curve.clone();
+        curve.calculate_arc_lengths_with_custom_height_function(&height_fn);
+        let sampled_segment = SampledTrackSegment { curve, world_translation: Vec3::ZERO, tunnel: segment.tunnel };
+
+        for local_t in [0., 0.25, 0.5, 0.75, 1.] {
+            let (position, _) = sampled_segment.get_point_at_local_t(local_t, &height_fn);
+            let height = height_fn(position.x as f64, position.z as f64) as f32;
+            assert!((position.y - height).abs() < TOLERANCE, "the height at local t {} is {} m on the track and {} m in the height function", local_t, position.y, height);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use bevy::prelude::*;
+use crate::{noise, NoiseSettings};
+use crate::world::route_gen::Route;
+use crate::world::terrain::{Terrain, TerrainHole};
+use crate::world::train_tracks::{PlacementData, TrackPathPoint, TrackSegmentPlaced};
+use crate::world::utils;
+
+/// The number of samples taken along each tunnel segment to find the portals and build the lining.
+const TUNNEL_SAMPLES_PER_SEGMENT: u32 = 20;
+/// Half of the inner width of the tunnel (measured at the top of the side walls).
+const TUNNEL_HALF_WIDTH: f32 = 4.5;
+/// The height of the vertical side walls, above which the arch begins.
+const TUNNEL_WALL_HEIGHT: f32 = 3.5;
+/// The number of vertices used to approximate the arch of the lining.
+const TUNNEL_ARCH_RESOLUTION: u32 = 12;
+/// The depth of the terrain above the track (excluding track elevation) at which the tunnel lining starts and the portal is placed.
+const PORTAL_COVER_DEPTH: f32 = TUNNEL_WALL_HEIGHT + TUNNEL_HALF_WIDTH + 1.;
+/// The radius of the hole cut into the terrain around each portal.
+const PORTAL_HOLE_RADIUS: f32 = 15.;
+/// The depth of the tunnel floor below the track.
+const TUNNEL_FLOOR_DEPTH: f32 = 1.;
+
+/// Marker for tunnel portal entities.
+#[derive(Component)]
+pub(crate) struct TunnelPortal;
+
+/// Marker for tunnel lining entities.
+#[derive(Component)]
+pub(crate) struct TunnelLining;
+
+#[derive(Resource, Default)]
+pub(crate) struct TunnelData {
+    portal_pillar_mesh: Option<Handle<Mesh>>,
+    portal_lintel_mesh: Option<Handle<Mesh>>,
+    portal_material: Option<Handle<StandardMaterial>>,
+    lining_material: Option<Handle<StandardMaterial>>,
+}
+
+pub(crate) fn setup_tunnel_data(
+    mut data_res: ResMut<TunnelData>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let portal_height = TUNNEL_WALL_HEIGHT + TUNNEL_HALF_WIDTH;
+    data_res.portal_pillar_mesh = Some(meshes.add(Cuboid::new(2., portal_height + 2., 2.)));
+    data_res.portal_lintel_mesh = Some(meshes.add(Cuboid::new(TUNNEL_HALF_WIDTH * 2. + 12., 4., 2.)));
+
+    data_res.portal_material = Some(materials.add(StandardMaterial {
+        base_color: Color::srgb(0.55, 0.53, 0.5),
+        perceptual_roughness: 0.95,
+        ..default()
+    }));
+    data_res.lining_material = Some(materials.add(StandardMaterial {
+        base_color: Color::srgb(0.3, 0.3, 0.3),
+        perceptual_roughness: 1.,
+        double_sided: true,
+        cull_mode: None,
+        ..default()
+    }));
+}
+
+/// Places portals and lining meshes for the tunnel segments that have been placed since the last run.
+/// The lining covers the parts of a segment where the terrain is at least `PORTAL_COVER_DEPTH` above the track,
+/// and a portal (with a hole in the terrain around it) is placed wherever the lining begins or ends. A lining reaching an end
+/// of the segment continues in the neighbouring segment if that one runs through a tunnel too, otherwise the portal is placed at the end.
+/// Both are children of the segment entity, so they are evicted together with it.
+pub(crate) fn place_tunnels(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut placed_events: EventReader<TrackSegmentPlaced>,
+    mut terrain_res: ResMut<Terrain>,
+    data_res: Res<TunnelData>,
+    placement_data: Res<PlacementData>,
+    route: Res<Route>,
+    noise_settings: Res<NoiseSettings>,
+) {
+    if data_res.portal_material.is_none() || data_res.lining_material.is_none() {
+        return;
+    }
+
+    for event in placed_events.read() {
+        if !placement_data.is_tunnel_segment(event.segment_id) {
+            continue;
+        }
+        let Some(path) = placement_data.sample_segment(event.segment_id, noise_settings.clone(), TUNNEL_SAMPLES_PER_SEGMENT) else {
+            continue;
+        };
+        let [start_covered, end_covered] = place_tunnel_segment(&mut commands, &mut meshes, &data_res, &mut terrain_res, &noise_settings, &path, event);
+
+        let previous_in_tunnel = event.segment_id.checked_sub(1).is_some_and(|id| route.is_tunnel_segment(id));
+        let next_in_tunnel = route.is_tunnel_segment(event.segment_id + 1);
+        let ends = [(path.first(), start_covered && !previous_in_tunnel), (path.last(), end_covered && !next_in_tunnel)];
+        for (point, has_portal) in ends {
+            if let (Some(point), true) = (point, has_portal) {
+                spawn_portal(&mut commands, &data_res, &mut terrain_res, point, event);
+            }
+        }
+    }
+}
+
+/// Places the lining of the segment and the portals inside it.
+/// Returns whether the lining reaches the start and the end of the segment, where the portals depend on the neighbouring segments.
+fn place_tunnel_segment(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    data_res: &TunnelData,
+    terrain_res: &mut Terrain,
+    noise_settings: &NoiseSettings,
+    path: &[TrackPathPoint],
+    event: &TrackSegmentPlaced,
+) -> [bool; 2] {
+    let noise_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+    let is_covered: Vec<bool> = path.iter()
+        .map(|point| noise_fn(point.position.x as f64, point.position.z as f64) as f32 - point.position.y >= PORTAL_COVER_DEPTH)
+        .collect();
+
+    // Split the segment into continuous covered runs, each of them gets its own lining mesh.
+    let mut run_start: Option<usize> = None;
+    for i in 0..=path.len() {
+        let covered = i < path.len() && is_covered[i];
+        match (run_start, covered) {
+            (None, true) => {
+                // The lining starts here. A portal at the segment start is placed by the caller.
+                if i > 0 {
+                    spawn_portal(commands, data_res, terrain_res, &path[i], event);
+                }
+                run_start = Some(i);
+            },
+            (Some(start), false) => {
+                let end = i - 1;
+                if end > start {
+                    // The lining is a child of the segment entity, so move the path into its space
+                    let local_path: Vec<TrackPathPoint> = path[start..=end].iter()
+                        .map(|point| TrackPathPoint { position: point.position - event.translation, rotation: point.rotation })
+                        .collect();
+                    let mesh = utils::extrude_profile(&lining_profile(), false, &local_path);
+                    commands.spawn(PbrBundle {
+                        mesh: meshes.add(mesh),
+                        material: data_res.lining_material.clone().unwrap(),
+                        ..default()
+                    })
+                        .insert(TunnelLining)
+                        .set_parent(event.entity);
+                }
+                if i < path.len() {
+                    spawn_portal(commands, data_res, terrain_res, &path[end], event);
+                }
+                run_start = None;
+            },
+            _ => {},
+        }
+    }
+
+    [is_covered.first().copied().unwrap_or(false), is_covered.last().copied().unwrap_or(false)]
+}
+
+fn spawn_portal(
+    commands: &mut Commands,
+    data_res: &TunnelData,
+    terrain_res: &mut Terrain,
+    point: &TrackPathPoint,
+    event: &TrackSegmentPlaced,
+) {
+    let portal_height = TUNNEL_WALL_HEIGHT + TUNNEL_HALF_WIDTH;
+    let material = data_res.portal_material.clone().unwrap();
+    let pillar_mesh = data_res.portal_pillar_mesh.clone().unwrap();
+    let lintel_mesh = data_res.portal_lintel_mesh.clone().unwrap();
+
+    let transform = Transform::from_translation(point.position - event.translation).with_rotation(point.rotation);
+    commands.spawn(SpatialBundle::from_transform(transform))
+        .insert(TunnelPortal)
+        .with_children(|parent| {
+            for side in [-1., 1.] {
+                parent.spawn(PbrBundle {
+                    mesh: pillar_mesh.clone(),
+                    material: material.clone(),
+                    transform: Transform::from_xyz(side * (TUNNEL_HALF_WIDTH + 1.), (portal_height + 2.) / 2. - 1., 0.),
+                    ..default()
+                });
+            }
+            parent.spawn(PbrBundle {
+                mesh: lintel_mesh.clone(),
+                material: material.clone(),
+                transform: Transform::from_xyz(0., portal_height + 2., 0.),
+                ..default()
+            });
+        })
+        .set_parent(event.entity);
+
+    terrain_res.add_hole(TerrainHole {
+        center: point.position.xz(),
+        radius: PORTAL_HOLE_RADIUS,
+    });
+}
+
+/// Returns the cross-section of the tunnel lining: two vertical walls topped by a half-circle arch.
+/// The points go clockwise from the bottom of the left wall to the bottom of the right wall, so the normals face inwards.
+fn lining_profile() -> Vec<Vec2> {
+    let mut profile = vec![Vec2::new(-TUNNEL_HALF_WIDTH, -TUNNEL_FLOOR_DEPTH)];
+    for i in 0..=TUNNEL_ARCH_RESOLUTION {
+        let angle = std::f32::consts::PI * (1. - i as f32 / TUNNEL_ARCH_RESOLUTION as f32);
+        profile.push(Vec2::new(angle.cos() * TUNNEL_HALF_WIDTH, TUNNEL_WALL_HEIGHT + angle.sin() * TUNNEL_HALF_WIDTH));
+    }
+    profile.push(Vec2::new(TUNNEL_HALF_WIDTH, -TUNNEL_FLOOR_DEPTH));
+
+    profile
+}
+
+/// Returns the height of the tunnel floor under the given position, if the position is inside the lining of a loaded tunnel segment.
+pub(crate) fn get_tunnel_floor_height(placement_data: &PlacementData, noise_settings: &NoiseSettings, position: Vec3) -> Option<f32> {
+    let noise_fn = noise::get_heightmap_function(noise_settings.clone(), Vec3::ZERO);
+    if (noise_fn(position.x as f64, position.z as f64) as f32) < position.y {
+        return None;
+    }
+
+    for segment_id in placement_data.tunnel_segment_ids() {
+        let Some(path) = placement_data.sample_segment(segment_id, noise_settings.clone(), TUNNEL_SAMPLES_PER_SEGMENT) else {
+            continue;
+        };
+        for pair in path.windows(2) {
+            let (start, end) = (pair[0].position, pair[1].position);
+            let direction = (end - start).xz();
+            let along = ((position.xz() - start.xz()).dot(direction) / direction.length_squared()).clamp(0., 1.);
+            let track_position = start.lerp(end, along);
+            if track_position.xz().distance(position.xz()) > TUNNEL_HALF_WIDTH {
+                continue;
+            }
+            let is_covered = noise_fn(track_position.x as f64, track_position.z as f64) as f32 - track_position.y >= PORTAL_COVER_DEPTH;
+            if is_covered {
+                return Some(track_position.y - TUNNEL_FLOOR_DEPTH);
+            }
+        }
+    }
+
+    None
+}
@@ -1,3 +1,8 @@
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use crate::world::train_tracks::TrackPathPoint;
+
 #[allow(dead_code)]
 pub fn get_closest<T>(t: f32, t_max: f32, vec: &Vec<(f32, T)>) -> Option<(usize, &(f32, T), bool)> {
     let mut closest_obj: Option<&(f32, T)> = None;
@@ -19,3 +24,51 @@ pub fn get_closest<T>(t: f32, t_max: f32, vec: &Vec<(f32, T)>) -> Option<(usize,
         None
     }
 }
+
+/// Extrudes a 2D cross-section (x is lateral, y is up) along a path of oriented points.
+/// Every edge of the cross-section gets its own vertices, so the shading is flat across the edges.
+/// The normals point to the right of each edge, i.e. outwards for a counterclockwise cross-section.
+pub fn extrude_profile(profile: &[Vec2], closed: bool, path: &[TrackPathPoint]) -> Mesh {
+    let mut edges: Vec<(Vec2, Vec2)> = profile.windows(2).map(|pair| (pair[0], pair[1])).collect();
+    if closed && profile.len() > 2 {
+        edges.push((profile[profile.len() - 1], profile[0]));
+    }
+    let ring_size = edges.len() as u32 * 2;
+
+    let mut vertices = Vec::with_capacity(ring_size as usize * path.len());
+    let mut normals = Vec::with_capacity(ring_size as usize * path.len());
+    for point in path {
+        for (start, end) in &edges {
+            let direction = *end - *start;
+            let normal = point.rotation * Vec3::new(direction.y, -direction.x, 0.).normalize_or_zero();
+            for vertex in [start, end] {
+                vertices.push((point.position + point.rotation * Vec3::new(vertex.x, vertex.y, 0.)).to_array());
+                normals.push(normal.to_array());
+            }
+        }
+    }
+
+    let mut indices = Vec::with_capacity(path.len().saturating_sub(1) * edges.len() * 6);
+    for ring in 0..(path.len() as u32).saturating_sub(1) {
+        for edge in 0..edges.len() as u32 {
+            let current = ring * ring_size + edge * 2;
+            let next = current + ring_size;
+            indices.extend_from_slice(&[current, next, current + 1, current + 1, next, next + 1]);
+        }
+    }
+
+    // The handedness of the path rotation is not known in advance, so flip the winding if the triangles face away from the normals.
+    if let Some(first) = indices.get(0..3) {
+        let [a, b, c] = [first[0], first[1], first[2]].map(|index| Vec3::from_array(vertices[index as usize]));
+        if (b - a).cross(c - a).dot(Vec3::from_array(normals[first[0] as usize])) < 0. {
+            indices.chunks_mut(3).for_each(|triangle| triangle.swap(1, 2));
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default());
+    mesh.insert_indices(Indices::U32(indices));
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vertices);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+
+    mesh
+}
@@ -0,0 +1,84 @@
+//! Records headless runs and replays them, checking that the replay steps through the same bogie states.
+
+use std::time::Duration;
+use bevy::app::App;
+use bevy::time::TimeUpdateStrategy;
+use bevy_procedural_world::PHYSICS_TIMESTEP;
+use bevy_procedural_world::config::WorldConfig;
+use bevy_procedural_world::headless::{build_headless_app, simulate};
+use bevy_procedural_world::replay::{self, Recording, Replay};
+use bevy_procedural_world::rolling_stock::wagon_systems::PhysicsTick;
+use bevy_procedural_world::save::WorldSave;
+
+/// The simulated time of each run in seconds.
+const DURATION: f32 = 3.;
+
+fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("bevy-procedural-world-{}-{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Advances the clock of the headless app by the given time in seconds per update, instead of one physics step.
+fn set_frame_time(app: &mut App, frame_time: f32) {
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(frame_time)));
+}
+
+/// Runs the config for `DURATION` with the given time per update, and writes its recording.
+fn record(config: &WorldConfig, path: &str, frame_time: f32) {
+    let config = WorldConfig { record_to: Some(path.to_string()), ..config.clone() };
+    let mut app = build_headless_app(&config);
+    set_frame_time(&mut app, frame_time);
+    simulate(&mut app, DURATION).unwrap();
+    replay::finish_headless_run(app.world_mut()).unwrap();
+}
+
+/// Replays the whole recording with the given time per update, and checks that the bogies never differed from it.
+fn assert_replay_matches(path: &str, frame_time: f32) {
+    let recording = Recording::load(path).unwrap();
+    let config = WorldConfig { replay_from: Some(path.to_string()), ..recording.start.config.clone() };
+    let mut app = build_headless_app(&config);
+    set_frame_time(&mut app, frame_time);
+    simulate(&mut app, recording.trace.len() as f32 * PHYSICS_TIMESTEP).unwrap();
+
+    let replay = app.world().resource::<Replay>();
+    assert_eq!(replay.diverged_at, None);
+    assert_eq!(app.world().resource::<PhysicsTick>().0, replay.recording().end_tick());
+}
+
+#[test]
+fn replay_of_a_recorded_run_matches_the_recording() {
+    let path = temp_path("run.ron");
+    record(&WorldConfig::default(), &path, PHYSICS_TIMESTEP);
+
+    let recording = Recording::load(&path).unwrap();
+    assert!(!recording.trace.is_empty());
+    assert_replay_matches(&path, PHYSICS_TIMESTEP);
+}
+
+#[test]
+fn replay_of_a_run_restored_from_a_save_matches_the_recording() {
+    // Save the world after a first run, and record a run continuing from the save
+    let save_path = temp_path("save.ron");
+    let mut app = build_headless_app(&WorldConfig::default());
+    simulate(&mut app, DURATION).unwrap();
+    WorldSave::capture(app.world_mut()).write(&save_path).unwrap();
+
+    let recording_path = temp_path("restored-run.ron");
+    let save = WorldSave::load(&save_path).unwrap();
+    let config = WorldConfig { restore_from: Some(save_path), ..save.config.clone() };
+    record(&config, &recording_path, PHYSICS_TIMESTEP);
+
+    let recording = Recording::load(&recording_path).unwrap();
+    assert!(recording.start.tick >= save.tick);
+    assert_replay_matches(&recording_path, PHYSICS_TIMESTEP);
+}
+
+#[test]
+fn replay_at_another_frame_rate_matches_the_recording() {
+    // Two physics steps per update while recording, and one step every two updates while replaying
+    let path = temp_path("frame-rate-run.ron");
+    record(&WorldConfig::default(), &path, 2. * PHYSICS_TIMESTEP);
+    assert_replay_matches(&path, PHYSICS_TIMESTEP / 2.);
+}